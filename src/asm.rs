@@ -0,0 +1,821 @@
+use super::chip8::{Byte, Word, PROGRAM_START};
+use super::error::Error;
+use super::instruction::{self, Instruction};
+use super::quirks::Platform;
+use super::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// Assembler for the classic mnemonics, the syntax the disassembler prints:
+//
+//     ; comment
+//     SPEED = 4               ; constant, `SPEED EQU 4` works as well
+//     main:   LD V0, SPEED
+//             LD I, sprite
+//             DRW V0, V1, 2
+//     loop:   JP loop
+//     sprite: DB 0b11000011, 0x3C
+//             DW sprite + 2
+//             ORG 0x300
+//             INCLUDE "other.asm"
+//
+// Mnemonics, registers and directives are case-insensitive, symbols are not.
+// Numbers are decimal, hex (`0x1F`, `#1F`, `$1F`) or binary (`0b101`), `*` is the
+// address of the current line. Expressions know + - * / % & | ^ << >> ~ and parentheses.
+
+// A successfully assembled program.
+pub struct Program {
+    // the rom, loaded at 0x200
+    pub rom: Vec<Byte>,
+    // every label and constant
+    pub symbols: BTreeMap<String, i64>,
+    // the source file and line of every instruction, for debuggers
+    pub lines: BTreeMap<Word, (String, usize)>,
+}
+
+impl Program {
+    // The symbols as `name = value` lines, ordered by value.
+    pub fn symbol_file(&self) -> String {
+        let mut symbols: Vec<_> = self.symbols.iter().collect();
+        symbols.sort_by_key(|&(name, value)| (*value, name.clone()));
+        symbols
+            .iter()
+            .map(|(name, value)| format!("{} = {:#06X}\n", name, value))
+            .collect()
+    }
+}
+
+// Assemble source code, includes are relative to the current directory.
+pub fn assemble(source: &str, platform: Platform) -> Result<Program> {
+    let mut assembler = Assembler::new(platform);
+    assembler.parse(source, Path::new("<source>"), 0)?;
+    assembler.finish()
+}
+
+// Assemble a source file, includes are relative to the file.
+pub fn assemble_file(path: &str, platform: Platform) -> Result<Program> {
+    let mut assembler = Assembler::new(platform);
+    assembler.parse_file(Path::new(path), None, 0)?;
+    assembler.finish()
+}
+
+// the largest program, leaving the rest of XO-CHIP memory empty
+const MAX_ADDRESS: i64 = 0xFFFF;
+// includes nested deeper than this are assumed to include themselves
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// Errors inside a line, they get the file and line number when they leave it.
+type Message<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Debug)]
+struct Location {
+    file: PathBuf,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, message: impl Into<String>) -> Error {
+        Error::Asm {
+            file: self.file.display().to_string(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    // `*`, the address of the line
+    Here,
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(Byte),
+    I,
+    // [I]
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    // LONG NNNN, the 16 bit address of F000 NNNN
+    Long(Expr),
+    Value(Expr),
+}
+
+enum DataItem {
+    Value(Expr),
+    Text(Vec<Byte>),
+}
+
+enum Kind {
+    Instruction(String, Vec<Operand>),
+    Db(Vec<DataItem>),
+    Dw(Vec<Expr>),
+}
+
+struct Statement {
+    location: Location,
+    address: i64,
+    kind: Kind,
+}
+
+enum Symbol {
+    Label(i64),
+    // constants are evaluated when used, so they can refer to later labels
+    Constant(Expr),
+}
+
+struct Assembler {
+    platform: Platform,
+    address: i64,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, (Symbol, Location)>,
+}
+
+impl Assembler {
+    fn new(platform: Platform) -> Self {
+        Assembler {
+            platform,
+            address: PROGRAM_START as i64,
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, from: Option<&Location>, depth: usize) -> Result<()> {
+        let source = std::fs::read_to_string(path).map_err(|e| match from {
+            Some(location) => location.error(format!("can't read {}: {}", path.display(), e)),
+            None => Error::RomErr(e),
+        })?;
+        self.parse(&source, path, depth)
+    }
+
+    // First pass: split the source into statements and give every label its address.
+    fn parse(&mut self, source: &str, file: &Path, depth: usize) -> Result<()> {
+        for (number, line) in source.lines().enumerate() {
+            let location = Location {
+                file: file.to_path_buf(),
+                line: number + 1,
+            };
+            self.parse_line(line, &location, depth)?;
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, location: &Location, depth: usize) -> Result<()> {
+        let mut line = strip_comment(line).trim();
+        let err = |message: String| location.error(message);
+
+        // label
+        if let Some((name, rest)) = line.split_once(':') {
+            if is_symbol(name.trim()) && !rest.starts_with(':') {
+                self.define(name.trim(), Symbol::Label(self.address), location)?;
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (word, rest) = split_word(line);
+        // constants, `NAME = value` or `NAME EQU value`
+        let (second, value) = split_word(rest);
+        if is_symbol(word) && (rest.starts_with('=') || second.eq_ignore_ascii_case("equ")) {
+            let value = rest.strip_prefix('=').unwrap_or(value);
+            let expr = parse_expr(value).map_err(err)?;
+            return self.define(word, Symbol::Constant(expr), location);
+        }
+
+        let mnemonic = word.to_ascii_uppercase();
+        let address = self.address;
+        let kind = match mnemonic.as_str() {
+            "ORG" => {
+                let expr = parse_expr(rest).map_err(err)?;
+                let value = self.eval(&expr, address, 0).map_err(err)?;
+                if !(PROGRAM_START as i64..=MAX_ADDRESS).contains(&value) {
+                    return Err(err(format!(
+                        "ORG {:#X} is outside of the program memory",
+                        value
+                    )));
+                }
+                self.address = value;
+                return Ok(());
+            }
+            "INCLUDE" => {
+                let name = parse_string(rest.trim())
+                    .ok_or_else(|| err("INCLUDE expects a \"file name\"".to_string()))?;
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(err("includes are nested too deep".to_string()));
+                }
+                let base = location.file.parent().unwrap_or(Path::new(""));
+                let path = base.join(String::from_utf8_lossy(&name).as_ref());
+                return self.parse_file(&path, Some(location), depth + 1);
+            }
+            "DB" => {
+                let mut items = Vec::new();
+                for operand in split_operands(rest) {
+                    items.push(match parse_string(operand) {
+                        Some(text) => DataItem::Text(text),
+                        None => DataItem::Value(parse_expr(operand).map_err(err)?),
+                    });
+                }
+                Kind::Db(items)
+            }
+            "DW" => Kind::Dw(
+                split_operands(rest)
+                    .into_iter()
+                    .map(parse_expr)
+                    .collect::<Message<_>>()
+                    .map_err(err)?,
+            ),
+            _ => Kind::Instruction(
+                mnemonic,
+                split_operands(rest)
+                    .into_iter()
+                    .map(parse_operand)
+                    .collect::<Message<_>>()
+                    .map_err(err)?,
+            ),
+        };
+
+        self.address += match &kind {
+            Kind::Db(items) => items
+                .iter()
+                .map(|item| match item {
+                    DataItem::Value(_) => 1,
+                    DataItem::Text(text) => text.len() as i64,
+                })
+                .sum(),
+            Kind::Dw(words) => 2 * words.len() as i64,
+            Kind::Instruction(_, operands) => {
+                if operands.iter().any(|o| matches!(o, Operand::Long(_))) {
+                    4
+                } else {
+                    2
+                }
+            }
+        };
+        if self.address > MAX_ADDRESS + 1 {
+            return Err(err("the program doesn't fit into memory".to_string()));
+        }
+        self.statements.push(Statement {
+            location: location.clone(),
+            address,
+            kind,
+        });
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, location: &Location) -> Result<()> {
+        if let Some((_, first)) = self.symbols.get(name) {
+            return Err(location.error(format!(
+                "`{}` is already defined at {}:{}",
+                name,
+                first.file.display(),
+                first.line
+            )));
+        }
+        self.symbols
+            .insert(name.to_string(), (symbol, location.clone()));
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, here: i64, depth: usize) -> Message<i64> {
+        if depth > 64 {
+            return Err("constants refer to each other in a loop".to_string());
+        }
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Here => here,
+            Expr::Symbol(name) => match self.symbols.get(name) {
+                Some((Symbol::Label(address), _)) => *address,
+                Some((Symbol::Constant(expr), _)) => self.eval(expr, here, depth + 1)?,
+                None => return Err(format!("unknown symbol `{}`", name)),
+            },
+            Expr::Unary(op, value) => {
+                let value = self.eval(value, here, depth)?;
+                match op {
+                    '-' => value.wrapping_neg(),
+                    _ => !value,
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left, here, depth)?;
+                let right = self.eval(right, here, depth)?;
+                match *op {
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "/" | "%" if right == 0 => return Err("division by zero".to_string()),
+                    "/" => left.checked_div(right).ok_or("overflow in division")?,
+                    "%" => left.checked_rem(right).ok_or("overflow in division")?,
+                    "&" => left & right,
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "<<" => left.wrapping_shl(right as u32),
+                    _ => left.wrapping_shr(right as u32),
+                }
+            }
+        })
+    }
+
+    // Second pass: now that every label is known, encode the statements.
+    fn finish(self) -> Result<Program> {
+        let mut rom: Vec<Option<Byte>> = Vec::new();
+        let mut lines = BTreeMap::new();
+        for statement in &self.statements {
+            let location = &statement.location;
+            if let Kind::Instruction(..) = statement.kind {
+                let source = (location.file.display().to_string(), location.line);
+                lines.insert(statement.address as Word, source);
+            }
+            let bytes = self.encode(statement).map_err(|e| location.error(e))?;
+            let start = (statement.address - PROGRAM_START as i64) as usize;
+            if rom.len() < start + bytes.len() {
+                rom.resize(start + bytes.len(), None);
+            }
+            for (offset, byte) in bytes.into_iter().enumerate() {
+                if rom[start + offset].replace(byte).is_some() {
+                    return Err(location.error(format!(
+                        "overwrites {:#05X}, which is already used",
+                        statement.address + offset as i64
+                    )));
+                }
+            }
+        }
+
+        let mut symbols = BTreeMap::new();
+        for (name, (_, location)) in &self.symbols {
+            let value = self
+                .eval(&Expr::Symbol(name.clone()), 0, 0)
+                .map_err(|e| location.error(e))?;
+            symbols.insert(name.clone(), value);
+        }
+        Ok(Program {
+            rom: rom.into_iter().map(|byte| byte.unwrap_or(0)).collect(),
+            symbols,
+            lines,
+        })
+    }
+
+    // Evaluate an operand that has to be in `min..=max`.
+    fn value(&self, expr: &Expr, here: i64, min: i64, max: i64, what: &str) -> Message<i64> {
+        let value = self.eval(expr, here, 0)?;
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!("{} {} is out of range", what, value))
+        }
+    }
+
+    fn encode(&self, statement: &Statement) -> Message<Vec<Byte>> {
+        let here = statement.address;
+        let value =
+            |expr: &Expr, min: i64, max: i64, what: &str| self.value(expr, here, min, max, what);
+
+        match &statement.kind {
+            Kind::Db(items) => {
+                let mut bytes = Vec::new();
+                for item in items {
+                    match item {
+                        DataItem::Value(expr) => {
+                            bytes.push(value(expr, -128, 0xFF, "byte")? as Byte)
+                        }
+                        DataItem::Text(text) => bytes.extend_from_slice(text),
+                    }
+                }
+                Ok(bytes)
+            }
+            Kind::Dw(words) => {
+                let mut bytes = Vec::new();
+                for expr in words {
+                    let word = value(expr, -0x8000, 0xFFFF, "word")? as Word;
+                    bytes.extend_from_slice(&word.to_be_bytes());
+                }
+                Ok(bytes)
+            }
+            Kind::Instruction(mnemonic, operands) => {
+                let (instruction, long) = self.instruction(mnemonic, operands, here)?;
+                let opcode = instruction::encode(&instruction);
+                if instruction::decode(opcode, self.platform).ok() != Some(instruction) {
+                    return Err(format!(
+                        "`{}` is not an instruction of {:?}",
+                        mnemonic, self.platform
+                    ));
+                }
+                let mut bytes = opcode.to_be_bytes().to_vec();
+                if let Some(long) = long {
+                    bytes.extend_from_slice(&long.to_be_bytes());
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    // Pick the instruction for a mnemonic and its operands.
+    // `long` is the address word that follows F000.
+    fn instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[Operand],
+        here: i64,
+    ) -> Message<(Instruction, Option<Word>)> {
+        use Instruction::*;
+        use Operand::*;
+        let value =
+            |expr: &Expr, min: i64, max: i64, what: &str| self.value(expr, here, min, max, what);
+        let addr = |expr: &Expr| value(expr, 0, 0xFFF, "address").map(|v| v as Word);
+        let byte = |expr: &Expr| value(expr, -128, 0xFF, "byte").map(|v| v as Byte);
+        let nibble = |expr: &Expr| value(expr, 0, 0xF, "nibble").map(|v| v as Byte);
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCR", []) => Scr,
+            ("SCL", []) => Scl,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => Low,
+            ("HIGH", []) => High,
+            ("AUDIO", []) => Audio,
+            ("SCD", [Value(n)]) => Scd(nibble(n)?),
+            ("SCU", [Value(n)]) => Scu(nibble(n)?),
+            ("PLANE", [Value(n)]) => Plane(value(n, 0, 3, "plane")? as Byte),
+            ("SYS", [Value(a)]) => Sys(addr(a)?),
+            ("JP", [Value(a)]) => Jp(addr(a)?),
+            ("JP", [Register(0), Value(a)]) => JpV0(addr(a)?),
+            ("CALL", [Value(a)]) => Call(addr(a)?),
+            ("SE", [Register(x), Value(nn)]) => SeByte {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("SE", [Register(x), Register(y)]) => Se { x: *x, y: *y },
+            ("SNE", [Register(x), Value(nn)]) => SneByte {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("SNE", [Register(x), Register(y)]) => Sne { x: *x, y: *y },
+            ("SAVE", [Register(x), Register(y)]) => SaveRange { x: *x, y: *y },
+            ("LOAD", [Register(x), Register(y)]) => LoadRange { x: *x, y: *y },
+            ("LD", [Register(x), Value(nn)]) => LdByte {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("LD", [Register(x), Register(y)]) => Ld { x: *x, y: *y },
+            ("LD", [I, Value(a)]) => LdI(addr(a)?),
+            ("LD", [I, Long(a)]) => {
+                let long = value(a, 0, 0xFFFF, "address")? as Word;
+                return Ok((LdILong, Some(long)));
+            }
+            ("LD", [Register(x), Dt]) => LdVxDt(*x),
+            ("LD", [Register(x), K]) => LdVxK(*x),
+            ("LD", [Dt, Register(x)]) => LdDtVx(*x),
+            ("LD", [St, Register(x)]) => LdStVx(*x),
+            ("LD", [F, Register(x)]) => LdF(*x),
+            ("LD", [Hf, Register(x)]) => LdHf(*x),
+            ("LD", [B, Register(x)]) => LdB(*x),
+            ("LD", [IndirectI, Register(x)]) => LdIVx(*x),
+            ("LD", [Register(x), IndirectI]) => LdVxI(*x),
+            ("LD", [R, Register(x)]) => LdRVx(*x),
+            ("LD", [Register(x), R]) => LdVxR(*x),
+            ("ADD", [Register(x), Value(nn)]) => AddByte {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("ADD", [Register(x), Register(y)]) => Add { x: *x, y: *y },
+            ("ADD", [I, Register(x)]) => AddI(*x),
+            ("OR", [Register(x), Register(y)]) => Or { x: *x, y: *y },
+            ("AND", [Register(x), Register(y)]) => And { x: *x, y: *y },
+            ("XOR", [Register(x), Register(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [Register(x), Register(y)]) => Sub { x: *x, y: *y },
+            ("SUBN", [Register(x), Register(y)]) => Subn { x: *x, y: *y },
+            // the one operand form shifts Vx, whichever shift quirk is on
+            ("SHR", [Register(x)]) => Shr { x: *x, y: *x },
+            ("SHR", [Register(x), Register(y)]) => Shr { x: *x, y: *y },
+            ("SHL", [Register(x)]) => Shl { x: *x, y: *x },
+            ("SHL", [Register(x), Register(y)]) => Shl { x: *x, y: *y },
+            ("RND", [Register(x), Value(nn)]) => Rnd {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Drw {
+                x: *x,
+                y: *y,
+                n: nibble(n)?,
+            },
+            ("SKP", [Register(x)]) => Skp(*x),
+            ("SKNP", [Register(x)]) => Sknp(*x),
+            ("PITCH", [Register(x)]) => Pitch(*x),
+            _ => {
+                return Err(if is_mnemonic(mnemonic) {
+                    format!("bad operands for `{}`", mnemonic)
+                } else {
+                    format!("unknown instruction `{}`", mnemonic)
+                })
+            }
+        };
+        Ok((instruction, None))
+    }
+}
+
+fn is_mnemonic(word: &str) -> bool {
+    const MNEMONICS: &[&str] = &[
+        "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "PLANE", "SYS",
+        "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN",
+        "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "PITCH",
+    ];
+    MNEMONICS.contains(&word)
+}
+
+fn is_symbol(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Remove a `;` comment, but not from inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// The first word of a line and the rest.
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    (&line[..end], line[end..].trim_start())
+}
+
+// Split operands at the commas outside of strings.
+fn split_operands(operands: &str) -> Vec<&str> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut result = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in operands.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                result.push(operands[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    result.push(operands[start..].trim());
+    result
+}
+
+fn parse_string(text: &str) -> Option<Vec<Byte>> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    Some(inner.bytes().collect())
+}
+
+fn parse_operand(text: &str) -> Message<Operand> {
+    let upper = text.to_ascii_uppercase();
+    Ok(match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            match Byte::from_str_radix(&upper[1..], 16) {
+                Ok(register) => Operand::Register(register),
+                Err(_) => Operand::Value(parse_expr(text)?),
+            }
+        }
+        _ if upper.starts_with("LONG ") => Operand::Long(parse_expr(&text[5..])?),
+        _ => Operand::Value(parse_expr(text)?),
+    })
+}
+
+// Expressions, by precedence climbing over the binary operators.
+fn parse_expr(text: &str) -> Message<Expr> {
+    let mut parser = ExprParser { text, pos: 0 };
+    let expr = parser.binary(0)?;
+    parser.skip_spaces();
+    if parser.pos < text.len() {
+        return Err(format!("unexpected `{}`", &text[parser.pos..]));
+    }
+    Ok(expr)
+}
+
+// binary operators from the lowest to the highest precedence
+const OPERATORS: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.rest().chars().next().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn binary(&mut self, level: usize) -> Message<Expr> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            self.skip_spaces();
+            let Some(&op) = OPERATORS[level]
+                .iter()
+                .find(|op| self.rest().starts_with(**op))
+            else {
+                return Ok(left);
+            };
+            self.pos += op.len();
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Message<Expr> {
+        self.skip_spaces();
+        let Some(c) = self.rest().chars().next() else {
+            return Err("expected a value".to_string());
+        };
+        match c {
+            '-' | '~' => {
+                self.pos += 1;
+                Ok(Expr::Unary(c, Box::new(self.unary()?)))
+            }
+            '(' => {
+                self.pos += 1;
+                let expr = self.binary(0)?;
+                self.skip_spaces();
+                if !self.rest().starts_with(')') {
+                    return Err("missing `)`".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            '*' => {
+                self.pos += 1;
+                Ok(Expr::Here)
+            }
+            _ => {
+                let len = self
+                    .rest()
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "_.#$".contains(c)))
+                    .unwrap_or(self.rest().len());
+                let word = &self.text[self.pos..self.pos + len];
+                if word.is_empty() {
+                    return Err(format!("unexpected `{}`", self.rest()));
+                }
+                self.pos += len;
+                if is_symbol(word) {
+                    Ok(Expr::Symbol(word.to_string()))
+                } else {
+                    parse_number(word)
+                        .map(Expr::Number)
+                        .ok_or_else(|| format!("bad number `{}`", word))
+                }
+            }
+        }
+    }
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('#').or(lower.strip_prefix('$')) {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::assemble;
+    use crate::disasm::{disassemble, Syntax};
+    use crate::quirks::Platform;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; draws a sprite and loops
+            SPEED = 2 * 2       ; constant
+            ROWS equ sprite_end - sprite
+            main:   LD V0, SPEED
+                    ld i, sprite
+                    DRW V0, V1, ROWS
+            loop:   JP loop
+                    SE V0, -1
+                    SHR V3
+            sprite: DB 0b11000011, 0x3C, \"AB\"
+            sprite_end:
+                    DW (sprite + 2) | 0x1000, *
+        ";
+        let program = assemble(source, Platform::CosmacVip).unwrap();
+        assert_eq!(
+            program.rom,
+            [
+                0x60, 0x04, 0xA2, 0x0C, 0xD0, 0x14, 0x12, 0x06, 0x30, 0xFF, 0x83, 0x36, 0xC3, 0x3C,
+                0x41, 0x42, 0x12, 0x0E, 0x02, 0x10
+            ]
+        );
+        assert_eq!(program.symbols["loop"], 0x206);
+        assert_eq!(program.symbols["ROWS"], 4);
+        assert!(program.symbol_file().contains("main = 0x0200\n"));
+        assert_eq!(program.lines[&0x206], ("<source>".to_string(), 8));
+        assert!(!program.lines.contains_key(&0x20C));
+    }
+
+    #[test]
+    fn test_platforms_and_org() {
+        let source = "
+            LD I, LONG data
+            ORG 0x300
+            data: HIGH
+        ";
+        assert!(assemble(source, Platform::CosmacVip).is_err());
+        let program = assemble(source, Platform::XoChip).unwrap();
+        assert_eq!(&program.rom[..4], [0xF0, 0x00, 0x03, 0x00]);
+        assert_eq!(program.rom.len(), 0x102);
+        assert_eq!(&program.rom[0x100..], [0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| {
+            assemble(source, Platform::CosmacVip)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            error("CLS\nLD V0, 256"),
+            "<source>:2: byte 256 is out of range"
+        );
+        assert_eq!(error("JP nowhere"), "<source>:1: unknown symbol `nowhere`");
+        assert_eq!(error("MOV V0, V1"), "<source>:1: unknown instruction `MOV`");
+        assert_eq!(error("DRW V0, 1, 2"), "<source>:1: bad operands for `DRW`");
+        assert!(error("a: CLS\na: CLS").starts_with("<source>:2: `a` is already defined"));
+        assert!(error("CLS\nORG 0x200\nCLS").starts_with("<source>:3: overwrites 0x200"));
+        assert_eq!(
+            error("LD V0, (0 - 9223372036854775807 - 1) / -1"),
+            "<source>:1: overflow in division"
+        );
+        // whitespace outside of ASCII between operands
+        assert_eq!(
+            assemble("LD V0, 1\u{a0}+ 2", Platform::CosmacVip)
+                .unwrap()
+                .rom,
+            [0x60, 0x03]
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let rom = [
+            0x00, 0xE0, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x06, 0xD0, 0x15, 0x00, 0xEE, 0xF0, 0x90,
+        ];
+        let listing = disassemble(&rom, Platform::CosmacVip, Syntax::Cowgod);
+        assert_eq!(assemble(&listing, Platform::CosmacVip).unwrap().rom, rom);
+    }
+}
@@ -1,804 +1,1883 @@
 use super::error::Error;
+use super::instruction::{self, Instruction};
+use super::quirks::{IndexIncrement, Platform, Quirks};
+use super::state::{self, StateReader, StateWriter};
+use super::trace::Tracer;
 use super::Result;
-use lazy_static::lazy_static;
 use rand::{self, Rng};
-use spin;
+use std::str::FromStr;
 use std::{fs::File, io::Read};
 
-type Byte = u8;
-type Word = u16;
+pub type Byte = u8;
+pub type Word = u16;
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+// SUPER-CHIP hi-res mode doubles both dimensions.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+// RGB colours of a pixel by its bit-planes: off, plane 1, plane 2, both planes.
+// Programs that only use the first plane are black and white.
+pub const PALETTE: [[Byte; 3]; 4] = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+];
+
+pub const PROGRAM_START: Word = 0x200;
+const STACK_DEPTH: usize = 16;
+const FONT_START: usize = 0x50;
+const BIG_FONT_START: usize = FONT_START + FONTSET.len();
+
+//The built-in hex digit sprites 0-F, 5 bytes each, stored at 0x50.
+const FONTSET: [Byte; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, //0
+    0x20, 0x60, 0x20, 0x20, 0x70, //1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, //2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, //3
+    0x90, 0x90, 0xF0, 0x10, 0x10, //4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, //5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, //6
+    0xF0, 0x10, 0x20, 0x40, 0x40, //7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, //8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, //9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, //A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, //B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, //C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, //D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, //E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, //F
+];
+
+//The SUPER-CHIP 8x10 digit sprites 0-F, 10 bytes each, stored right after the small font.
+const BIG_FONTSET: [Byte; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, //0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, //1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, //2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, //3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, //4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, //5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, //6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, //7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, //8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, //9
+    0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, //A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, //B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, //C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, //D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, //E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, //F
+];
+
+// What to do when the program runs into an opcode that does not exist
+// on the platform, or one that can't be executed like 00EE with an empty stack,
+// 2NNN with a full one or FX55 past the end of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultPolicy {
+    // stop at the faulting instruction and return the fault, e.g. `Error::Opcode`, from `step`
+    Halt,
+    // skip the instruction, the fault can still be fetched with `take_fault`
+    Skip,
+    // silently treat the instruction as a no-op
+    Nop,
+}
+
+impl FromStr for FaultPolicy {
+    type Err = Error;
 
-static mut GAMEMEMEORY: [Byte; 0x1000] = [0; 0x1000];
-static mut REGISTER: [Byte; 16] = [0; 16];
-static mut ADDRESS_I: Word = 0;
-static mut PROGCOUNTER: Word = 0;
-lazy_static! {
-    static ref STACK: spin::Mutex<Vec<Word>> = spin::Mutex::new(Vec::new());
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "halt" => Ok(FaultPolicy::Halt),
+            "skip" => Ok(FaultPolicy::Skip),
+            "nop" => Ok(FaultPolicy::Nop),
+            _ => Err(Error::Config(format!("unknown fault policy `{}`", s))),
+        }
+    }
 }
-static mut SCREENDATA: [[Byte; 32]; 64] = [[0; 32]; 64];
-static mut DELAY_TIMER: Byte = 60;
-static mut SOUND_TIMER: Byte = 60;
-static mut KEY: [Byte; 0x10] = [0; 0x10];
-static mut CLEARFLAG: bool = false;
-
-pub fn cpu_reset(path: String) -> Result<()> {
-    unsafe {
-        ADDRESS_I = 0;
-        PROGCOUNTER = 0x200;
-        REGISTER = [0; 16];
-        delay_timer();
-        sound_timer();
-        set_sprite();
-    }
-
-    let mut file = File::open(path)?;
-    unsafe {
-        file.read(&mut GAMEMEMEORY[0x200..])?;
-    }
-    Ok(())
+
+// A register or memory byte touched by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Register(Byte),
+    Memory(usize),
 }
 
-pub unsafe fn execute() -> Result<()> {
-    let code1 = GAMEMEMEORY[PROGCOUNTER as usize];
-    let code2 = GAMEMEMEORY[PROGCOUNTER as usize + 1];
-    let opcode = (code1 as u16) * 256 + code2 as Word;
-    PROGCOUNTER += 2;
-
-    match code1 >> 4 {
-        0 => match code2 {
-            0xE0 => opcode_00e0(),
-            0xEE => opcode_00ee()?,
-            _ => opcode_0nnn(opcode),
-        },
-
-        1 => opcode_1nnn(opcode),
-        2 => opcode_2nnn(opcode),
-        3 => opcode_3xnn(opcode),
-        4 => opcode_4xnn(opcode),
-        5 => opcode_5xy0(opcode),
-        6 => opcode_6xnn(opcode),
-        7 => opcode_7xnn(opcode),
-        8 => match code2 & 0x0F {
-            0 => opcode_8xy0(opcode),
-            1 => opcode_8xy1(opcode),
-            2 => opcode_8xy2(opcode),
-            3 => opcode_8xy3(opcode),
-            4 => opcode_8xy4(opcode),
-            5 => opcode_8xy5(opcode),
-            6 => opcode_8xy6(opcode),
-            7 => opcode_8xy7(opcode),
-            0xE => opcode_8xye(opcode),
-            _ => unimplemented!(), //todo: Err(e) after implementation of my own Error
-        },
-        9 => opcode_9xy0(opcode),
-        0xA => opcode_annn(opcode),
-        0xB => opcode_bnnn(opcode),
-        0xC => opcode_cxnn(opcode),
-        0xD => opcode_dxyn(opcode),
-        0xE => match code2 {
-            0x9E => opcode_ex9e(opcode),
-            0xA1 => opcode_exa1(opcode),
-            _ => unimplemented!(),
-        },
-        0xF => match code2 {
-            0x07 => opcode_fx07(opcode),
-            0x0A => opcode_fx0a(opcode),
-            0x15 => opcode_fx15(opcode),
-            0x18 => opcode_fx18(opcode),
-            0x1E => opcode_fx1e(opcode),
-            0x29 => opcode_fx29(opcode),
-            0x33 => opcode_fx33(opcode),
-            0x55 => opcode_fx55(opcode),
-            0x65 => opcode_fx65(opcode),
-            _ => unimplemented!(),
-        },
-        _ => (),
-    }
-    Ok(())
+// An access reported to the debug hook: a read, or a write with the value it replaced.
+// Fetching instructions doesn't count as a read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(Location),
+    Write(Location, Byte),
 }
 
-unsafe fn set_sprite() {
-    //0
-    GAMEMEMEORY[0x50] = 0xF0;
-    GAMEMEMEORY[0x51] = 0x90;
-    GAMEMEMEORY[0x52] = 0x90;
-    GAMEMEMEORY[0x53] = 0x90;
-    GAMEMEMEORY[0x54] = 0xF0;
-    //1
-    GAMEMEMEORY[0x55] = 0x20;
-    GAMEMEMEORY[0x56] = 0x60;
-    GAMEMEMEORY[0x57] = 0x20;
-    GAMEMEMEORY[0x58] = 0x20;
-    GAMEMEMEORY[0x59] = 0x70;
-    //2
-    GAMEMEMEORY[0x5A] = 0xF0;
-    GAMEMEMEORY[0x5B] = 0x10;
-    GAMEMEMEORY[0x5C] = 0xF0;
-    GAMEMEMEORY[0x5D] = 0x80;
-    GAMEMEMEORY[0x5E] = 0xF0;
-    //3
-    GAMEMEMEORY[0x5F] = 0xF0;
-    GAMEMEMEORY[0x60] = 0x10;
-    GAMEMEMEORY[0x61] = 0xF0;
-    GAMEMEMEORY[0x62] = 0x10;
-    GAMEMEMEORY[0x63] = 0xF0;
-    //4
-    GAMEMEMEORY[0x64] = 0x90;
-    GAMEMEMEORY[0x65] = 0x90;
-    GAMEMEMEORY[0x66] = 0xF0;
-    GAMEMEMEORY[0x67] = 0x10;
-    GAMEMEMEORY[0x68] = 0x10;
-    //5
-    GAMEMEMEORY[0x69] = 0xF0;
-    GAMEMEMEORY[0x6A] = 0x80;
-    GAMEMEMEORY[0x6B] = 0xF0;
-    GAMEMEMEORY[0x6C] = 0x10;
-    GAMEMEMEORY[0x6D] = 0xF0;
-    //6
-    GAMEMEMEORY[0x6E] = 0xF0;
-    GAMEMEMEORY[0x6F] = 0x80;
-    GAMEMEMEORY[0x70] = 0xF0;
-    GAMEMEMEORY[0x71] = 0x90;
-    GAMEMEMEORY[0x72] = 0xF0;
-    //7
-    GAMEMEMEORY[0x73] = 0xF0;
-    GAMEMEMEORY[0x74] = 0x10;
-    GAMEMEMEORY[0x75] = 0x22;
-    GAMEMEMEORY[0x76] = 0x40;
-    GAMEMEMEORY[0x77] = 0x40;
-    //8
-    GAMEMEMEORY[0x78] = 0xF0;
-    GAMEMEMEORY[0x79] = 0x90;
-    GAMEMEMEORY[0x7A] = 0xF0;
-    GAMEMEMEORY[0x7B] = 0x90;
-    GAMEMEMEORY[0x7C] = 0xF0;
-    //9
-    GAMEMEMEORY[0x7D] = 0xF0;
-    GAMEMEMEORY[0x7E] = 0x90;
-    GAMEMEMEORY[0x7F] = 0xF0;
-    GAMEMEMEORY[0x80] = 0x10;
-    GAMEMEMEORY[0x81] = 0xF0;
-    //A
-    GAMEMEMEORY[0x82] = 0xF0;
-    GAMEMEMEORY[0x83] = 0x90;
-    GAMEMEMEORY[0x84] = 0xF0;
-    GAMEMEMEORY[0x85] = 0x90;
-    GAMEMEMEORY[0x86] = 0x90;
-    //B
-    GAMEMEMEORY[0x87] = 0xE0;
-    GAMEMEMEORY[0x88] = 0x90;
-    GAMEMEMEORY[0x89] = 0xE0;
-    GAMEMEMEORY[0x8A] = 0x90;
-    GAMEMEMEORY[0x8B] = 0xE0;
-    //C
-    GAMEMEMEORY[0x8C] = 0xE0;
-    GAMEMEMEORY[0x8D] = 0x90;
-    GAMEMEMEORY[0x8E] = 0xE0;
-    GAMEMEMEORY[0x8F] = 0x90;
-    GAMEMEMEORY[0x90] = 0xE0;
-    //D
-    GAMEMEMEORY[0x91] = 0xE0;
-    GAMEMEMEORY[0x92] = 0x90;
-    GAMEMEMEORY[0x93] = 0x90;
-    GAMEMEMEORY[0x94] = 0x90;
-    GAMEMEMEORY[0x95] = 0xE0;
-    //E
-    GAMEMEMEORY[0x96] = 0xF0;
-    GAMEMEMEORY[0x97] = 0x80;
-    GAMEMEMEORY[0x98] = 0xF0;
-    GAMEMEMEORY[0x99] = 0x80;
-    GAMEMEMEORY[0x9A] = 0xF0;
-    //F
-    GAMEMEMEORY[0x9B] = 0xF0;
-    GAMEMEMEORY[0x9C] = 0x80;
-    GAMEMEMEORY[0x9D] = 0xF0;
-    GAMEMEMEORY[0x9E] = 0x80;
-    GAMEMEMEORY[0x9F] = 0x80;
+// Progress of FX0A, which waits for a key to be pressed and released again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyWait {
+    None,
+    // FX0A is waiting for a key press
+    Waiting,
+    // the key was pressed, FX0A completes once it is released
+    Pressed(Byte),
 }
 
-pub unsafe fn delay_timer() {
-    std::thread::spawn(|| loop {
-        if DELAY_TIMER == 0 {
-            DELAY_TIMER = 60;
+// A whole chip8 machine: memory, registers, stack, display, timers and keypad.
+// Every machine owns its own state, so several of them can run in one process.
+pub struct Chip8 {
+    // 4 KiB, or 64 KiB on XO-CHIP
+    memory: Vec<Byte>,
+    register: [Byte; 16],
+    address_i: Word,
+    progcounter: Word,
+    stack: Vec<Word>,
+    // always hi-res sized, lo-res mode only uses the top left 64x32 pixels.
+    // Bit 0 of every pixel is the first bit-plane, bit 1 the second XO-CHIP plane.
+    screen: [[Byte; HIRES_HEIGHT]; HIRES_WIDTH],
+    hires: bool,
+    // XO-CHIP bit-planes selected by FN01, drawing and scrolling only touch these
+    plane: Byte,
+    delay_timer: Byte,
+    sound_timer: Byte,
+    key: [Byte; 0x10],
+    key_wait: KeyWait,
+    // set whenever the screen changes, so frontends only redraw when needed
+    draw_flag: bool,
+    platform: Platform,
+    quirks: Quirks,
+    // set by DXYN when the display wait quirk is on, ends the current frame
+    vblank_wait: bool,
+    // SUPER-CHIP RPL user flags, saved and loaded by FX75/FX85
+    rpl: [Byte; 16],
+    // set by 00FD, the program has exited
+    halted: bool,
+    // XO-CHIP 1-bit audio pattern loaded by F002 and its pitch set by FX3A
+    audio_pattern: [Byte; 16],
+    pitch: Byte,
+    fault_policy: FaultPolicy,
+    // address of the instruction being executed, for error reports
+    instruction_pc: Word,
+    opcode: Word,
+    // the last fault skipped with `FaultPolicy::Skip`
+    last_fault: Option<Error>,
+    // crc32 of the loaded rom, save states are tagged with it
+    rom_hash: u32,
+    // the debug hook: when watching, every access of the last instruction
+    // to a register or memory is recorded in `accesses`
+    watching: bool,
+    accesses: Vec<Access>,
+    // the execution trace, written before every instruction
+    tracer: Option<Tracer>,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chip8 {
+    pub fn new() -> Self {
+        Self::with_platform(Platform::CosmacVip)
+    }
+
+    pub fn with_platform(platform: Platform) -> Self {
+        let mut chip8 = Chip8 {
+            memory: vec![0; platform.memory_size()],
+            register: [0; 16],
+            address_i: 0,
+            progcounter: PROGRAM_START,
+            stack: Vec::new(),
+            screen: [[0; HIRES_HEIGHT]; HIRES_WIDTH],
+            hires: false,
+            plane: 1,
+            delay_timer: 0,
+            sound_timer: 0,
+            key: [0; 0x10],
+            key_wait: KeyWait::None,
+            draw_flag: true,
+            platform,
+            quirks: platform.quirks(),
+            vblank_wait: false,
+            rpl: [0; 16],
+            halted: false,
+            audio_pattern: [0; 16],
+            pitch: 64,
+            fault_policy: FaultPolicy::Halt,
+            instruction_pc: PROGRAM_START,
+            opcode: 0,
+            last_fault: None,
+            rom_hash: 0,
+            watching: false,
+            accesses: Vec::new(),
+            tracer: None,
+        };
+        chip8.memory[FONT_START..FONT_START + FONTSET.len()].copy_from_slice(&FONTSET);
+        chip8.memory[BIG_FONT_START..BIG_FONT_START + BIG_FONTSET.len()]
+            .copy_from_slice(&BIG_FONTSET);
+        chip8
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // override the quirks of the platform, e.g. from a config file
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_fault_policy(&mut self, fault_policy: FaultPolicy) {
+        self.fault_policy = fault_policy;
+    }
+
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.fault_policy
+    }
+
+    // the last fault that was skipped, if any
+    pub fn take_fault(&mut self) -> Option<Error> {
+        self.last_fault.take()
+    }
+
+    // load the rom file at `path` into memory, starting from 0x200
+    pub fn load_rom(&mut self, path: &str) -> Result<()> {
+        let mut rom = Vec::new();
+        File::open(path)?.read_to_end(&mut rom)?;
+        self.load_bytes(&rom)
+    }
+
+    // load a rom that is already in memory, starting from 0x200
+    pub fn load_bytes(&mut self, rom: &[Byte]) -> Result<()> {
+        let start = PROGRAM_START as usize;
+        if rom.len() > self.memory.len() - start {
+            return Err(Error::RomSize(rom.len()));
+        }
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.rom_hash = state::crc32(rom);
+        Ok(())
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    // Snapshot the complete machine into a versioned, checksummed save state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u8(match self.platform {
+            Platform::CosmacVip => 0,
+            Platform::Chip48 => 1,
+            Platform::SuperChip => 2,
+            Platform::XoChip => 3,
+        });
+        w.bool(self.quirks.shift_vy);
+        w.u8(match self.quirks.index_increment {
+            IndexIncrement::XPlusOne => 0,
+            IndexIncrement::X => 1,
+            IndexIncrement::None => 2,
+        });
+        w.bool(self.quirks.vf_reset);
+        w.bool(self.quirks.jump_vx);
+        w.bool(self.quirks.wrap_sprites);
+        w.bool(self.quirks.display_wait);
+        w.bool(self.quirks.memory_wrap);
+
+        w.bytes(&self.memory);
+        w.bytes(&self.register);
+        w.u16(self.address_i);
+        w.u16(self.progcounter);
+        w.u8(self.stack.len() as u8);
+        for &address in &self.stack {
+            w.u16(address);
+        }
+        for column in &self.screen {
+            w.bytes(column);
+        }
+        w.bool(self.hires);
+        w.u8(self.plane);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        w.bytes(&self.key);
+        match self.key_wait {
+            KeyWait::None => w.u8(0),
+            KeyWait::Waiting => w.u8(1),
+            KeyWait::Pressed(key) => {
+                w.u8(2);
+                w.u8(key);
+            }
+        }
+        w.bool(self.vblank_wait);
+        w.bytes(&self.rpl);
+        w.bool(self.halted);
+        w.bytes(&self.audio_pattern);
+        w.u8(self.pitch);
+        state::seal(self.rom_hash, &w.finish())
+    }
+
+    // Restore a state written by `save_state` for the same rom. The keys held
+    // right now stay held. On error the machine is left unchanged.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let bad = |what: &str| Error::State(format!("bad {}", what));
+        let mut r = StateReader::new(state::open(data, self.rom_hash)?);
+        let platform = match r.u8()? {
+            0 => Platform::CosmacVip,
+            1 => Platform::Chip48,
+            2 => Platform::SuperChip,
+            3 => Platform::XoChip,
+            _ => return Err(bad("platform")),
+        };
+        let mut chip8 = Chip8::with_platform(platform);
+        chip8.quirks.shift_vy = r.bool()?;
+        chip8.quirks.index_increment = match r.u8()? {
+            0 => IndexIncrement::XPlusOne,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::None,
+            _ => return Err(bad("index increment")),
+        };
+        chip8.quirks.vf_reset = r.bool()?;
+        chip8.quirks.jump_vx = r.bool()?;
+        chip8.quirks.wrap_sprites = r.bool()?;
+        chip8.quirks.display_wait = r.bool()?;
+        chip8.quirks.memory_wrap = r.bool()?;
+
+        let memory_size = chip8.memory.len();
+        chip8.memory.copy_from_slice(r.bytes(memory_size)?);
+        chip8.register = r.array()?;
+        chip8.address_i = r.u16()?;
+        chip8.progcounter = r.u16()?;
+        let depth = r.u8()? as usize;
+        if depth > STACK_DEPTH {
+            return Err(bad("stack depth"));
+        }
+        for _ in 0..depth {
+            chip8.stack.push(r.u16()?);
+        }
+        for column in chip8.screen.iter_mut() {
+            *column = r.array()?;
+        }
+        chip8.hires = r.bool()?;
+        chip8.plane = r.u8()?;
+        chip8.delay_timer = r.u8()?;
+        chip8.sound_timer = r.u8()?;
+        // the keypad is what the player holds now, not what they held back then
+        let _: [Byte; 0x10] = r.array()?;
+        chip8.key_wait = match r.u8()? {
+            0 => KeyWait::None,
+            1 => KeyWait::Waiting,
+            2 => KeyWait::Pressed(r.u8()? & 0x0F),
+            _ => return Err(bad("key wait")),
+        };
+        chip8.vblank_wait = r.bool()?;
+        chip8.rpl = r.array()?;
+        chip8.halted = r.bool()?;
+        chip8.audio_pattern = r.array()?;
+        chip8.pitch = r.u8()?;
+        if !r.is_empty() {
+            return Err(bad("length"));
+        }
+
+        chip8.fault_policy = self.fault_policy;
+        chip8.rom_hash = self.rom_hash;
+        chip8.watching = self.watching;
+        chip8.key = self.key;
+        chip8.set_tracer(self.tracer.take());
+        *self = chip8;
+        Ok(())
+    }
+
+    // fetch, decode and execute one instruction.
+    pub fn step(&mut self) -> Result<()> {
+        if self.halted {
+            return Ok(());
+        }
+
+        //Unlike 8086, chip8 store higher bits in lower address
+        //and store lower bits in higher address
+        //like 0x1234, the 0x12 will store in 0x200
+        // and the 0x34 will store in 0x201
+        // (It called Big-endian?)
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self);
+            self.tracer = Some(tracer);
+        }
+        self.instruction_pc = self.progcounter;
+        self.opcode = 0;
+        self.accesses.clear();
+        let result = self.fetch().and_then(|opcode| self.execute(opcode));
+        if result.is_err() {
+            // leave the machine at the faulting instruction
+            self.progcounter = self.instruction_pc;
+        }
+        result
+    }
+
+    fn fetch(&mut self) -> Result<Word> {
+        let pc = self.progcounter as usize;
+        let code1 = self.fetch_byte(pc)?;
+        let code2 = self.fetch_byte(pc + 1)?;
+        self.opcode = (code1 as Word) << 8 | code2 as Word;
+        self.progcounter = self.progcounter.wrapping_add(2);
+        Ok(self.opcode)
+    }
+
+    fn execute(&mut self, opcode: Word) -> Result<()> {
+        let instruction = match instruction::decode(opcode, self.platform) {
+            Ok(instruction) => instruction,
+            Err(_) => return self.fault(opcode),
+        };
+        match instruction {
+            Instruction::Sys(nnn) => self.opcode_0nnn(nnn),
+            Instruction::Cls => self.opcode_00e0(),
+            Instruction::Ret => self.opcode_00ee()?,
+            Instruction::Scd(n) => self.opcode_00cn(n),
+            Instruction::Scu(n) => self.opcode_00dn(n),
+            Instruction::Scr => self.opcode_00fb(),
+            Instruction::Scl => self.opcode_00fc(),
+            Instruction::Exit => self.opcode_00fd(),
+            Instruction::Low => self.opcode_00fe(),
+            Instruction::High => self.opcode_00ff(),
+            Instruction::Jp(nnn) => self.opcode_1nnn(nnn),
+            Instruction::Call(nnn) => self.opcode_2nnn(nnn)?,
+            Instruction::SeByte { x, nn } => self.opcode_3xnn(x, nn),
+            Instruction::SneByte { x, nn } => self.opcode_4xnn(x, nn),
+            Instruction::Se { x, y } => self.opcode_5xy0(x, y),
+            Instruction::SaveRange { x, y } => self.opcode_5xy2(x, y)?,
+            Instruction::LoadRange { x, y } => self.opcode_5xy3(x, y)?,
+            Instruction::LdByte { x, nn } => self.opcode_6xnn(x, nn),
+            Instruction::AddByte { x, nn } => self.opcode_7xnn(x, nn),
+            Instruction::Ld { x, y } => self.opcode_8xy0(x, y),
+            Instruction::Or { x, y } => self.opcode_8xy1(x, y),
+            Instruction::And { x, y } => self.opcode_8xy2(x, y),
+            Instruction::Xor { x, y } => self.opcode_8xy3(x, y),
+            Instruction::Add { x, y } => self.opcode_8xy4(x, y),
+            Instruction::Sub { x, y } => self.opcode_8xy5(x, y),
+            Instruction::Shr { x, y } => self.opcode_8xy6(x, y),
+            Instruction::Subn { x, y } => self.opcode_8xy7(x, y),
+            Instruction::Shl { x, y } => self.opcode_8xye(x, y),
+            Instruction::Sne { x, y } => self.opcode_9xy0(x, y),
+            Instruction::LdI(nnn) => self.opcode_annn(nnn),
+            Instruction::JpV0(nnn) => self.opcode_bnnn(nnn),
+            Instruction::Rnd { x, nn } => self.opcode_cxnn(x, nn),
+            Instruction::Drw { x, y, n } => self.opcode_dxyn(x, y, n)?,
+            Instruction::Skp(x) => self.opcode_ex9e(x),
+            Instruction::Sknp(x) => self.opcode_exa1(x),
+            Instruction::LdILong => self.opcode_f000()?,
+            Instruction::Plane(n) => self.opcode_fn01(n),
+            Instruction::Audio => self.opcode_f002()?,
+            Instruction::LdVxDt(x) => self.opcode_fx07(x),
+            Instruction::LdVxK(x) => self.opcode_fx0a(x),
+            Instruction::LdDtVx(x) => self.opcode_fx15(x),
+            Instruction::LdStVx(x) => self.opcode_fx18(x),
+            Instruction::AddI(x) => self.opcode_fx1e(x),
+            Instruction::LdF(x) => self.opcode_fx29(x),
+            Instruction::LdHf(x) => self.opcode_fx30(x),
+            Instruction::LdB(x) => self.opcode_fx33(x)?,
+            Instruction::Pitch(x) => self.opcode_fx3a(x),
+            Instruction::LdIVx(x) => self.opcode_fx55(x)?,
+            Instruction::LdVxI(x) => self.opcode_fx65(x)?,
+            Instruction::LdRVx(x) => self.opcode_fx75(x),
+            Instruction::LdVxR(x) => self.opcode_fx85(x),
+        }
+        Ok(())
+    }
+
+    // Run one 60Hz frame: `ipf` instructions followed by one timer tick.
+    // With the display wait quirk the frame ends early at the first DXYN.
+    pub fn run_frame(&mut self, ipf: u32) -> Result<()> {
+        self.run_frame_until(ipf, |_| false)?;
+        Ok(())
+    }
+
+    // Like `run_frame`, but `stop` is asked before every instruction.
+    // If it returns true the frame ends right there without ticking the timers,
+    // and `Ok(true)` is returned. Debuggers use this to pause mid-frame.
+    pub fn run_frame_until(
+        &mut self,
+        ipf: u32,
+        mut stop: impl FnMut(&Chip8) -> bool,
+    ) -> Result<bool> {
+        self.vblank_wait = false;
+        for _ in 0..ipf {
+            if stop(self) {
+                return Ok(true);
+            }
+            self.step()?;
+            if self.vblank_wait {
+                break;
+            }
+        }
+        self.tick_timers();
+        Ok(false)
+    }
+
+    // Count both timers down by one, stopping at zero.
+    // Call this 60 times per second of emulated time, i.e. once per frame.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    // exposed api for keys.
+    pub fn key_down(&mut self, key_code: Byte) {
+        self.key[key_code as usize] = 1;
+        if self.key_wait == KeyWait::Waiting {
+            self.key_wait = KeyWait::Pressed(key_code);
+        }
+    }
+
+    pub fn key_up(&mut self, key_code: Byte) {
+        self.key[key_code as usize] = 0;
+    }
+
+    // the value of register VX
+    pub fn register(&self, x: usize) -> Byte {
+        self.register[x]
+    }
+
+    pub fn pc(&self) -> Word {
+        self.progcounter
+    }
+
+    pub fn index(&self) -> Word {
+        self.address_i
+    }
+
+    // return addresses of the subroutines being executed, the innermost last
+    pub fn stack(&self) -> &[Word] {
+        &self.stack
+    }
+
+    pub fn delay_timer(&self) -> Byte {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> Byte {
+        self.sound_timer
+    }
+
+    pub fn memory(&self) -> &[Byte] {
+        &self.memory
+    }
+
+    // changing the machine from the outside, for debuggers
+    pub fn set_register(&mut self, x: usize, value: Byte) {
+        self.register[x] = value;
+    }
+
+    pub fn set_pc(&mut self, pc: Word) {
+        self.progcounter = pc;
+    }
+
+    // drop return addresses or push zeros, up to the depth of the stack
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack.resize(depth.min(STACK_DEPTH), 0);
+    }
+
+    pub fn set_index(&mut self, i: Word) {
+        self.address_i = i;
+    }
+
+    pub fn set_delay_timer(&mut self, value: Byte) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: Byte) {
+        self.sound_timer = value;
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [Byte] {
+        &mut self.memory
+    }
+
+    // turn the debug hook on or off
+    pub fn set_watching(&mut self, watching: bool) {
+        self.watching = watching;
+        self.accesses.clear();
+    }
+
+    // Trace every instruction from now on, or stop tracing with `None`.
+    pub fn set_tracer(&mut self, mut tracer: Option<Tracer>) {
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.screen_changed();
+        }
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // registers and memory accessed by the last instruction, in order, while watching
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    // exposed api for viewing the screen
+    pub fn pixel(&self, x: usize, y: usize) -> Byte {
+        self.screen[x][y]
+    }
+
+    // crc32 of the visible pixels, row by row, to compare displays
+    pub fn display_hash(&self) -> u32 {
+        let mut pixels = Vec::with_capacity(self.width() * self.height());
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                pixels.push(self.screen[x][y]);
+            }
+        }
+        state::crc32(&pixels)
+    }
+
+    // whether the program is blocked in FX0A until a key is pressed and released
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait != KeyWait::None
+    }
+
+    // whether the screen changed since the last call
+    pub fn take_draw_flag(&mut self) -> bool {
+        std::mem::take(&mut self.draw_flag)
+    }
+
+    // size of the screen in the current resolution
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
         } else {
-            DELAY_TIMER -= 1;
+            SCREEN_WIDTH
         }
-        let dura = std::time::Duration::from_millis(16);
-        std::thread::sleep(dura);
-    });
-}
+    }
 
-pub unsafe fn sound_timer() {
-    std::thread::spawn(|| loop {
-        if SOUND_TIMER == 0 {
-            SOUND_TIMER = 60;
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
         } else {
-            SOUND_TIMER -= 1;
+            SCREEN_HEIGHT
         }
-        let dura = std::time::Duration::from_millis(16);
-        std::thread::sleep(dura);
-    });
-}
+    }
 
-//Unlike 8086, chip8 store higher bits in lower address
-//and store lower ibts in higher address
-//like 0x1234, the 0x12 will store in 0x200
-// and the 0x34 will store in 0x201
-// (It called Big-endian?)
-pub fn get_nexe_opcode() -> Word {
-    let mut res = unsafe { GAMEMEMEORY[PROGCOUNTER as usize] as Word };
-    // shifit lower bits into higer bits => 0x12->0x1200
-    res <<= 8;
-    unsafe {
-        res |= GAMEMEMEORY[PROGCOUNTER as usize + 1] as u16;
-        PROGCOUNTER += 2;
-    }
-    res
-}
-pub fn opcode_0nnn(opcode: Word) {
-    unsafe { PROGCOUNTER = opcode }
-}
+    // whether the program has exited with 00FD
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
 
-//00e0 - clear the screen
-pub fn opcode_00e0() {
-    unsafe {
-        for i in 0..64 {
-            for j in 0..32 {
-                SCREENDATA[i][j] = 0;
+    // The buzzer sounds as long as the sound timer is running.
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // XO-CHIP audio: the 16 byte (128 bit) pattern is played back
+    // at `playback_rate()` bits per second while the sound timer is running.
+    pub fn audio_pattern(&self) -> &[Byte; 16] {
+        &self.audio_pattern
+    }
+
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    // Report a faulting opcode according to the fault policy.
+    fn fault(&mut self, opcode: Word) -> Result<()> {
+        self.report(Error::Opcode {
+            opcode,
+            pc: self.instruction_pc,
+            i: self.address_i,
+            registers: self.register,
+        })
+    }
+
+    // Report a fault according to the fault policy.
+    fn report(&mut self, fault: Error) -> Result<()> {
+        match self.fault_policy {
+            FaultPolicy::Halt => Err(fault),
+            FaultPolicy::Skip => {
+                self.last_fault = Some(fault);
+                Ok(())
             }
+            FaultPolicy::Nop => Ok(()),
         }
-        CLEARFLAG = true;
     }
-}
 
-//00EE - return from a subroutine
-pub fn opcode_00ee() -> Result<()> {
-    unsafe {
-        PROGCOUNTER = STACK
-            .lock()
-            .pop()
-            .ok_or(Error::Opcode("00EE".to_string()))?;
+    // Check an address against the size of memory.
+    // Depending on the platform, addresses past the end wrap around or fault.
+    fn address(&self, address: usize) -> Result<usize> {
+        if address < self.memory.len() {
+            Ok(address)
+        } else if self.quirks.memory_wrap {
+            Ok(address % self.memory.len())
+        } else {
+            Err(Error::Address {
+                address,
+                opcode: self.opcode,
+                pc: self.instruction_pc,
+            })
+        }
     }
-    Ok(())
-}
 
-//1NNN is the opcode for jump instruction.
-pub fn opcode_1nnn(opcode: Word) {
-    unsafe {
-        PROGCOUNTER = opcode & 0x0FFF;
+    // Check that `len` bytes from `start` can be accessed before an instruction touches
+    // any of them, so it never faults halfway. Out of memory is a fault for the fault
+    // policy, returns whether the instruction can go ahead.
+    fn check_range(&mut self, start: usize, len: usize) -> Result<bool> {
+        let end = start + len;
+        if len == 0 || end <= self.memory.len() || self.quirks.memory_wrap {
+            return Ok(true);
+        }
+        self.report(Error::Address {
+            address: start.max(self.memory.len()),
+            opcode: self.opcode,
+            pc: self.instruction_pc,
+        })?;
+        Ok(false)
     }
-}
 
-//2NNN - Call subroutine at NNN
-pub fn opcode_2nnn(opcode: Word) {
-    unsafe {
-        STACK.lock().push(PROGCOUNTER);
-        PROGCOUNTER = opcode & 0xFFF;
+    // read a byte of the program itself, not reported to the debug hook
+    fn fetch_byte(&self, address: usize) -> Result<Byte> {
+        Ok(self.memory[self.address(address)?])
+    }
+
+    fn read(&mut self, address: usize) -> Result<Byte> {
+        let address = self.address(address)?;
+        self.hook(Access::Read(Location::Memory(address)));
+        Ok(self.memory[address])
+    }
+
+    fn write(&mut self, address: usize, value: Byte) -> Result<()> {
+        let address = self.address(address)?;
+        self.hook(Access::Write(
+            Location::Memory(address),
+            self.memory[address],
+        ));
+        self.memory[address] = value;
+        Ok(())
+    }
+
+    // Vx, reported to the debug hook
+    fn vx(&mut self, x: Byte) -> Byte {
+        self.hook(Access::Read(Location::Register(x)));
+        self.register[x as usize]
+    }
+
+    fn set_vx(&mut self, x: Byte, value: Byte) {
+        self.hook(Access::Write(
+            Location::Register(x),
+            self.register[x as usize],
+        ));
+        self.register[x as usize] = value;
     }
-}
 
-//3XNN - if Vx == NN, then skip the next instruction.
-pub fn opcode_3xnn(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let nn = (opcode & 0x00FF) as u8;
-    unsafe {
-        if REGISTER[regx as usize] == nn {
-            PROGCOUNTER += 2;
+    fn hook(&mut self, access: Access) {
+        if self.watching {
+            self.accesses.push(access);
         }
     }
-}
 
-//4XNN - if Vx != NN, then skip the next instruction
-pub fn opcode_4xnn(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let nn = (opcode & 0x00FF) as u8;
-    unsafe {
-        if REGISTER[regx as usize] != nn {
-            PROGCOUNTER += 2;
+    fn supports_schip(&self) -> bool {
+        matches!(self.platform, Platform::SuperChip | Platform::XoChip)
+    }
+
+    fn supports_xochip(&self) -> bool {
+        self.platform == Platform::XoChip
+    }
+
+    // Skip the next instruction. On XO-CHIP, F000 NNNN is 4 bytes long
+    // and is skipped as a whole.
+    fn skip_next(&mut self) {
+        let pc = self.progcounter as usize;
+        let next = (self.memory.get(pc), self.memory.get(pc + 1));
+        if self.supports_xochip() && next == (Some(&0xF0), Some(&0x00)) {
+            self.progcounter = self.progcounter.wrapping_add(4);
+        } else {
+            self.progcounter = self.progcounter.wrapping_add(2);
         }
     }
-}
 
-//5xy0 - if x==y,then skip the next instruction.
-pub fn opcode_5xy0(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        if REGISTER[regx as usize] == REGISTER[regy as usize] {
-            PROGCOUNTER += 2;
+    // Move the selected planes of the screen by (dx, dy) pixels,
+    // pixels moved in from the edges are blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let old = self.screen;
+        for x in 0..width {
+            for y in 0..height {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    old[from_x as usize][from_y as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.screen[x as usize][y as usize];
+                *pixel = (*pixel & !self.plane) | (moved & self.plane);
+            }
         }
+        self.draw_flag = true;
     }
-}
 
-// 6XNN - Set Vx = NN
-pub fn opcode_6xnn(opcode: Word) {
-    let regx = (opcode & 0xF00) >> 8;
-    let nn = (opcode & 0x00FF) as Byte;
-    unsafe {
-        REGISTER[regx as usize] = nn;
+    fn opcode_0nnn(&mut self, nnn: Word) {
+        self.progcounter = nnn;
     }
-}
 
-// 7XNN -  Vx += NN, carry flag is not changed
-pub fn opcode_7xnn(opcode: Word) {
-    let regx = (opcode & 0xF00) >> 8;
-    let nn = (opcode & 0x00FF) as Byte;
-    unsafe {
-        REGISTER[regx as usize] = REGISTER[regx as usize].wrapping_add(nn);
+    //00e0 - clear the screen (only the selected planes on XO-CHIP)
+    fn opcode_00e0(&mut self) {
+        let keep = if self.supports_xochip() {
+            !self.plane & 0x3
+        } else {
+            0
+        };
+        for column in self.screen.iter_mut() {
+            for pixel in column.iter_mut() {
+                *pixel &= keep;
+            }
+        }
+        self.draw_flag = true;
     }
-}
 
-//8XY0 - Vx = Vy
-pub fn opcode_8xy0(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        REGISTER[regx as usize] = REGISTER[regy as usize];
+    //00CN - scroll the screen down N pixels
+    fn opcode_00cn(&mut self, n: Byte) {
+        self.scroll(0, n as isize);
     }
-}
 
-//8XY1 - Vx |= Vy
-pub fn opcode_8xy1(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        REGISTER[regx as usize] |= REGISTER[regy as usize];
+    //00DN - scroll the screen up N pixels
+    fn opcode_00dn(&mut self, n: Byte) {
+        self.scroll(0, -(n as isize));
     }
-}
 
-//8XY2 - Vx &= Vy
-pub fn opcode_8xy2(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        REGISTER[regx as usize] &= REGISTER[regy as usize];
+    //00FB - scroll the screen right 4 pixels
+    fn opcode_00fb(&mut self) {
+        self.scroll(4, 0);
     }
-}
 
-//8XY3 - Vx ^= Vy
-pub fn opcode_8xy3(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        REGISTER[regx as usize] ^= REGISTER[regy as usize];
+    //00FC - scroll the screen left 4 pixels
+    fn opcode_00fc(&mut self) {
+        self.scroll(-4, 0);
     }
-}
 
-//8XY4 - Vx += Vy, set carry flag to 1 if overflow ,otherwise 0.
-pub fn opcode_8xy4(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        let (result, carry) = REGISTER[regx as usize].overflowing_add(REGISTER[regy as usize]);
-        REGISTER[regx as usize] = result;
-        REGISTER[0xF] = carry.into();
+    //00FD - exit the interpreter
+    fn opcode_00fd(&mut self) {
+        self.halted = true;
     }
-}
 
-//8XY5 - Vx -= Vy, set carry flag to 0 if borrow, otherwise 1.
-pub fn opcode_8xy5(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        let tmp = 1u8 - (REGISTER[regx as usize] < REGISTER[regy as usize]) as Byte;
-        REGISTER[regx as usize] = REGISTER[regx as usize].wrapping_sub(REGISTER[regy as usize]);
-        REGISTER[0xF] = tmp;
+    //00FE - switch to lo-res 64x32 mode
+    fn opcode_00fe(&mut self) {
+        self.hires = false;
+        self.screen = [[0; HIRES_HEIGHT]; HIRES_WIDTH];
+        self.draw_flag = true;
     }
-}
 
-//8XY6 - Vx >>=1, store the least significant bit into VF.
-pub fn opcode_8xy6(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        REGISTER[0xF] = REGISTER[regx as usize] & 0x01;
-        REGISTER[regx as usize] >>= 1
+    //00FF - switch to hi-res 128x64 mode
+    fn opcode_00ff(&mut self) {
+        self.hires = true;
+        self.screen = [[0; HIRES_HEIGHT]; HIRES_WIDTH];
+        self.draw_flag = true;
     }
-}
 
-//8XY7 - Vx = Vy - Vx, set carry flag to 0 if borrowed, otherwise 1.
-pub fn opcode_8xy7(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        let tmp = 1u8 - (REGISTER[regx as usize] > REGISTER[regy as usize]) as Byte;
-        REGISTER[regx as usize] = REGISTER[regy as usize].wrapping_sub(REGISTER[regx as usize]);
-        REGISTER[0xF] = tmp;
+    //00EE - return from a subroutine
+    fn opcode_00ee(&mut self) -> Result<()> {
+        match self.stack.pop() {
+            Some(address) => {
+                self.progcounter = address;
+                Ok(())
+            }
+            None => self.report(Error::StackUnderflow {
+                opcode: self.opcode,
+                pc: self.instruction_pc,
+            }),
+        }
     }
-}
 
-//8XYE - Vx =  Vx<<1, store the most significant bit to VF.
-pub fn opcode_8xye(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        REGISTER[0xF] = (REGISTER[regx as usize] & 0x80) >> 7;
-        REGISTER[regx as usize] <<= 1
+    //1NNN is the opcode for jump instruction.
+    fn opcode_1nnn(&mut self, nnn: Word) {
+        self.progcounter = nnn;
     }
-}
 
-//9xy0 - if Vx!=Vy,then skip the next instruction.
-pub fn opcode_9xy0(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    unsafe {
-        if REGISTER[regx as usize] != REGISTER[regy as usize] {
-            PROGCOUNTER += 2;
+    //2NNN - Call subroutine at NNN
+    fn opcode_2nnn(&mut self, nnn: Word) -> Result<()> {
+        if self.stack.len() == STACK_DEPTH {
+            return self.report(Error::StackOverflow {
+                opcode: self.opcode,
+                pc: self.instruction_pc,
+            });
         }
+        self.stack.push(self.progcounter);
+        self.progcounter = nnn;
+        Ok(())
     }
-}
 
-//ANNN - Set address I to the address NNN
-pub fn opcode_annn(opcode: Word) {
-    let address = opcode & 0xFFF;
-    unsafe {
-        ADDRESS_I = address;
+    //3XNN - if Vx == NN, then skip the next instruction.
+    fn opcode_3xnn(&mut self, x: Byte, nn: Byte) {
+        if self.vx(x) == nn {
+            self.skip_next();
+        }
     }
-}
 
-//BNNN - Jump to the address NN plus V0
-pub fn opcode_bnnn(opcode: Word) {
-    let nnn = opcode & 0xFFF;
-    unsafe {
-        PROGCOUNTER = REGISTER[0] as Word + nnn;
+    //4XNN - if Vx != NN, then skip the next instruction
+    fn opcode_4xnn(&mut self, x: Byte, nn: Byte) {
+        if self.vx(x) != nn {
+            self.skip_next();
+        }
     }
-}
 
-//CXNN - Vx - rand()&NN
-pub fn opcode_cxnn(opcode: Word) {
-    let mut rng = rand::thread_rng();
-    let r: Byte = rng.gen::<Byte>();
-    let regx = (opcode & 0x0F00) >> 8;
-    let nn = (opcode & 0x00FF) as Byte;
-    unsafe { REGISTER[regx as usize] = r & nn }
-}
+    //5xy0 - if x==y,then skip the next instruction.
+    fn opcode_5xy0(&mut self, x: Byte, y: Byte) {
+        if self.vx(x) == self.vx(y) {
+            self.skip_next();
+        }
+    }
 
-//DXYN - draw(Vx,Vy,N)
-pub fn opcode_dxyn(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let regy = (opcode & 0x00F0) >> 4;
-    let height = (opcode & 0x000F) as Byte;
-    let coord_x = unsafe { REGISTER[regx as usize] };
-    let coord_y = unsafe { REGISTER[regy as usize] };
-    unsafe {
-        REGISTER[0xF] = 0;
-    }
-    for yline in 0..height {
-        let data: Byte = unsafe { GAMEMEMEORY[(ADDRESS_I + yline as Word) as usize] };
-        for xpix in 0..8 {
-            let mask = 1 << (7 - xpix);
-            if (data & mask) != 0 {
-                let mut x = coord_x + xpix;
-                let mut y = coord_y + yline;
-                if y > 31 {
-                    y = 31;
-                }
-                if x > 63 {
-                    x = 63;
-                }
+    //5XY2 - MEMORY[I..] = REGISTER[Vx..=Vy], I is not changed
+    //If X > Y the registers are stored in reverse order.
+    fn opcode_5xy2(&mut self, x: Byte, y: Byte) -> Result<()> {
+        if !self.check_range(self.address_i as usize, register_range(x, y).count())? {
+            return Ok(());
+        }
+        for (offset, reg) in register_range(x, y).enumerate() {
+            let value = self.vx(reg);
+            self.write(self.address_i as usize + offset, value)?;
+        }
+        Ok(())
+    }
 
-                unsafe {
-                    if SCREENDATA[x as usize][y as usize] == 1 {
-                        REGISTER[0xF] = 1;
-                    } else {
-                    }
+    //5XY3 - REGISTER[Vx..=Vy] = MEMORY[I..], I is not changed
+    fn opcode_5xy3(&mut self, x: Byte, y: Byte) -> Result<()> {
+        if !self.check_range(self.address_i as usize, register_range(x, y).count())? {
+            return Ok(());
+        }
+        for (offset, reg) in register_range(x, y).enumerate() {
+            let value = self.read(self.address_i as usize + offset)?;
+            self.set_vx(reg, value);
+        }
+        Ok(())
+    }
 
-                    SCREENDATA[x as usize][y as usize] ^= 1;
+    // 6XNN - Set Vx = NN
+    fn opcode_6xnn(&mut self, x: Byte, nn: Byte) {
+        self.set_vx(x, nn);
+    }
+
+    // 7XNN -  Vx += NN, carry flag is not changed
+    fn opcode_7xnn(&mut self, x: Byte, nn: Byte) {
+        let value = self.vx(x).wrapping_add(nn);
+        self.set_vx(x, value);
+    }
+
+    //8XY0 - Vx = Vy
+    fn opcode_8xy0(&mut self, x: Byte, y: Byte) {
+        let value = self.vx(y);
+        self.set_vx(x, value);
+    }
+
+    //8XY1 - Vx |= Vy
+    fn opcode_8xy1(&mut self, x: Byte, y: Byte) {
+        let value = self.vx(x) | self.vx(y);
+        self.set_vx(x, value);
+        if self.quirks.vf_reset {
+            self.set_vx(0xF, 0);
+        }
+    }
+
+    //8XY2 - Vx &= Vy
+    fn opcode_8xy2(&mut self, x: Byte, y: Byte) {
+        let value = self.vx(x) & self.vx(y);
+        self.set_vx(x, value);
+        if self.quirks.vf_reset {
+            self.set_vx(0xF, 0);
+        }
+    }
+
+    //8XY3 - Vx ^= Vy
+    fn opcode_8xy3(&mut self, x: Byte, y: Byte) {
+        let value = self.vx(x) ^ self.vx(y);
+        self.set_vx(x, value);
+        if self.quirks.vf_reset {
+            self.set_vx(0xF, 0);
+        }
+    }
+
+    //8XY4 - Vx += Vy, set carry flag to 1 if overflow ,otherwise 0.
+    fn opcode_8xy4(&mut self, x: Byte, y: Byte) {
+        let (result, carry) = self.vx(x).overflowing_add(self.vx(y));
+        self.set_vx(x, result);
+        self.set_vx(0xF, carry.into());
+    }
+
+    //8XY5 - Vx -= Vy, set carry flag to 0 if borrow, otherwise 1.
+    fn opcode_8xy5(&mut self, x: Byte, y: Byte) {
+        let (vx, vy) = (self.vx(x), self.vx(y));
+        let tmp = 1u8 - (vx < vy) as Byte;
+        self.set_vx(x, vx.wrapping_sub(vy));
+        self.set_vx(0xF, tmp);
+    }
+
+    //8XY6 - Vx >>=1, store the least significant bit into VF.
+    //With the shift quirk, Vx = Vy >> 1.
+    fn opcode_8xy6(&mut self, x: Byte, y: Byte) {
+        let value = if self.quirks.shift_vy {
+            self.vx(y)
+        } else {
+            self.vx(x)
+        };
+        self.set_vx(x, value >> 1);
+        self.set_vx(0xF, value & 0x01);
+    }
+
+    //8XY7 - Vx = Vy - Vx, set carry flag to 0 if borrowed, otherwise 1.
+    fn opcode_8xy7(&mut self, x: Byte, y: Byte) {
+        let (vx, vy) = (self.vx(x), self.vx(y));
+        let tmp = 1u8 - (vx > vy) as Byte;
+        self.set_vx(x, vy.wrapping_sub(vx));
+        self.set_vx(0xF, tmp);
+    }
+
+    //8XYE - Vx =  Vx<<1, store the most significant bit to VF.
+    //With the shift quirk, Vx = Vy << 1.
+    fn opcode_8xye(&mut self, x: Byte, y: Byte) {
+        let value = if self.quirks.shift_vy {
+            self.vx(y)
+        } else {
+            self.vx(x)
+        };
+        self.set_vx(x, value << 1);
+        self.set_vx(0xF, (value & 0x80) >> 7);
+    }
+
+    //9xy0 - if Vx!=Vy,then skip the next instruction.
+    fn opcode_9xy0(&mut self, x: Byte, y: Byte) {
+        if self.vx(x) != self.vx(y) {
+            self.skip_next();
+        }
+    }
+
+    //ANNN - Set address I to the address NNN
+    fn opcode_annn(&mut self, nnn: Word) {
+        self.address_i = nnn;
+    }
+
+    //BNNN - Jump to the address NNN plus V0
+    //With the jump quirk it is BXNN, jump to XNN plus Vx.
+    fn opcode_bnnn(&mut self, nnn: Word) {
+        let x = if self.quirks.jump_vx { nnn >> 8 } else { 0 };
+        self.progcounter = self.vx(x as Byte) as Word + nnn;
+    }
+
+    //CXNN - Vx - rand()&NN
+    fn opcode_cxnn(&mut self, x: Byte, nn: Byte) {
+        let mut rng = rand::thread_rng();
+        let r: Byte = rng.gen::<Byte>();
+        self.set_vx(x, r & nn);
+    }
+
+    //DXYN - draw(Vx,Vy,N)
+    //On SUPER-CHIP, DXY0 draws a 16x16 sprite.
+    //On XO-CHIP, the sprite is drawn to every selected plane,
+    //with the data of the second plane following the first one.
+    //The start coordinate always wraps around the screen, the rest of the
+    //sprite is clipped at the edges or wraps, depending on the platform.
+    fn opcode_dxyn(&mut self, x: Byte, y: Byte, n: Byte) -> Result<()> {
+        let n = n as usize;
+        let (sprite_width, sprite_height) = if n == 0 && self.supports_schip() {
+            (16, 16)
+        } else {
+            (8, n)
+        };
+        let (width, height) = (self.width(), self.height());
+        let coord_x = self.vx(x) as usize % width;
+        let coord_y = self.vx(y) as usize % height;
+        let sprite_len = self.plane.count_ones() as usize * sprite_height * sprite_width / 8;
+        if !self.check_range(self.address_i as usize, sprite_len)? {
+            return Ok(());
+        }
+        let mut collision = 0;
+        let mut address = self.address_i as usize;
+        for plane in [1, 2] {
+            if self.plane & plane == 0 {
+                continue;
+            }
+            for yline in 0..sprite_height {
+                let row = address + yline * sprite_width / 8;
+                let mut data = (self.read(row)? as Word) << 8;
+                if sprite_width == 16 {
+                    data |= self.read(row + 1)? as Word;
+                }
+                for xpix in 0..sprite_width {
+                    let mask = 0x8000 >> xpix;
+                    if (data & mask) != 0 {
+                        let (mut x, mut y) = (coord_x + xpix, coord_y + yline);
+                        if self.quirks.wrap_sprites {
+                            x %= width;
+                            y %= height;
+                        } else if x >= width || y >= height {
+                            continue;
+                        }
+
+                        if self.screen[x][y] & plane != 0 {
+                            collision = 1;
+                        }
+
+                        self.screen[x][y] ^= plane;
+                    }
                 }
             }
+            address += sprite_height * sprite_width / 8;
         }
+        self.set_vx(0xF, collision);
+        self.vblank_wait = self.quirks.display_wait;
+        self.draw_flag = true;
+        Ok(())
     }
-}
 
-//EX9E - if key() == Vx, skip the next instruction.
-pub fn opcode_ex9e(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let key = get_key_pressed();
-    unsafe {
-        if key == REGISTER[regx as usize] {
-            PROGCOUNTER += 2;
+    //EX9E - if key Vx is pressed, skip the next instruction.
+    fn opcode_ex9e(&mut self, x: Byte) {
+        let key = self.vx(x) & 0x0F;
+        if self.key[key as usize] == 1 {
+            self.skip_next();
         }
     }
-}
 
-//EXA1 - if key() != Vx, skip the next instruction.
-pub fn opcode_exa1(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let key = get_key_pressed();
-    unsafe {
-        if key != REGISTER[regx as usize] {
-            PROGCOUNTER += 2;
+    //EXA1 - if key Vx is not pressed, skip the next instruction.
+    fn opcode_exa1(&mut self, x: Byte) {
+        let key = self.vx(x) & 0x0F;
+        if self.key[key as usize] == 0 {
+            self.skip_next();
         }
     }
-}
 
-//FX07 - Vx = get_delay()
-pub fn opcode_fx07(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        REGISTER[regx as usize] = DELAY_TIMER;
+    //F000 NNNN - I = NNNN, the address is the word after the instruction
+    fn opcode_f000(&mut self) -> Result<()> {
+        let pc = self.progcounter as usize;
+        self.address_i = (self.fetch_byte(pc)? as Word) << 8 | self.fetch_byte(pc + 1)? as Word;
+        self.progcounter = self.progcounter.wrapping_add(2);
+        Ok(())
     }
-}
 
-//FX0A - Vx = get_key()
-pub fn opcode_fx0a(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        let key = get_key_pressed();
-        if key == 0xFF {
-            PROGCOUNTER -= 2;
-        } else {
-            REGISTER[regx as usize] = key;
+    //FN01 - select the bit-planes N to draw on
+    fn opcode_fn01(&mut self, n: Byte) {
+        self.plane = n & 0x3;
+    }
+
+    //F002 - load the 16 byte audio pattern from MEMORY[I..]
+    fn opcode_f002(&mut self) -> Result<()> {
+        if !self.check_range(self.address_i as usize, 16)? {
+            return Ok(());
         }
+        for offset in 0..16 {
+            self.audio_pattern[offset] = self.read(self.address_i as usize + offset)?;
+        }
+        Ok(())
     }
-}
 
-//FX15 - set the delay timer to vx
-pub fn opcode_fx15(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        DELAY_TIMER = REGISTER[regx as usize];
+    //FX07 - Vx = get_delay()
+    fn opcode_fx07(&mut self, x: Byte) {
+        self.set_vx(x, self.delay_timer);
     }
-}
 
-//FX18 - set the delay timer to vx
-pub fn opcode_fx18(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        SOUND_TIMER = REGISTER[regx as usize];
+    //FX0A - Vx = get_key()
+    //Like the COSMAC VIP, wait for a key press and complete when that key is released.
+    //While waiting, the instruction is executed again and again.
+    fn opcode_fx0a(&mut self, x: Byte) {
+        match self.key_wait {
+            KeyWait::Pressed(key) if self.key[key as usize] == 0 => {
+                self.set_vx(x, key);
+                self.key_wait = KeyWait::None;
+            }
+            KeyWait::None => {
+                self.key_wait = KeyWait::Waiting;
+                self.progcounter = self.instruction_pc;
+            }
+            _ => self.progcounter = self.instruction_pc,
+        }
     }
-}
 
-//FX1E - ADDRESS_I += Vx
-pub fn opcode_fx1e(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        ADDRESS_I += REGISTER[regx as usize] as Word;
+    //FX15 - set the delay timer to vx
+    fn opcode_fx15(&mut self, x: Byte) {
+        self.delay_timer = self.vx(x);
     }
-}
 
-//FX29 - I = sprtie_addr[Vx]
-pub fn opcode_fx29(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    unsafe {
-        ADDRESS_I = (REGISTER[regx as usize] * 5 + 0x50) as Word;
+    //FX18 - set the sound timer to vx
+    fn opcode_fx18(&mut self, x: Byte) {
+        self.sound_timer = self.vx(x);
+    }
+
+    //FX1E - ADDRESS_I += Vx
+    fn opcode_fx1e(&mut self, x: Byte) {
+        self.address_i = self.address_i.wrapping_add(self.vx(x) as Word);
     }
-}
 
-//FX33 - Binary-coded decimal
-pub fn opcode_fx33(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    let value = unsafe { REGISTER[regx as usize] };
-    let hundreds = value / 100;
-    let tens = (value / 10) % 10;
-    let units = value % 10;
+    //FX29 - I = sprtie_addr[Vx]
+    fn opcode_fx29(&mut self, x: Byte) {
+        let digit = (self.vx(x) & 0x0F) as Word;
+        self.address_i = digit * 5 + FONT_START as Word;
+    }
 
-    unsafe {
-        GAMEMEMEORY[ADDRESS_I as usize] = hundreds;
-        GAMEMEMEORY[ADDRESS_I as usize + 1] = tens;
-        GAMEMEMEORY[ADDRESS_I as usize + 2] = units;
+    //FX30 - I = big_sprite_addr[Vx]
+    fn opcode_fx30(&mut self, x: Byte) {
+        let digit = (self.vx(x) & 0x0F) as Word;
+        self.address_i = digit * 10 + BIG_FONT_START as Word;
+    }
+
+    //FX3A - set the audio pitch to Vx
+    fn opcode_fx3a(&mut self, x: Byte) {
+        self.pitch = self.vx(x);
     }
-}
 
-// FX55 - MEMORY[I..] = REGISTER[0..Vx]
-pub fn opcode_fx55(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    for i in 0..=regx {
-        unsafe {
-            GAMEMEMEORY[ADDRESS_I as usize] = REGISTER[i as usize];
-            ADDRESS_I += 1;
+    //FX33 - Binary-coded decimal
+    fn opcode_fx33(&mut self, x: Byte) -> Result<()> {
+        let value = self.vx(x);
+        let hundreds = value / 100;
+        let tens = (value / 10) % 10;
+        let units = value % 10;
+        if !self.check_range(self.address_i as usize, 3)? {
+            return Ok(());
         }
+        self.write(self.address_i as usize, hundreds)?;
+        self.write(self.address_i as usize + 1, tens)?;
+        self.write(self.address_i as usize + 2, units)?;
+        Ok(())
     }
-}
 
-// FX65 -   REGISTER[0..Vx] = MEMORY[I..]
-pub fn opcode_fx65(opcode: Word) {
-    let regx = (opcode & 0x0F00) >> 8;
-    for i in 0..=regx {
-        unsafe {
-            REGISTER[i as usize] = GAMEMEMEORY[ADDRESS_I as usize];
-            ADDRESS_I += 1;
+    // FX55 - MEMORY[I..] = REGISTER[0..Vx]
+    fn opcode_fx55(&mut self, x: Byte) -> Result<()> {
+        if !self.check_range(self.address_i as usize, x as usize + 1)? {
+            return Ok(());
+        }
+        for i in 0..=x {
+            let value = self.vx(i);
+            self.write(self.address_i as usize + i as usize, value)?;
         }
+        self.increment_index(x as Word);
+        Ok(())
     }
-}
 
-fn get_key_pressed() -> Byte {
-    let mut key = 0xFF;
-    unsafe {
-        for i in 0..=0xF {
-            if KEY[i] == 1 {
-                key = i as Byte;
-                break;
-            }
+    // FX65 -   REGISTER[0..Vx] = MEMORY[I..]
+    fn opcode_fx65(&mut self, x: Byte) -> Result<()> {
+        if !self.check_range(self.address_i as usize, x as usize + 1)? {
+            return Ok(());
+        }
+        for i in 0..=x {
+            let value = self.read(self.address_i as usize + i as usize)?;
+            self.set_vx(i, value);
         }
+        self.increment_index(x as Word);
+        Ok(())
     }
-    key
-}
 
-// exposed api for keys.
-pub fn key_pressed(key_code: Byte) {
-    unsafe {
-        KEY[key_code as usize] = 1;
+    // FX75 - RPL[0..Vx] = REGISTER[0..Vx]
+    fn opcode_fx75(&mut self, x: Byte) {
+        for i in 0..=x {
+            self.rpl[i as usize] = self.vx(i);
+        }
     }
-}
 
-pub fn key_released(key_code: Byte) {
-    unsafe {
-        KEY[key_code as usize] = 0;
+    // FX85 - REGISTER[0..Vx] = RPL[0..Vx]
+    fn opcode_fx85(&mut self, x: Byte) {
+        for i in 0..=x {
+            self.set_vx(i, self.rpl[i as usize]);
+        }
     }
-}
 
-// exposed api for viewing SCREENDATA
-pub fn view(x: usize, y: usize) -> Byte {
-    unsafe { SCREENDATA[x][y] }
+    // move I after FX55/FX65 according to the platform
+    fn increment_index(&mut self, regx: Word) {
+        self.address_i = self
+            .address_i
+            .wrapping_add(match self.quirks.index_increment {
+                IndexIncrement::XPlusOne => regx + 1,
+                IndexIncrement::X => regx,
+                IndexIncrement::None => 0,
+            });
+    }
 }
 
-pub fn get_clear_flag() -> bool {
-    unsafe {
-        let a = CLEARFLAG;
-        CLEARFLAG = false;
-        a
+// Registers X to Y, counting down if X > Y.
+fn register_range(regx: Byte, regy: Byte) -> Box<dyn Iterator<Item = Byte>> {
+    if regx <= regy {
+        Box::new(regx..=regy)
+    } else {
+        Box::new((regy..=regx).rev())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Byte, SCREENDATA};
+    use super::{Byte, Chip8, FaultPolicy, Platform};
+    use crate::error::Error;
 
     #[test]
     fn test_00e0() {
-        unsafe {
-            for i in 0..64 {
-                for j in 0..32 {
-                    SCREENDATA[i][j] = (i * j) as Byte;
-                }
+        let mut chip8 = Chip8::new();
+        for i in 0..64 {
+            for j in 0..32 {
+                chip8.screen[i][j] = (i * j) as Byte;
             }
         }
-        super::opcode_00e0();
-        unsafe {
-            for i in 0..64 {
-                for j in 0..32 {
-                    assert_eq!(SCREENDATA[i][j], 0);
-                }
+        chip8.execute(0x00E0).unwrap();
+        for i in 0..64 {
+            for j in 0..32 {
+                assert_eq!(chip8.screen[i][j], 0);
             }
         }
     }
 
     #[test]
     fn test_call_and_return() {
-        super::opcode_1nnn(0x200);
-        super::opcode_2nnn(0x202);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
-            assert_eq!(*super::STACK.lock().last().unwrap(), 0x200);
-        }
-        super::opcode_00ee().unwrap();
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x200);
-        }
+        let mut chip8 = Chip8::new();
+        chip8.execute(0x1200).unwrap();
+        chip8.execute(0x2202).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        assert_eq!(*chip8.stack.last().unwrap(), 0x200);
+        chip8.execute(0x00EE).unwrap();
+        assert_eq!(chip8.progcounter, 0x200);
     }
 
     #[test]
     fn test_cond_xnn() {
-        super::opcode_1nnn(0x200);
+        let mut chip8 = Chip8::new();
+        chip8.execute(0x1200).unwrap();
+
+        chip8.register[0] = 0x11;
+        chip8.execute(0x3011).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.execute(0x4011).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+
+        chip8.execute(0x3001).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.execute(0x4001).unwrap();
+        assert_eq!(chip8.progcounter, 0x204);
+    }
 
-        unsafe {
-            super::REGISTER[0] = 0x11;
-        }
-        super::opcode_3xnn(0x3011);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
-        }
-        super::opcode_4xnn(0x4011);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
-        }
+    #[test]
+    fn test_cond_xy() {
+        let mut chip8 = Chip8::new();
+        chip8.execute(0x1200).unwrap();
+
+        chip8.register[0] = 0x11;
+        chip8.register[1] = 0x11;
+        chip8.register[2] = 0x01;
+        chip8.execute(0x5010).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.execute(0x9010).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+
+        chip8.execute(0x5020).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.execute(0x9020).unwrap();
+        assert_eq!(chip8.progcounter, 0x204);
+    }
 
-        super::opcode_3xnn(0x3001);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
-        }
-        super::opcode_4xnn(0x4001);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x204);
-        }
+    #[test]
+    fn test_const() {
+        let mut chip8 = Chip8::new();
+        chip8.register[0] = 0x11;
+        chip8.execute(0x6022).unwrap();
+        assert_eq!(chip8.register[0], 0x22);
+
+        chip8.execute(0x7011).unwrap();
+        assert_eq!(chip8.register[0], 0x33);
     }
 
     #[test]
-    fn test_cond_xy() {
-        super::opcode_1nnn(0x200);
+    fn test_assign() {
+        let mut chip8 = Chip8::new();
+        chip8.register[0] = 0x11;
+        chip8.register[2] = 0x23;
+        chip8.execute(0x8020).unwrap();
+        assert_eq!(chip8.register[0], 0x23);
+    }
+
+    #[test]
+    fn test_bitop() {
+        let mut chip8 = Chip8::with_platform(Platform::Chip48);
+        chip8.register[0] = 0xAA;
+        chip8.register[1] = 0xA5;
+        chip8.execute(0x8011).unwrap();
+        assert_eq!(chip8.register[0], 0xAA | 0xA5); //0xAF
+        chip8.execute(0x8012).unwrap();
+        assert_eq!(chip8.register[0], 0xAF & 0xA5); //0xA5
+        chip8.execute(0x8013).unwrap();
+        assert_eq!(chip8.register[0], 0xA5 ^ 0xA5); //0x00
+        chip8.execute(0x8016).unwrap();
+        assert_eq!(chip8.register[0], 0x00);
+
+        chip8.register[0] = 0xA5;
+        chip8.execute(0x8016).unwrap();
+        assert_eq!(chip8.register[0], 0x52);
+        assert_eq!(chip8.register[0xF], 0x1);
+        chip8.execute(0x801E).unwrap();
+        assert_eq!(chip8.register[0], 0xA4);
+        assert_eq!(chip8.register[0xF], 0x0);
+    }
+
+    #[test]
+    fn test_math() {
+        let mut chip8 = Chip8::new();
+        chip8.register[0] = 0xFF;
+        chip8.register[1] = 0x11;
+
+        chip8.execute(0x8014).unwrap();
+        assert_eq!(chip8.register[0], 0x10);
+        assert_eq!(chip8.register[0xF], 0x1);
+        chip8.execute(0x8014).unwrap();
+        assert_eq!(chip8.register[0], 0x21);
+        assert_eq!(chip8.register[0xF], 0x0);
+
+        chip8.execute(0x8015).unwrap();
+        assert_eq!(chip8.register[0], 0x10);
+        assert_eq!(chip8.register[0xF], 0x1);
+        chip8.execute(0x8015).unwrap();
+        assert_eq!(chip8.register[0], 0xFF);
+        assert_eq!(chip8.register[0xF], 0x0);
+
+        chip8.execute(0x8017).unwrap();
+        assert_eq!(chip8.register[0], 0x12);
+        assert_eq!(chip8.register[0xF], 0x0);
+    }
 
-        unsafe {
-            super::REGISTER[0] = 0x11;
-            super::REGISTER[1] = 0x11;
-            super::REGISTER[2] = 0x01;
+    #[test]
+    fn test_machines_are_independent() {
+        let mut a = Chip8::new();
+        let b = Chip8::new();
+        a.load_bytes(&[0x60, 0x42]).unwrap();
+        a.step().unwrap();
+        assert_eq!(a.register[0], 0x42);
+        assert_eq!(b.register[0], 0);
+        assert_eq!(b.progcounter, 0x200);
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut chip8 = Chip8::new();
+        chip8.register[0] = 2;
+        chip8.register[1] = 1;
+        chip8.execute(0xF015).unwrap();
+        chip8.execute(0xF118).unwrap();
+
+        chip8.tick_timers();
+        chip8.execute(0xF207).unwrap();
+        assert_eq!(chip8.register[2], 1);
+        assert_eq!(chip8.sound_timer, 0);
+
+        chip8.tick_timers();
+        chip8.tick_timers();
+        chip8.execute(0xF207).unwrap();
+        assert_eq!(chip8.register[2], 0);
+        assert_eq!(chip8.sound_timer, 0);
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mut vip = Chip8::with_platform(Platform::CosmacVip);
+        let mut schip = Chip8::with_platform(Platform::SuperChip);
+        for chip8 in [&mut vip, &mut schip] {
+            chip8.register[0] = 0x01;
+            chip8.register[1] = 0x84;
+            chip8.register[2] = 0x10;
+            chip8.register[0xF] = 0x01;
+            chip8.address_i = 0x300;
         }
-        super::opcode_5xy0(0x5010);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
+
+        vip.execute(0x8011).unwrap();
+        schip.execute(0x8011).unwrap();
+        assert_eq!(vip.register[0xF], 0x0);
+        assert_eq!(schip.register[0xF], 0x1);
+
+        vip.execute(0x801E).unwrap();
+        schip.execute(0x801E).unwrap();
+        assert_eq!(vip.register[0], 0x08);
+        assert_eq!(vip.register[0xF], 0x1);
+        assert_eq!(schip.register[0], 0x0A);
+        assert_eq!(schip.register[0xF], 0x1);
+
+        vip.execute(0xF255).unwrap();
+        schip.execute(0xF255).unwrap();
+        assert_eq!(vip.address_i, 0x303);
+        assert_eq!(schip.address_i, 0x300);
+        assert_eq!(schip.memory[0x300..0x303], [0x0A, 0x84, 0x10]);
+
+        vip.execute(0xB210).unwrap();
+        schip.execute(0xB210).unwrap();
+        assert_eq!(vip.progcounter, 0x218);
+        assert_eq!(schip.progcounter, 0x220);
+    }
+
+    #[test]
+    fn test_schip_display() {
+        let mut chip8 = Chip8::with_platform(Platform::SuperChip);
+        chip8.load_bytes(&[0x00, 0xFF, 0xD0, 0x10]).unwrap();
+        chip8.register[0] = 100;
+        chip8.register[1] = 40;
+        chip8.address_i = 0x300;
+        chip8.memory[0x300] = 0x80;
+        chip8.memory[0x301] = 0x01;
+        chip8.step().unwrap();
+        assert_eq!((chip8.width(), chip8.height()), (128, 64));
+        chip8.step().unwrap();
+        assert_eq!(chip8.pixel(100, 40), 1);
+        assert_eq!(chip8.pixel(115, 40), 1);
+
+        chip8.execute(0x00C2).unwrap();
+        assert_eq!(chip8.pixel(100, 40), 0);
+        assert_eq!(chip8.pixel(100, 42), 1);
+        chip8.execute(0x00FB).unwrap();
+        assert_eq!(chip8.pixel(104, 42), 1);
+        chip8.execute(0x00FC).unwrap();
+        chip8.execute(0x00FC).unwrap();
+        assert_eq!(chip8.pixel(96, 42), 1);
+        assert_eq!(chip8.pixel(111, 42), 1);
+
+        chip8.execute(0x00FE).unwrap();
+        assert_eq!((chip8.width(), chip8.height()), (64, 32));
+        assert_eq!(chip8.pixel(96, 42), 0);
+    }
+
+    #[test]
+    fn test_schip_misc() {
+        let mut chip8 = Chip8::with_platform(Platform::SuperChip);
+        chip8.register[..4].copy_from_slice(&[1, 2, 3, 9]);
+        chip8.execute(0xF375).unwrap();
+        chip8.register[..4].fill(0);
+        chip8.execute(0xF285).unwrap();
+        assert_eq!(chip8.register[..4], [1, 2, 3, 0]);
+
+        chip8.execute(0xF230).unwrap();
+        assert_eq!(chip8.memory[chip8.address_i as usize], 0x3C);
+        assert_eq!(chip8.address_i, 0xA0 + 30);
+
+        chip8.load_bytes(&[0x00, 0xFD]).unwrap();
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert!(chip8.halted());
+        assert_eq!(chip8.progcounter, 0x202);
+    }
+
+    #[test]
+    fn test_xochip() {
+        let mut chip8 = Chip8::with_platform(Platform::XoChip);
+        assert_eq!(chip8.memory.len(), 0x10000);
+
+        // 3XNN skips the whole F000 NNNN
+        chip8
+            .load_bytes(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00, 0xE0, 0x00])
+            .unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.progcounter, 0x206);
+        chip8.step().unwrap();
+        assert_eq!(chip8.address_i, 0xE000);
+        assert_eq!(chip8.progcounter, 0x20A);
+
+        chip8.register[..4].copy_from_slice(&[1, 2, 3, 4]);
+        chip8.execute(0x5312).unwrap();
+        assert_eq!(chip8.memory[0xE000..0xE003], [4, 3, 2]);
+        chip8.execute(0x5203).unwrap();
+        assert_eq!(chip8.register[..4], [2, 3, 4, 4]);
+        assert_eq!(chip8.address_i, 0xE000);
+    }
+
+    #[test]
+    fn test_xochip_planes() {
+        let mut chip8 = Chip8::with_platform(Platform::XoChip);
+        chip8.address_i = 0x300;
+        chip8.memory[0x300] = 0x80;
+        chip8.memory[0x301] = 0xC0;
+
+        chip8.execute(0xF301).unwrap();
+        chip8.execute(0xD001).unwrap();
+        assert_eq!(chip8.pixel(0, 0), 3);
+        assert_eq!(chip8.pixel(1, 0), 2);
+
+        chip8.execute(0xF201).unwrap();
+        chip8.execute(0x00D1).unwrap();
+        chip8.execute(0x00E0).unwrap();
+        assert_eq!(chip8.pixel(0, 0), 1);
+        assert_eq!(chip8.pixel(1, 0), 0);
+
+        chip8.register[0] = 0x7F;
+        chip8.execute(0xF03A).unwrap();
+        assert!(chip8.playback_rate() > 4000.0);
+    }
+
+    #[test]
+    fn test_fault_policy() {
+        let mut chip8 = Chip8::new();
+        chip8
+            .load_bytes(&[0x6A, 0x05, 0x80, 0x1F, 0xE0, 0xFF])
+            .unwrap();
+        chip8.step().unwrap();
+        match chip8.step() {
+            Err(Error::Opcode {
+                opcode,
+                pc,
+                registers,
+                ..
+            }) => {
+                assert_eq!(opcode, 0x801F);
+                assert_eq!(pc, 0x202);
+                assert_eq!(registers[0xA], 0x05);
+            }
+            _ => panic!("expected an opcode fault"),
         }
-        super::opcode_9xy0(0x9010);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
+        assert_eq!(chip8.progcounter, 0x202);
+
+        chip8.set_fault_policy(FaultPolicy::Skip);
+        chip8.step().unwrap();
+        assert!(matches!(
+            chip8.take_fault(),
+            Some(Error::Opcode { opcode: 0x801F, .. })
+        ));
+        chip8.set_fault_policy(FaultPolicy::Nop);
+        chip8.step().unwrap();
+        assert!(chip8.take_fault().is_none());
+        assert_eq!(chip8.progcounter, 0x206);
+    }
+
+    #[test]
+    fn test_memory_bounds() {
+        let mut schip = Chip8::with_platform(Platform::SuperChip);
+        schip.load_bytes(&[0xAF, 0xFF, 0xF1, 0x55]).unwrap();
+        schip.register[0] = 0x07;
+        schip.step().unwrap();
+        assert!(matches!(
+            schip.step(),
+            Err(Error::Address {
+                address: 0x1000,
+                opcode: 0xF155,
+                pc: 0x202
+            })
+        ));
+        assert_eq!(schip.progcounter, 0x202);
+        assert_eq!(schip.memory[0xFFF], 0);
+
+        // other policies skip the instruction without touching memory
+        let mut schip = Chip8::with_platform(Platform::SuperChip);
+        schip.set_fault_policy(FaultPolicy::Skip);
+        schip.load_bytes(&[0xAF, 0xFF, 0xF1, 0x55]).unwrap();
+        schip.register[0] = 0x07;
+        schip.step().unwrap();
+        schip.step().unwrap();
+        assert_eq!(schip.memory[0xFFF], 0);
+        assert_eq!(schip.address_i, 0xFFF);
+        assert_eq!(schip.progcounter, 0x204);
+        assert!(matches!(
+            schip.take_fault(),
+            Some(Error::Address {
+                address: 0x1000,
+                ..
+            })
+        ));
+
+        let mut vip = Chip8::with_platform(Platform::CosmacVip);
+        vip.load_bytes(&[0xAF, 0xFF, 0x60, 0x07, 0xF1, 0x55])
+            .unwrap();
+        vip.register[1] = 0x08;
+        for _ in 0..3 {
+            vip.step().unwrap();
         }
+        assert_eq!(vip.memory[0xFFF], 0x07);
+        assert_eq!(vip.memory[0x000], 0x08);
 
-        super::opcode_5xy0(0x5020);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x202);
+        vip.address_i = 0xFFFF;
+        vip.execute(0xF11E).unwrap();
+        assert_eq!(vip.address_i, 0x0007);
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&[0x22, 0x00]).unwrap();
+        for _ in 0..16 {
+            chip8.step().unwrap();
         }
-        super::opcode_9xy0(0x9020);
-        unsafe {
-            assert_eq!(super::PROGCOUNTER, 0x204);
+        assert!(matches!(
+            chip8.step(),
+            Err(Error::StackOverflow { pc: 0x200, .. })
+        ));
+
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&[0x00, 0xEE]).unwrap();
+        assert!(matches!(
+            chip8.step(),
+            Err(Error::StackUnderflow {
+                opcode: 0x00EE,
+                pc: 0x200
+            })
+        ));
+
+        // with the other policies the call or return is skipped
+        let mut chip8 = Chip8::new();
+        chip8.set_fault_policy(FaultPolicy::Skip);
+        chip8.load_bytes(&[0x00, 0xEE]).unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        assert!(matches!(
+            chip8.take_fault(),
+            Some(Error::StackUnderflow { pc: 0x200, .. })
+        ));
+
+        let mut chip8 = Chip8::new();
+        chip8.set_fault_policy(FaultPolicy::Nop);
+        chip8.load_bytes(&[0x22, 0x00]).unwrap();
+        for _ in 0..17 {
+            chip8.step().unwrap();
         }
+        assert_eq!(chip8.pc(), 0x202);
+        assert!(chip8.take_fault().is_none());
     }
 
     #[test]
-    fn test_const() {
-        unsafe {
-            super::REGISTER[0] = 0x11;
-            super::opcode_6xnn(0x6022);
-            assert_eq!(super::REGISTER[0], 0x22);
+    fn test_draw_flag() {
+        let mut chip8 = Chip8::new();
+        assert!(chip8.take_draw_flag());
+        assert!(!chip8.take_draw_flag());
+        chip8.execute(0x6001).unwrap();
+        assert!(!chip8.take_draw_flag());
+        chip8.execute(0xD001).unwrap();
+        assert!(chip8.take_draw_flag());
+        chip8.execute(0x00E0).unwrap();
+        assert!(chip8.take_draw_flag());
+    }
 
-            super::opcode_7xnn(0x7011);
-            assert_eq!(super::REGISTER[0], 0x33);
-        }
+    #[test]
+    fn test_sprite_clipping() {
+        let mut chip8 = Chip8::with_platform(Platform::CosmacVip);
+        chip8.address_i = 0x300;
+        chip8.memory[0x300..0x302].copy_from_slice(&[0xFF, 0xFF]);
+        // the start coordinate wraps, 126 is column 62
+        chip8.register[0] = 126;
+        chip8.register[1] = 31;
+        chip8.execute(0xD012).unwrap();
+        assert_eq!(chip8.pixel(62, 31), 1);
+        assert_eq!(chip8.pixel(63, 31), 1);
+        assert_eq!(chip8.pixel(0, 31), 0);
+        assert_eq!(chip8.pixel(62, 0), 0);
+        assert_eq!(chip8.register[0xF], 0);
+
+        // clipped pixels don't collide with the last column
+        chip8.register[0] = 63;
+        chip8.execute(0xD012).unwrap();
+        assert_eq!(chip8.pixel(63, 31), 0);
+        assert_eq!(chip8.pixel(62, 31), 1);
+        assert_eq!(chip8.register[0xF], 1);
+
+        let mut chip8 = Chip8::with_platform(Platform::XoChip);
+        chip8.address_i = 0x300;
+        chip8.memory[0x300..0x302].copy_from_slice(&[0xFF, 0xFF]);
+        chip8.register[0] = 62;
+        chip8.register[1] = 31;
+        chip8.execute(0xD012).unwrap();
+        assert_eq!(chip8.pixel(63, 31), 1);
+        assert_eq!(chip8.pixel(0, 31), 1);
+        assert_eq!(chip8.pixel(5, 0), 1);
+        assert_eq!(chip8.pixel(6, 0), 0);
+        assert_eq!(chip8.register[0xF], 0);
     }
 
     #[test]
-    fn test_assign() {
-        unsafe {
-            super::REGISTER[0] = 0x11;
-            super::REGISTER[2] = 0x23;
-            super::opcode_8xy0(0x8020);
-            assert_eq!(super::REGISTER[0], 0x23);
-        }
+    fn test_keys() {
+        let mut chip8 = Chip8::new();
+        chip8.key_down(0x3);
+        chip8.key_down(0x7);
+        chip8.register[0] = 0x7;
+        chip8.execute(0x1200).unwrap();
+        chip8.execute(0xE09E).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.execute(0xE0A1).unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        chip8.key_up(0x7);
+        chip8.execute(0xE0A1).unwrap();
+        assert_eq!(chip8.progcounter, 0x204);
     }
 
     #[test]
-    fn test_bitop() {
-        unsafe {
-            super::REGISTER[0] = 0xAA;
-            super::REGISTER[1] = 0xA5;
-            super::opcode_8xy1(0x8011);
-            assert_eq!(super::REGISTER[0], 0xAA | 0xA5); //0xAF
-            super::opcode_8xy2(0x8012);
-            assert_eq!(super::REGISTER[0], 0xAF & 0xA5); //0xA5
-            super::opcode_8xy3(0x8013);
-            assert_eq!(super::REGISTER[0], 0xA5 ^ 0xA5); //0x00
-            super::opcode_8xy6(0x8016);
-            assert_eq!(super::REGISTER[0], 0x00);
-
-            super::REGISTER[0] = 0xA5;
-            super::opcode_8xy6(0x8016);
-            assert_eq!(super::REGISTER[0], 0x52);
-            assert_eq!(super::REGISTER[0xF], 0x1);
-            super::opcode_8xye(0x801e);
-            assert_eq!(super::REGISTER[0], 0xA4);
-            assert_eq!(super::REGISTER[0xF], 0x0);
-        }
+    fn test_wait_for_key() {
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&[0xF5, 0x0A]).unwrap();
+        chip8.key_down(0x3);
+        chip8.step().unwrap();
+        assert!(chip8.waiting_for_key());
+        assert_eq!(chip8.progcounter, 0x200);
+
+        // keys held before FX0A started don't count
+        chip8.key_up(0x3);
+        chip8.step().unwrap();
+        assert_eq!(chip8.progcounter, 0x200);
+
+        chip8.key_down(0xA);
+        chip8.step().unwrap();
+        assert_eq!(chip8.progcounter, 0x200);
+        chip8.key_up(0xA);
+        chip8.step().unwrap();
+        assert_eq!(chip8.progcounter, 0x202);
+        assert_eq!(chip8.register[5], 0xA);
+        assert!(!chip8.waiting_for_key());
     }
 
     #[test]
-    fn test_math() {
-        unsafe {
-            super::REGISTER[0] = 0xFF;
-            super::REGISTER[1] = 0x11;
-
-            super::opcode_8xy4(0x8014);
-            assert_eq!(super::REGISTER[0], 0x10);
-            assert_eq!(super::REGISTER[0xF], 0x1);
-            super::opcode_8xy4(0x8014);
-            assert_eq!(super::REGISTER[0], 0x21);
-            assert_eq!(super::REGISTER[0xF], 0x0);
-
-            super::opcode_8xy5(0x8015);
-            assert_eq!(super::REGISTER[0], 0x10);
-            assert_eq!(super::REGISTER[0xF], 0x1);
-            super::opcode_8xy5(0x8015);
-            assert_eq!(super::REGISTER[0], 0xFF);
-            assert_eq!(super::REGISTER[0xF], 0x0);
-
-            super::opcode_8xy7(0x8017);
-            assert_eq!(super::REGISTER[0], 0x12);
-            assert_eq!(super::REGISTER[0xF], 0x0);
+    fn test_debug_hook() {
+        use super::{Access, Location};
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&[0xF1, 0x33, 0x80, 0x14]).unwrap();
+        chip8.register[1] = 123;
+        chip8.address_i = 0x300;
+        chip8.step().unwrap();
+        assert!(chip8.accesses().is_empty());
+
+        chip8.set_watching(true);
+        chip8.progcounter = 0x200;
+        chip8.step().unwrap();
+        assert_eq!(
+            chip8.accesses(),
+            &[
+                Access::Read(Location::Register(1)),
+                Access::Write(Location::Memory(0x300), 1),
+                Access::Write(Location::Memory(0x301), 2),
+                Access::Write(Location::Memory(0x302), 3),
+            ]
+        );
+        chip8.step().unwrap();
+        assert_eq!(
+            chip8.accesses(),
+            &[
+                Access::Read(Location::Register(0)),
+                Access::Read(Location::Register(1)),
+                Access::Write(Location::Register(0), 0),
+                Access::Write(Location::Register(0xF), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut chip8 = Chip8::with_platform(Platform::SuperChip);
+        chip8
+            .load_bytes(&[0x00, 0xFF, 0x60, 0x0A, 0xF0, 0x29, 0xD0, 0x05, 0x22, 0x00])
+            .unwrap();
+        for _ in 0..5 {
+            chip8.step().unwrap();
         }
+        let saved = chip8.save_state();
+
+        let mut other = Chip8::with_platform(Platform::SuperChip);
+        other
+            .load_bytes(&[0x00, 0xFF, 0x60, 0x0A, 0xF0, 0x29, 0xD0, 0x05, 0x22, 0x00])
+            .unwrap();
+        other.load_state(&saved).unwrap();
+        assert_eq!(other.save_state(), saved);
+        assert_eq!(other.register[0], 0x0A);
+        assert_eq!(other.stack, [0x20A]);
+        assert!(other.hires);
+        assert_eq!(other.pixel(10, 10), 1);
+
+        // keys held when saving aren't held after loading, and the other way round
+        chip8.key_down(5);
+        let saved = chip8.save_state();
+        other.key_down(7);
+        other.load_state(&saved).unwrap();
+        assert_eq!(other.key[5], 0);
+        assert_eq!(other.key[7], 1);
+
+        let mut wrong_rom = Chip8::with_platform(Platform::SuperChip);
+        wrong_rom.load_bytes(&[0x12, 0x00]).unwrap();
+        assert!(matches!(wrong_rom.load_state(&saved), Err(Error::State(_))));
+        assert_eq!(wrong_rom.register[0], 0);
     }
 }
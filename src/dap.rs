@@ -0,0 +1,979 @@
+use super::asm;
+use super::chip8::{Byte, Chip8, Word, PROGRAM_START};
+use super::disasm::{self, Syntax};
+use super::error::Error;
+use super::gdb::write_all;
+use super::instruction::{self, Instruction};
+use super::octo;
+use super::Result;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+// the machine is the only thread
+const THREAD: i64 = 1;
+// variablesReference of the registers scope
+const REGISTERS: i64 = 1;
+
+type Message<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Run {
+    Stopped,
+    Continue,
+    Step,
+    // until the stack is back to this depth, for next and stepOut
+    Return(usize),
+}
+
+struct Client {
+    stream: TcpStream,
+    // received bytes that don't make up a whole message yet
+    input: Vec<u8>,
+}
+
+// A Debug Adapter Protocol server for editors, serving one client at a time over TCP.
+// It drives the machine like the other debuggers, through `Chip8::run_frame_until`.
+// Programs launched from assembly or Octo source get breakpoints by source line.
+pub struct DapServer {
+    listener: TcpListener,
+    client: Option<Client>,
+    seq: i64,
+    run: Run,
+    // nothing stops the first instruction after resuming
+    resumed: bool,
+    // breakpoints set in the disassembly
+    instruction_breakpoints: BTreeSet<Word>,
+    // breakpoints set on source lines, by file
+    source_breakpoints: BTreeMap<String, Vec<Word>>,
+    // source file and line of every instruction, if the program was built from source
+    lines: BTreeMap<Word, (String, usize)>,
+    // labels, to name stack frames and disassembly
+    labels: BTreeMap<Word, String>,
+    // the program starts once it is launched or attached and configured
+    launched: bool,
+    configured: bool,
+    started: bool,
+    stop_on_entry: bool,
+    // the client asked to end the emulator
+    quit: bool,
+}
+
+impl DapServer {
+    // Listen on `address`. The machine waits for a client to launch or attach.
+    pub fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(address).map_err(|e| Error::Debugger(e.to_string()))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| Error::Debugger(e.to_string()))?;
+        Ok(DapServer {
+            listener,
+            client: None,
+            seq: 0,
+            run: Run::Stopped,
+            resumed: false,
+            instruction_breakpoints: BTreeSet::new(),
+            source_breakpoints: BTreeMap::new(),
+            lines: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            launched: false,
+            configured: false,
+            started: false,
+            stop_on_entry: false,
+            quit: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    // whether the client holds the machine
+    pub fn stopped(&self) -> bool {
+        self.run == Run::Stopped
+    }
+
+    // whether the client ended the debug session with terminateDebuggee
+    pub fn quitting(&self) -> bool {
+        self.quit
+    }
+
+    // Serve the client, then run one frame unless the machine is stopped.
+    // Returns whether the machine ran.
+    pub fn run_frame(&mut self, chip8: &mut Chip8, ipf: u32) -> bool {
+        self.accept();
+        self.serve(chip8);
+        if self.stopped() {
+            return false;
+        }
+
+        let DapServer {
+            instruction_breakpoints,
+            source_breakpoints,
+            run,
+            resumed,
+            ..
+        } = self;
+        let mut reason = "step";
+        let result = chip8.run_frame_until(ipf, |chip8| {
+            let pc = chip8.pc();
+            if !*resumed {
+                if instruction_breakpoints.contains(&pc) {
+                    reason = "instruction breakpoint";
+                    return true;
+                }
+                if source_breakpoints
+                    .values()
+                    .any(|addresses| addresses.contains(&pc))
+                {
+                    reason = "breakpoint";
+                    return true;
+                }
+                match *run {
+                    Run::Step => return true,
+                    Run::Return(depth) if chip8.stack().len() <= depth => return true,
+                    _ => (),
+                }
+            }
+            *resumed = false;
+            false
+        });
+        match result {
+            Err(e) => {
+                // the machine is left at the faulting instruction
+                self.run = Run::Stopped;
+                self.stopped_event("exception", Some(e.to_string()));
+            }
+            Ok(_) if chip8.halted() => {
+                self.run = Run::Stopped;
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            }
+            Ok(true) => {
+                self.run = Run::Stopped;
+                self.stopped_event(reason, None);
+            }
+            Ok(false) => (),
+        }
+        true
+    }
+
+    fn accept(&mut self) {
+        if self.client.is_some() {
+            return;
+        }
+        if let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.client = Some(Client {
+                    stream,
+                    input: Vec::new(),
+                });
+                self.run = Run::Stopped;
+            }
+        }
+    }
+
+    // Let the machine run on its own once the client is gone.
+    fn detach(&mut self) {
+        self.client = None;
+        self.instruction_breakpoints.clear();
+        self.source_breakpoints.clear();
+        self.launched = false;
+        self.configured = false;
+        self.started = false;
+        self.resume(Run::Continue);
+    }
+
+    fn resume(&mut self, run: Run) {
+        self.run = run;
+        self.resumed = true;
+    }
+
+    // Handle every request that arrived since the last frame.
+    fn serve(&mut self, chip8: &mut Chip8) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let mut buffer = [0; 4096];
+        loop {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => return self.detach(),
+                Ok(len) => client.input.extend_from_slice(&buffer[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return self.detach(),
+            }
+        }
+
+        // messages are a `Content-Length: N` header, an empty line and N bytes of JSON
+        while let Some(client) = self.client.as_mut() {
+            let Some(header_end) = client.input.windows(4).position(|w| w == b"\r\n\r\n") else {
+                break;
+            };
+            let header = String::from_utf8_lossy(&client.input[..header_end]).into_owned();
+            let len = header
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+            let Some(len) = len else {
+                // not a message, skip the header
+                client.input.drain(..header_end + 4);
+                continue;
+            };
+            if client.input.len() < header_end + 4 + len {
+                break;
+            }
+            let body: Vec<u8> = client.input.drain(..header_end + 4 + len).collect();
+            if let Ok(request) = serde_json::from_slice::<Value>(&body[header_end + 4..]) {
+                self.request(chip8, &request);
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let body = message.to_string();
+        let data = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        if write_all(&mut client.stream, data.as_bytes()).is_err() {
+            self.client = None;
+        }
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped_event(&mut self, reason: &str, text: Option<String>) {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    fn request(&mut self, chip8: &mut Chip8, request: &Value) {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        let result = self.execute(chip8, &command, args);
+        let success = result.is_ok();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": success,
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+        if !success {
+            return;
+        }
+
+        // events that follow the response
+        match command.as_str() {
+            "initialize" => self.event("initialized", json!({})),
+            "launch" | "attach" | "configurationDone" => self.start(),
+            "pause" => self.stopped_event("pause", None),
+            "disconnect" => self.detach(),
+            _ => (),
+        }
+    }
+
+    // Start the program once it is launched and configured.
+    fn start(&mut self) {
+        if !self.launched || !self.configured || self.started {
+            return;
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            self.stopped_event("entry", None);
+        } else {
+            self.resume(Run::Continue);
+        }
+    }
+
+    fn execute(&mut self, chip8: &mut Chip8, command: &str, args: &Value) -> Message<Value> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsSetVariable": true,
+            })),
+            "launch" => {
+                let path = args["program"]
+                    .as_str()
+                    .ok_or("launch needs the path of the `program`")?;
+                self.launch(chip8, path, args["symbols"].as_str())
+                    .map_err(|e| e.to_string())?;
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.launched = true;
+                Ok(json!({}))
+            }
+            "attach" => {
+                if let Some(symbols) = args["symbols"].as_str() {
+                    self.load_symbols(symbols).map_err(|e| e.to_string())?;
+                }
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.launched = true;
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            }
+            "setBreakpoints" => {
+                let path = args["source"]["path"]
+                    .as_str()
+                    .ok_or("setBreakpoints needs a source path")?;
+                let path = canonical(path);
+                let mut addresses = Vec::new();
+                let mut breakpoints = Vec::new();
+                for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+                    let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+                    match self.address_of_line(&path, line) {
+                        Some((address, line)) => {
+                            addresses.push(address);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "line": line,
+                                "instructionReference": reference(address),
+                            }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "no instruction at or after this line",
+                        })),
+                    }
+                }
+                self.source_breakpoints.insert(path, addresses);
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setInstructionBreakpoints" => {
+                self.instruction_breakpoints.clear();
+                let mut breakpoints = Vec::new();
+                for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+                    let address = breakpoint["instructionReference"]
+                        .as_str()
+                        .and_then(number)
+                        .map(|address| address + breakpoint["offset"].as_i64().unwrap_or(0))
+                        .filter(|&address| (0..chip8.memory().len() as i64).contains(&address));
+                    match address {
+                        Some(address) => {
+                            self.instruction_breakpoints.insert(address as Word);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "instructionReference": reference(address as Word),
+                            }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "message": "not an address in memory",
+                        })),
+                    }
+                }
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace(chip8)),
+            "scopes" => Ok(json!({
+                "scopes": [{
+                    "name": "Registers",
+                    "presentationHint": "registers",
+                    "variablesReference": REGISTERS,
+                    "expensive": false,
+                }]
+            })),
+            "variables" => match args["variablesReference"].as_i64() {
+                Some(REGISTERS) => Ok(json!({ "variables": registers(chip8) })),
+                _ => Ok(json!({ "variables": [] })),
+            },
+            "setVariable" => {
+                let name = args["name"].as_str().unwrap_or_default();
+                let value = args["value"]
+                    .as_str()
+                    .and_then(number)
+                    .ok_or("the value has to be a number")?;
+                set_register(chip8, name, value)?;
+                let variable = registers(chip8)
+                    .into_iter()
+                    .find(|variable| variable["name"] == name)
+                    .unwrap_or_default();
+                Ok(json!({ "value": variable["value"] }))
+            }
+            "readMemory" => {
+                let start = args["memoryReference"]
+                    .as_str()
+                    .and_then(number)
+                    .ok_or("readMemory needs a memoryReference")?
+                    + args["offset"].as_i64().unwrap_or(0);
+                let count = args["count"].as_u64().unwrap_or(0) as i64;
+                let memory = chip8.memory();
+                let begin = start.clamp(0, memory.len() as i64) as usize;
+                let end = (start + count).clamp(0, memory.len() as i64) as usize;
+                let bytes = memory.get(begin..end).unwrap_or_default();
+                Ok(json!({
+                    "address": reference(begin as Word),
+                    "data": base64(bytes),
+                    "unreadableBytes": count - bytes.len() as i64,
+                }))
+            }
+            "disassemble" => {
+                let start = args["memoryReference"]
+                    .as_str()
+                    .and_then(number)
+                    .ok_or("disassemble needs a memoryReference")?
+                    + args["offset"].as_i64().unwrap_or(0)
+                    + args["instructionOffset"].as_i64().unwrap_or(0) * 2;
+                let count = args["instructionCount"].as_u64().unwrap_or(0) as usize;
+                Ok(json!({ "instructions": self.disassemble(chip8, start, count) }))
+            }
+            "continue" => {
+                self.resume(Run::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                let pc = chip8.pc() as usize;
+                let memory = chip8.memory();
+                let opcode = (memory[pc % memory.len()] as Word) << 8
+                    | memory[(pc + 1) % memory.len()] as Word;
+                self.resume(match instruction::decode(opcode, chip8.platform()) {
+                    Ok(Instruction::Call(_)) => Run::Return(chip8.stack().len()),
+                    _ => Run::Step,
+                });
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.resume(Run::Step);
+                Ok(json!({}))
+            }
+            "stepOut" => match chip8.stack().len() {
+                0 => Err("not in a subroutine".to_string()),
+                depth => {
+                    self.resume(Run::Return(depth - 1));
+                    Ok(json!({}))
+                }
+            },
+            "pause" => {
+                self.run = Run::Stopped;
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.quit = args["terminateDebuggee"].as_bool().unwrap_or(false);
+                Ok(json!({}))
+            }
+            _ => Err(format!("`{}` is not supported", command)),
+        }
+    }
+
+    // Load a rom, or build it from assembly or Octo source, into a fresh machine.
+    fn launch(&mut self, chip8: &mut Chip8, path: &str, symbols: Option<&str>) -> Result<()> {
+        let platform = chip8.platform();
+        let program = if path.ends_with(".8o") {
+            Some(octo::compile_file(path, platform)?)
+        } else if path.ends_with(".asm") || path.ends_with(".s") {
+            Some(asm::assemble_file(path, platform)?)
+        } else {
+            None
+        };
+        let rom = match &program {
+            Some(program) => program.rom.clone(),
+            None => std::fs::read(path)?,
+        };
+        let mut fresh = Chip8::with_platform(platform);
+        fresh.set_quirks(chip8.quirks());
+        fresh.set_fault_policy(chip8.fault_policy());
+        fresh.set_tracer(chip8.take_tracer());
+        fresh.load_bytes(&rom)?;
+        *chip8 = fresh;
+
+        self.lines.clear();
+        self.labels.clear();
+        if let Some(program) = program {
+            for (&address, (file, line)) in &program.lines {
+                self.lines.insert(address, (canonical(file), *line));
+            }
+            self.add_labels(program.symbols);
+        }
+        if let Some(symbols) = symbols {
+            self.load_symbols(symbols)?;
+        }
+        Ok(())
+    }
+
+    // Read labels from a symbol file written by `asm --symbols`.
+    fn load_symbols(&mut self, path: &str) -> Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        let symbols = text
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .filter_map(|(name, value)| Some((name.trim().to_string(), number(value.trim())?)))
+            .collect();
+        self.add_labels(symbols);
+        Ok(())
+    }
+
+    // Symbols with values inside the program are taken as labels.
+    fn add_labels(&mut self, symbols: BTreeMap<String, i64>) {
+        for (name, value) in symbols {
+            if (PROGRAM_START as i64..=0xFFFF).contains(&value) {
+                self.labels.entry(value as Word).or_insert(name);
+            }
+        }
+    }
+
+    // The first instruction at or after `line` of `file`, and the line it is on.
+    fn address_of_line(&self, file: &str, line: usize) -> Option<(Word, usize)> {
+        self.lines
+            .iter()
+            .filter(|(_, (source, at))| source == file && *at >= line)
+            .min_by_key(|&(&address, &(_, at))| (at, address))
+            .map(|(&address, &(_, at))| (address, at))
+    }
+
+    // PC, then the CALL instruction of every return address on the stack.
+    fn stack_trace(&self, chip8: &Chip8) -> Value {
+        let mut addresses = vec![chip8.pc()];
+        addresses.extend(
+            chip8
+                .stack()
+                .iter()
+                .rev()
+                .map(|&address| address.wrapping_sub(2)),
+        );
+        let frames: Vec<Value> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, &address)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": self.frame_name(address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": reference(address),
+                });
+                if let Some((file, line)) = self.lines.get(&address) {
+                    frame["source"] = source(file);
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    // the label before `address`, like `draw+0x4`
+    fn frame_name(&self, address: Word) -> String {
+        match self.labels.range(..=address).next_back() {
+            Some((&start, label)) if start == address => label.clone(),
+            Some((&start, label)) => format!("{}+0x{:X}", label, address - start),
+            None => reference(address),
+        }
+    }
+
+    fn disassemble(&self, chip8: &Chip8, start: i64, count: usize) -> Vec<Value> {
+        let memory = chip8.memory();
+        let fetch = |address: i64| {
+            (memory[address as usize] as Word) << 8 | memory[address as usize + 1] as Word
+        };
+        let mut address = start;
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            if !(0..memory.len() as i64 - 1).contains(&address) {
+                // outside of memory, the client still expects `count` entries
+                instructions.push(json!({
+                    "address": format!("0x{:03X}", address.max(0)),
+                    "instruction": "",
+                    "presentationHint": "invalid",
+                }));
+                address += 2;
+                continue;
+            }
+            let opcode = fetch(address);
+            let (text, len) = match instruction::decode(opcode, chip8.platform()) {
+                Ok(Instruction::LdILong) if address + 3 < memory.len() as i64 => {
+                    let long = fetch(address + 2);
+                    let text =
+                        disasm::mnemonic(Instruction::LdILong, Syntax::Cowgod, &self.labels, long);
+                    (text, 4)
+                }
+                Ok(instruction) => (
+                    disasm::mnemonic(instruction, Syntax::Cowgod, &self.labels, 0),
+                    2,
+                ),
+                Err(_) => (format!("DW 0x{:04X}", opcode), 2),
+            };
+            let bytes: Vec<String> = memory[address as usize..address as usize + len]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            let mut entry = json!({
+                "address": reference(address as Word),
+                "instructionBytes": bytes.join(" "),
+                "instruction": text,
+            });
+            if let Some(label) = self.labels.get(&(address as Word)) {
+                entry["symbol"] = json!(label);
+            }
+            if let Some((file, line)) = self.lines.get(&(address as Word)) {
+                entry["location"] = source(file);
+                entry["line"] = json!(line);
+            }
+            instructions.push(entry);
+            address += len as i64;
+        }
+        instructions
+    }
+}
+
+fn registers(chip8: &Chip8) -> Vec<Value> {
+    let byte = |name: String, value: Byte| json!({ "name": name, "value": format!("0x{:02X}", value), "variablesReference": 0 });
+    let mut variables: Vec<Value> = (0..16)
+        .map(|x| byte(format!("V{:X}", x), chip8.register(x)))
+        .collect();
+    variables.push(json!({
+        "name": "I",
+        "value": reference(chip8.index()),
+        "variablesReference": 0,
+        "memoryReference": reference(chip8.index()),
+    }));
+    variables.push(json!({
+        "name": "PC",
+        "value": reference(chip8.pc()),
+        "variablesReference": 0,
+        "memoryReference": reference(chip8.pc()),
+    }));
+    variables.push(byte("SP".to_string(), chip8.stack().len() as Byte));
+    variables.push(byte("DT".to_string(), chip8.delay_timer()));
+    variables.push(byte("ST".to_string(), chip8.sound_timer()));
+    variables
+}
+
+fn set_register(chip8: &mut Chip8, name: &str, value: i64) -> Message<()> {
+    let byte = || Byte::try_from(value).map_err(|_| format!("{} doesn't fit a byte", value));
+    let address = || {
+        if (0..chip8.memory().len() as i64).contains(&value) {
+            Ok(value as Word)
+        } else {
+            Err(format!("0x{:X} is out of memory", value))
+        }
+    };
+    match name {
+        "I" => chip8.set_index(address()?),
+        "PC" => chip8.set_pc(address()?),
+        "SP" => chip8.set_stack_depth(byte()? as usize),
+        "DT" => chip8.set_delay_timer(byte()?),
+        "ST" => chip8.set_sound_timer(byte()?),
+        _ => match name.strip_prefix('V').map(|x| usize::from_str_radix(x, 16)) {
+            Some(Ok(x)) if x < 16 => chip8.set_register(x, byte()?),
+            _ => return Err(format!("unknown register `{}`", name)),
+        },
+    }
+    Ok(())
+}
+
+// Addresses are passed around as `0x204`.
+fn reference(address: Word) -> String {
+    format!("0x{:03X}", address)
+}
+
+fn number(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn source(file: &str) -> Value {
+    let name = std::path::Path::new(file)
+        .file_name()
+        .map_or(file.to_string(), |name| name.to_string_lossy().into_owned());
+    json!({ "name": name, "path": file })
+}
+
+// Paths from the client and from the program are compared in their canonical form.
+fn canonical(path: &str) -> String {
+    std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let triple = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |triple, (i, &b)| triple | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(triple >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base64, DapServer};
+    use crate::chip8::Chip8;
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    struct Session {
+        server: DapServer,
+        chip8: Chip8,
+        stream: TcpStream,
+        received: Vec<u8>,
+        seq: i64,
+        events: Vec<Value>,
+    }
+
+    impl Session {
+        fn new() -> Self {
+            let server = DapServer::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            Session {
+                server,
+                chip8: Chip8::new(),
+                stream,
+                received: Vec::new(),
+                seq: 0,
+                events: Vec::new(),
+            }
+        }
+
+        // Run frames until the next message arrives.
+        fn message(&mut self) -> Value {
+            for _ in 0..500 {
+                if let Some(header_end) = self.received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let header = String::from_utf8_lossy(&self.received[..header_end]).to_string();
+                    let len: usize = header["Content-Length: ".len()..].parse().unwrap();
+                    if self.received.len() >= header_end + 4 + len {
+                        let body: Vec<u8> = self.received.drain(..header_end + 4 + len).collect();
+                        return serde_json::from_slice(&body[header_end + 4..]).unwrap();
+                    }
+                }
+                self.server.run_frame(&mut self.chip8, 10);
+                let mut buffer = [0; 4096];
+                if let Ok(len) = self.stream.read(&mut buffer) {
+                    self.received.extend_from_slice(&buffer[..len]);
+                }
+            }
+            panic!("no message");
+        }
+
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let body = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            let data = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+            self.stream.write_all(data.as_bytes()).unwrap();
+            loop {
+                let message = self.message();
+                if message["type"] == "response" && message["request_seq"] == self.seq {
+                    assert_eq!(message["command"], command);
+                    return message;
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn event(&mut self, event: &str) -> Value {
+            if let Some(at) = self.events.iter().position(|e| e["event"] == event) {
+                return self.events.remove(at);
+            }
+            loop {
+                let message = self.message();
+                if message["event"] == event {
+                    return message;
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn pc(&mut self) -> String {
+            let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+            trace["body"]["stackFrames"][0]["instructionPointerReference"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chip8_dap_{}_{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_rom_session() {
+        // 0x200: V0 = 1, call 0x208, V2 = 3, jump 0x206
+        // 0x208: V1 = 2, return
+        let rom = [
+            0x60, 0x01, 0x22, 0x08, 0x62, 0x03, 0x12, 0x06, 0x61, 0x02, 0x00, 0xEE,
+        ];
+        let path = temp_file("rom.ch8", &rom);
+        let mut session = Session::new();
+        let initialize = session.request("initialize", json!({ "adapterID": "chip8" }));
+        assert_eq!(initialize["body"]["supportsInstructionBreakpoints"], true);
+        session.event("initialized");
+        let launch = session.request(
+            "launch",
+            json!({ "program": path.to_str().unwrap(), "stopOnEntry": true }),
+        );
+        assert_eq!(launch["success"], true);
+        session.request("configurationDone", json!({}));
+        assert_eq!(session.event("stopped")["body"]["reason"], "entry");
+        assert_eq!(session.pc(), "0x200");
+
+        let breakpoints = session.request(
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0x208", "offset": 2 }] }),
+        );
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["verified"], true);
+        session.request("continue", json!({ "threadId": 1 }));
+        let stopped = session.event("stopped");
+        assert_eq!(stopped["body"]["reason"], "instruction breakpoint");
+
+        // inside the subroutine, called from 0x202
+        let trace = session.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = &trace["body"]["stackFrames"];
+        assert_eq!(frames[0]["instructionPointerReference"], "0x20A");
+        assert_eq!(frames[1]["instructionPointerReference"], "0x202");
+
+        let variables = session.request("variables", json!({ "variablesReference": 1 }));
+        let variables = variables["body"]["variables"].as_array().unwrap();
+        assert_eq!(
+            variables[1],
+            json!({ "name": "V1", "value": "0x02", "variablesReference": 0 })
+        );
+        assert!(variables
+            .iter()
+            .any(|v| v["name"] == "SP" && v["value"] == "0x01"));
+        let set = session.request(
+            "setVariable",
+            json!({ "variablesReference": 1, "name": "V5", "value": "0x7F" }),
+        );
+        assert_eq!(set["body"]["value"], "0x7F");
+        assert_eq!(session.chip8.register(5), 0x7F);
+
+        let memory = session.request(
+            "readMemory",
+            json!({ "memoryReference": "0x200", "offset": 0, "count": 4 }),
+        );
+        assert_eq!(memory["body"]["data"], base64(&rom[..4]));
+        let disassembly = session.request(
+            "disassemble",
+            json!({ "memoryReference": "0x208", "instructionCount": 2 }),
+        );
+        let instructions = &disassembly["body"]["instructions"];
+        assert_eq!(instructions[0]["instruction"], "LD V1, 0x02");
+        assert_eq!(instructions[1]["instructionBytes"], "00 EE");
+
+        session.request("stepOut", json!({ "threadId": 1 }));
+        assert_eq!(session.event("stopped")["body"]["reason"], "step");
+        assert_eq!(session.pc(), "0x204");
+        session.request("next", json!({ "threadId": 1 }));
+        session.event("stopped");
+        assert_eq!(session.pc(), "0x206");
+        let step_out = session.request("stepOut", json!({ "threadId": 1 }));
+        assert_eq!(step_out["success"], false);
+
+        session.request("disconnect", json!({ "terminateDebuggee": false }));
+        assert!(!session.server.stopped());
+        assert!(!session.server.quitting());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_source_breakpoints() {
+        let source = "\
+main:   LD V0, 1
+        CALL draw
+loop:   JP loop
+
+draw:   LD V1, 2
+        RET
+";
+        let path = temp_file("source.asm", source.as_bytes());
+        let path = path.to_str().unwrap();
+        let mut session = Session::new();
+        session.request("initialize", json!({}));
+        session.request("launch", json!({ "program": path }));
+        let breakpoints = session.request(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 4 }, { "line": 9 }] }),
+        );
+        let breakpoints = &breakpoints["body"]["breakpoints"];
+        // the empty line 4 moves to the instruction on line 5
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 5);
+        assert_eq!(breakpoints[1]["verified"], false);
+        session.request("configurationDone", json!({}));
+        assert_eq!(session.event("stopped")["body"]["reason"], "breakpoint");
+
+        let trace = session.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = &trace["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "draw");
+        assert_eq!(frames[0]["line"], 5);
+        assert_eq!(frames[1]["name"], "main+0x2");
+        assert_eq!(frames[1]["line"], 2);
+        assert!(frames[1]["source"]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("source.asm"));
+
+        // a fault stops with an exception
+        session.chip8.set_stack_depth(0);
+        session.request("continue", json!({ "threadId": 1 }));
+        let stopped = session.event("stopped");
+        assert_eq!(stopped["body"]["reason"], "exception");
+        assert!(stopped["body"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("Stack underflow"));
+
+        session.request("disconnect", json!({ "terminateDebuggee": true }));
+        assert!(session.server.quitting());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xFF, 0x00, 0x10, 0x80]), "/wAQgA==");
+    }
+}
@@ -0,0 +1,919 @@
+use super::chip8::{Access, Byte, Chip8, Location, Word};
+use super::disasm::{self, Syntax};
+use super::instruction::{self, Instruction};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+const HELP: &str = "\
+step [N]               s  execute N instructions, 1 by default
+next                   n  step, but run subroutine calls as a whole
+finish                 f  run until the current subroutine returns
+continue               c  run until a breakpoint
+pause                     stop running
+break ADDR [if COND]   b  stop before the instruction at ADDR
+watch LOC [if COND]       stop after LOC (Vx or a memory address) is written
+rwatch LOC [if COND]      stop after LOC is read
+awatch LOC [if COND]      stop after LOC is read or written
+delete [ADDR|Vx]       d  remove breakpoints and watchpoints, or all of them
+info                      list the breakpoints and watchpoints
+regs                   r  show V0-VF, I, PC, the stack and the timers
+mem ADDR [LEN]         x  hex dump of memory
+set REG VALUE             change V0-VF, I, PC, DT or ST
+poke ADDR BYTE..          change memory
+list [ADDR]            l  disassembly around PC or ADDR
+quit                   q  exit the emulator
+Conditions compare V0-VF, I, PC, SP, DT, ST, memory bytes [ADDR] and numbers
+with == != < > <= >=, combined with && || ! and parentheses.
+An empty line repeats the last command.";
+
+type Message<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Run {
+    Paused,
+    // until a breakpoint
+    Continue,
+    // this many more instructions
+    Step(u32),
+    // until the stack is back to this depth, for next and finish
+    Return(usize),
+}
+
+// Which accesses a watchpoint stops at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    location: Location,
+    watch: Watch,
+    condition: Option<Condition>,
+}
+
+// An interactive step debugger. It is driven by text commands and runs the machine
+// through `Chip8::run_frame_until`, so it works on the same state as `execute()`.
+// It starts paused, before the first instruction.
+pub struct Debugger {
+    breakpoints: BTreeMap<Word, Option<Condition>>,
+    watchpoints: Vec<Watchpoint>,
+    run: Run,
+    // nothing stops the first instruction after resuming,
+    // it may be the breakpoint the debugger stopped at
+    resumed: bool,
+    // address of the last instruction executed, watchpoints stop after it
+    last_pc: Word,
+    last_command: String,
+    quit: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            run: Run::Paused,
+            resumed: false,
+            last_pc: 0,
+            last_command: String::new(),
+            quit: false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.run == Run::Paused
+    }
+
+    // whether `quit` was entered
+    pub fn quitting(&self) -> bool {
+        self.quit
+    }
+
+    // Run the command `line` and return what to print.
+    pub fn command(&mut self, chip8: &mut Chip8, line: &str) -> String {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.trim().to_string();
+            line.trim().to_string()
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return String::new();
+        }
+        self.execute(chip8, &words)
+            .unwrap_or_else(|e| format!("error: {}", e))
+    }
+
+    fn execute(&mut self, chip8: &mut Chip8, words: &[&str]) -> Message<String> {
+        // `break ADDR if V3 > 10` and the like, the condition is the rest of the line
+        let (words, condition) = match words.iter().position(|&word| word == "if") {
+            Some(at) => (
+                &words[..at],
+                Some(Condition::parse(&words[at + 1..].join(" "))?),
+            ),
+            None => (words, None),
+        };
+        let args = &words[1..];
+        let arg = |i: usize| -> Message<u32> {
+            args.get(i)
+                .ok_or_else(|| format!("`{}` needs more arguments", words[0]))
+                .and_then(|arg| number(arg))
+        };
+        if condition.is_some() && !matches!(words[0], "break" | "b" | "watch" | "rwatch" | "awatch")
+        {
+            return Err(format!("`{}` takes no condition", words[0]));
+        }
+        match words[0] {
+            "step" | "s" => {
+                let count = if args.is_empty() { 1 } else { arg(0)? };
+                self.resume(Run::Step(count.max(1)));
+            }
+            "next" | "n" => {
+                let opcode = fetch(chip8, chip8.pc());
+                self.resume(match instruction::decode(opcode, chip8.platform()) {
+                    Ok(Instruction::Call(_)) => Run::Return(chip8.stack().len()),
+                    _ => Run::Step(1),
+                });
+            }
+            "finish" | "f" => match chip8.stack().len() {
+                0 => return Err("not in a subroutine".to_string()),
+                depth => self.resume(Run::Return(depth - 1)),
+            },
+            "continue" | "c" => self.resume(Run::Continue),
+            "pause" => {
+                self.run = Run::Paused;
+                return Ok(current(chip8, &self.breakpoints));
+            }
+            "break" | "b" => {
+                let address = address(chip8, arg(0)?)?;
+                let text = format!(
+                    "breakpoint at 0x{:03X}{}",
+                    address,
+                    condition_text(&condition)
+                );
+                self.breakpoints.insert(address, condition);
+                return Ok(text);
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let name = args
+                    .first()
+                    .ok_or_else(|| format!("`{}` needs more arguments", words[0]))?;
+                let location = match register(&name.to_ascii_uppercase()) {
+                    Some(x) => Location::Register(x as Byte),
+                    None => Location::Memory(address(chip8, number(name)?)? as usize),
+                };
+                let watch = match words[0] {
+                    "watch" => Watch::Write,
+                    "rwatch" => Watch::Read,
+                    _ => Watch::Access,
+                };
+                let watchpoint = Watchpoint {
+                    location,
+                    watch,
+                    condition,
+                };
+                let text = format!("watchpoint: {}", watchpoint);
+                self.watchpoints.push(watchpoint);
+                chip8.set_watching(true);
+                return Ok(text);
+            }
+            "delete" | "d" => {
+                if args.is_empty() {
+                    self.breakpoints.clear();
+                    self.watchpoints.clear();
+                } else {
+                    let location = match register(&args[0].to_ascii_uppercase()) {
+                        Some(x) => Location::Register(x as Byte),
+                        None => Location::Memory(arg(0)? as usize),
+                    };
+                    let count = self.breakpoints.len() + self.watchpoints.len();
+                    if let Location::Memory(address) = location {
+                        self.breakpoints.remove(&(address as Word));
+                    }
+                    self.watchpoints
+                        .retain(|watchpoint| watchpoint.location != location);
+                    if self.breakpoints.len() + self.watchpoints.len() == count {
+                        return Err(format!("nothing to delete at {}", args[0]));
+                    }
+                }
+                chip8.set_watching(!self.watchpoints.is_empty());
+            }
+            "info" => {
+                if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+                    return Ok("no breakpoints or watchpoints".to_string());
+                }
+                let breakpoints = self.breakpoints.iter().map(|(address, condition)| {
+                    format!("break 0x{:03X}{}", address, condition_text(condition))
+                });
+                let watchpoints = self
+                    .watchpoints
+                    .iter()
+                    .map(|watchpoint| watchpoint.to_string());
+                return Ok(breakpoints
+                    .chain(watchpoints)
+                    .collect::<Vec<_>>()
+                    .join("\n"));
+            }
+            "regs" | "r" => return Ok(registers(chip8)),
+            "mem" | "x" => {
+                let start = address(chip8, arg(0)?)?;
+                let len = if args.len() > 1 { arg(1)? } else { 64 };
+                return Ok(hex_dump(chip8, start, len));
+            }
+            "set" => {
+                let value = arg(1)?;
+                let name = args[0].to_ascii_uppercase();
+                let byte =
+                    || u8::try_from(value).map_err(|_| format!("{} doesn't fit a byte", value));
+                match name.as_str() {
+                    "I" => chip8.set_index(address(chip8, value)?),
+                    "PC" => chip8.set_pc(address(chip8, value)?),
+                    "DT" => chip8.set_delay_timer(byte()?),
+                    "ST" => chip8.set_sound_timer(byte()?),
+                    _ => match register(&name) {
+                        Some(x) => chip8.set_register(x, byte()?),
+                        None => return Err(format!("unknown register `{}`", args[0])),
+                    },
+                }
+                return Ok(registers(chip8));
+            }
+            "poke" => {
+                let start = address(chip8, arg(0)?)? as usize;
+                for i in 1..args.len().max(2) {
+                    let value = arg(i)?;
+                    let byte =
+                        u8::try_from(value).map_err(|_| format!("{} doesn't fit a byte", value))?;
+                    let memory = chip8.memory_mut();
+                    let len = memory.len();
+                    memory[(start + i - 1) % len] = byte;
+                }
+                return Ok(hex_dump(chip8, start as Word, args.len() as u32 - 1));
+            }
+            "list" | "l" => {
+                let start = if args.is_empty() {
+                    chip8.pc().saturating_sub(8)
+                } else {
+                    address(chip8, arg(0)?)?
+                };
+                return Ok(listing(chip8, &self.breakpoints, start, 10));
+            }
+            "quit" | "q" => {
+                self.quit = true;
+                self.run = Run::Paused;
+            }
+            "help" | "h" => return Ok(HELP.to_string()),
+            command => return Err(format!("unknown command `{}`, try `help`", command)),
+        }
+        Ok(String::new())
+    }
+
+    fn resume(&mut self, run: Run) {
+        self.run = run;
+        self.resumed = true;
+    }
+
+    // Run one frame unless paused. Returns what to print when the machine stopped:
+    // at a breakpoint or watchpoint, after stepping, at a fault or when the program exited.
+    pub fn run_frame(&mut self, chip8: &mut Chip8, ipf: u32) -> Option<String> {
+        if self.paused() {
+            return None;
+        }
+        let Debugger {
+            breakpoints,
+            watchpoints,
+            run,
+            resumed,
+            last_pc,
+            ..
+        } = self;
+        let mut reason = String::new();
+        let result = chip8.run_frame_until(ipf, |chip8| {
+            if chip8.halted() {
+                reason = "the program exited".to_string();
+                return true;
+            }
+            if !*resumed {
+                // the accesses are those of the instruction that just ran
+                for &access in chip8.accesses() {
+                    if let Some(watchpoint) = watchpoints.iter().find(|w| w.hit(chip8, access)) {
+                        reason = watchpoint.report(chip8, access, *last_pc);
+                        return true;
+                    }
+                }
+                if let Some(condition) = breakpoints.get(&chip8.pc()) {
+                    if condition.as_ref().is_none_or(|c| c.holds(chip8)) {
+                        reason = format!("breakpoint at 0x{:03X}", chip8.pc());
+                        return true;
+                    }
+                }
+                match *run {
+                    Run::Step(0) => return true,
+                    Run::Return(depth) if chip8.stack().len() <= depth => return true,
+                    _ => (),
+                }
+            }
+            *resumed = false;
+            *last_pc = chip8.pc();
+            if let Run::Step(count) = run {
+                *count -= 1;
+            }
+            false
+        });
+        match result {
+            Ok(false) => None,
+            Ok(true) => {
+                self.run = Run::Paused;
+                if !reason.is_empty() {
+                    reason.push('\n');
+                }
+                Some(reason + &current(chip8, &self.breakpoints))
+            }
+            Err(e) => {
+                // the machine is left at the faulting instruction, it can be inspected and fixed
+                self.run = Run::Paused;
+                Some(format!("{}\n{}", e, current(chip8, &self.breakpoints)))
+            }
+        }
+    }
+}
+
+impl Watchpoint {
+    fn hit(&self, chip8: &Chip8, access: Access) -> bool {
+        let matches = match (access, self.watch) {
+            (Access::Read(location), Watch::Read | Watch::Access) => location == self.location,
+            (Access::Write(location, _), Watch::Write | Watch::Access) => location == self.location,
+            _ => false,
+        };
+        matches && self.condition.as_ref().is_none_or(|c| c.holds(chip8))
+    }
+
+    fn report(&self, chip8: &Chip8, access: Access, pc: Word) -> String {
+        let name = location_name(self.location);
+        let value = match self.location {
+            Location::Register(x) => chip8.register(x as usize),
+            Location::Memory(address) => chip8.memory()[address],
+        };
+        match access {
+            Access::Read(_) => format!("{} read at 0x{:03X}: {:02X}", name, pc, value),
+            Access::Write(_, old) => format!(
+                "{} written at 0x{:03X}: {:02X} -> {:02X}",
+                name, pc, old, value
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    // as the command that sets it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let command = match self.watch {
+            Watch::Write => "watch",
+            Watch::Read => "rwatch",
+            Watch::Access => "awatch",
+        };
+        write!(
+            f,
+            "{} {}{}",
+            command,
+            location_name(self.location),
+            condition_text(&self.condition)
+        )
+    }
+}
+
+fn location_name(location: Location) -> String {
+    match location {
+        Location::Register(x) => format!("V{:X}", x),
+        Location::Memory(address) => format!("0x{:03X}", address),
+    }
+}
+
+fn condition_text(condition: &Option<Condition>) -> String {
+    match condition {
+        Some(condition) => format!(" if {}", condition.text),
+        None => String::new(),
+    }
+}
+
+// A condition of a breakpoint or watchpoint, like `V3 > 10 && I == 0x300`.
+struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+enum Expr {
+    Number(i64),
+    Register(usize),
+    Index,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    // the memory byte at an address
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Condition {
+    fn parse(text: &str) -> Message<Condition> {
+        let mut parser = ConditionParser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected `{}` in condition", token));
+        }
+        Ok(Condition {
+            text: text.to_string(),
+            expr,
+        })
+    }
+
+    fn holds(&self, chip8: &Chip8) -> bool {
+        self.expr.eval(chip8) != 0
+    }
+}
+
+impl Expr {
+    fn eval(&self, chip8: &Chip8) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Register(x) => chip8.register(*x) as i64,
+            Expr::Index => chip8.index() as i64,
+            Expr::Pc => chip8.pc() as i64,
+            Expr::Sp => chip8.stack().len() as i64,
+            Expr::Dt => chip8.delay_timer() as i64,
+            Expr::St => chip8.sound_timer() as i64,
+            Expr::Memory(address) => {
+                let memory = chip8.memory();
+                let address = address.eval(chip8).rem_euclid(memory.len() as i64);
+                memory[address as usize] as i64
+            }
+            Expr::Not(expr) => (expr.eval(chip8) == 0) as i64,
+            Expr::Binary(op, left, right) => {
+                let left = left.eval(chip8);
+                // && and || short-circuit
+                let right = || right.eval(chip8);
+                (match op {
+                    Op::Or => left != 0 || right() != 0,
+                    Op::And => left != 0 && right() != 0,
+                    Op::Eq => left == right(),
+                    Op::Ne => left != right(),
+                    Op::Lt => left < right(),
+                    Op::Gt => left > right(),
+                    Op::Le => left <= right(),
+                    Op::Ge => left >= right(),
+                }) as i64
+            }
+        }
+    }
+}
+
+// Words like `V3` or `0x300` and the operators.
+fn tokenize(text: &str) -> Message<Vec<String>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '#' || c == '$' {
+            let len = chars[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '#' || **c == '$')
+                .count();
+            tokens.push(chars[i..i + len].iter().collect());
+            i += len;
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()) {
+                tokens.push(pair);
+                i += 2;
+            } else if "<>!()[]".contains(c) {
+                tokens.push(c.to_string());
+                i += 1;
+            } else {
+                return Err(format!("unexpected `{}` in condition", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent, from the lowest precedence: || then && then comparisons.
+struct ConditionParser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl ConditionParser {
+    fn next(&mut self) -> Message<String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| "the condition ends too early".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.tokens.get(self.pos).map(String::as_str) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Message<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}` in condition", token))
+        }
+    }
+
+    fn or(&mut self) -> Message<Expr> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Binary(Op::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Message<Expr> {
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            left = Expr::Binary(Op::And, Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Message<Expr> {
+        let left = self.atom()?;
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        for (token, op) in ops {
+            if self.eat(token) {
+                return Ok(Expr::Binary(op, Box::new(left), Box::new(self.atom()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn atom(&mut self) -> Message<Expr> {
+        let token = self.next()?;
+        Ok(match token.to_ascii_uppercase().as_str() {
+            "(" => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                expr
+            }
+            "[" => {
+                let address = self.or()?;
+                self.expect("]")?;
+                Expr::Memory(Box::new(address))
+            }
+            "!" => Expr::Not(Box::new(self.atom()?)),
+            "I" => Expr::Index,
+            "PC" => Expr::Pc,
+            "SP" => Expr::Sp,
+            "DT" => Expr::Dt,
+            "ST" => Expr::St,
+            name => match register(name) {
+                Some(x) => Expr::Register(x),
+                None => Expr::Number(number(&token)? as i64),
+            },
+        })
+    }
+}
+
+// Numbers are decimal, or hex with 0x, # or $.
+fn number(text: &str) -> Message<u32> {
+    let lower = text.to_ascii_lowercase();
+    let hex = lower
+        .strip_prefix("0x")
+        .or_else(|| lower.strip_prefix('#'))
+        .or_else(|| lower.strip_prefix('$'));
+    match hex {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => lower.parse(),
+    }
+    .map_err(|_| format!("`{}` is not a number", text))
+}
+
+fn address(chip8: &Chip8, value: u32) -> Message<Word> {
+    if (value as usize) < chip8.memory().len() {
+        Ok(value as Word)
+    } else {
+        Err(format!("0x{:X} is out of memory", value))
+    }
+}
+
+// V0-VF by name
+fn register(name: &str) -> Option<usize> {
+    let digit = name.strip_prefix('V')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    usize::from_str_radix(digit, 16).ok()
+}
+
+fn fetch(chip8: &Chip8, address: Word) -> Word {
+    let memory = chip8.memory();
+    let byte = |offset: usize| memory[(address as usize + offset) % memory.len()] as Word;
+    byte(0) << 8 | byte(1)
+}
+
+// PC, I, SP, the timers and V0-VF, as printed by `regs`.
+pub fn registers(chip8: &Chip8) -> String {
+    let mut text = format!(
+        "PC 0x{:03X}  I 0x{:03X}  SP {}  DT {}  ST {}\n",
+        chip8.pc(),
+        chip8.index(),
+        chip8.stack().len(),
+        chip8.delay_timer(),
+        chip8.sound_timer()
+    );
+    for row in 0..2 {
+        let line: Vec<String> = (row * 8..row * 8 + 8)
+            .map(|x| format!("V{:X} {:02X}", x, chip8.register(x)))
+            .collect();
+        writeln!(text, "{}", line.join("  ")).unwrap();
+    }
+    let stack: Vec<String> = chip8
+        .stack()
+        .iter()
+        .map(|address| format!("0x{:03X}", address))
+        .collect();
+    write!(text, "stack [{}]", stack.join(" ")).unwrap();
+    text
+}
+
+// `len` bytes from `start`, 16 per line, as printed by `mem`.
+pub fn hex_dump(chip8: &Chip8, start: Word, len: u32) -> String {
+    let memory = chip8.memory();
+    let end = (start as usize + len as usize).min(memory.len());
+    let lines: Vec<String> = (start as usize..end)
+        .step_by(16)
+        .map(|row| {
+            let bytes: Vec<String> = memory[row..(row + 16).min(end)]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            format!("0x{:03X}  {}", row, bytes.join(" "))
+        })
+        .collect();
+    lines.join("\n")
+}
+
+// `count` instructions from `start`, PC is marked with `=>` and breakpoints with `*`.
+fn listing(
+    chip8: &Chip8,
+    breakpoints: &BTreeMap<Word, Option<Condition>>,
+    start: Word,
+    count: usize,
+) -> String {
+    let mut address = start;
+    let mut lines = Vec::new();
+    for _ in 0..count {
+        if address as usize + 1 >= chip8.memory().len() {
+            break;
+        }
+        let opcode = fetch(chip8, address);
+        let (text, len) = match instruction::decode(opcode, chip8.platform()) {
+            Ok(Instruction::LdILong) => {
+                let long = fetch(chip8, address.wrapping_add(2));
+                let text =
+                    disasm::mnemonic(Instruction::LdILong, Syntax::Cowgod, &BTreeMap::new(), long);
+                (text, 4)
+            }
+            Ok(instruction) => (
+                disasm::mnemonic(instruction, Syntax::Cowgod, &BTreeMap::new(), 0),
+                2,
+            ),
+            Err(_) => ("???".to_string(), 2),
+        };
+        let marker = match (address == chip8.pc(), breakpoints.contains_key(&address)) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        lines.push(format!(
+            "{} 0x{:03X}  {:04X}  {}",
+            marker, address, opcode, text
+        ));
+        address = address.wrapping_add(len);
+    }
+    lines.join("\n")
+}
+
+// the instruction at PC
+fn current(chip8: &Chip8, breakpoints: &BTreeMap<Word, Option<Condition>>) -> String {
+    listing(chip8, breakpoints, chip8.pc(), 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Debugger;
+    use crate::chip8::Chip8;
+
+    // 0x200: V0 = 1, call 0x208, V2 = 3, jump 0x206
+    // 0x208: V1 = 2, return
+    const ROM: [u8; 12] = [
+        0x60, 0x01, 0x22, 0x08, 0x62, 0x03, 0x12, 0x06, 0x61, 0x02, 0x00, 0xEE,
+    ];
+
+    fn machine() -> (Chip8, Debugger) {
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&ROM).unwrap();
+        (chip8, Debugger::new())
+    }
+
+    #[test]
+    fn test_step() {
+        let (mut chip8, mut debugger) = machine();
+        assert!(debugger.paused());
+        assert_eq!(debugger.run_frame(&mut chip8, 10), None);
+        assert_eq!(chip8.pc(), 0x200);
+
+        debugger.command(&mut chip8, "step");
+        let report = debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(report, "=> 0x202  2208  CALL 0x208");
+        assert_eq!(chip8.register(0), 1);
+
+        // next runs the whole subroutine
+        debugger.command(&mut chip8, "next");
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.register(1), 2);
+
+        // an empty line repeats the last command
+        debugger.command(&mut chip8, "");
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(chip8.pc(), 0x206);
+        assert_eq!(chip8.register(2), 3);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut chip8, mut debugger) = machine();
+        debugger.command(&mut chip8, "break 0x20A");
+        debugger.command(&mut chip8, "continue");
+        let report = debugger.run_frame(&mut chip8, 100).unwrap();
+        assert!(report.starts_with("breakpoint at 0x20A\n=> 0x20A"));
+        assert_eq!(chip8.stack(), &[0x204]);
+
+        debugger.command(&mut chip8, "finish");
+        debugger.run_frame(&mut chip8, 100).unwrap();
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(
+            debugger.command(&mut chip8, "finish"),
+            "error: not in a subroutine"
+        );
+
+        // without breakpoints it runs for good
+        debugger.command(&mut chip8, "delete");
+        debugger.command(&mut chip8, "c");
+        assert_eq!(debugger.run_frame(&mut chip8, 100), None);
+        assert!(!debugger.paused());
+        debugger.command(&mut chip8, "pause");
+        assert!(debugger.paused());
+    }
+
+    #[test]
+    fn test_inspect_and_change() {
+        let (mut chip8, mut debugger) = machine();
+        debugger.command(&mut chip8, "set v3 0x12");
+        debugger.command(&mut chip8, "set I 0x300");
+        debugger.command(&mut chip8, "poke 0x300 1 2 0xFF");
+        assert_eq!(chip8.register(3), 0x12);
+        assert_eq!(chip8.index(), 0x300);
+        assert_eq!(&chip8.memory()[0x300..0x303], &[1, 2, 0xFF]);
+        assert_eq!(
+            debugger.command(&mut chip8, "mem 0x300 4"),
+            "0x300  01 02 FF 00"
+        );
+        let regs = debugger.command(&mut chip8, "regs");
+        assert!(regs.starts_with("PC 0x200  I 0x300  SP 0"));
+        assert!(regs.contains("V3 12"));
+
+        debugger.command(&mut chip8, "b 0x204");
+        let listing = debugger.command(&mut chip8, "list 0x200");
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "=> 0x200  6001  LD V0, 0x01");
+        assert_eq!(lines[2], " * 0x204  6203  LD V2, 0x03");
+
+        assert!(debugger
+            .command(&mut chip8, "set v3 256")
+            .starts_with("error"));
+        assert!(debugger.command(&mut chip8, "set x 1").starts_with("error"));
+        assert!(debugger
+            .command(&mut chip8, "frobnicate")
+            .starts_with("error"));
+        debugger.command(&mut chip8, "quit");
+        assert!(debugger.quitting());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // V0 = 5, I = 0x300, then forever: BCD V0, V0 += 1
+        let mut chip8 = Chip8::new();
+        chip8
+            .load_bytes(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0x70, 0x01, 0x12, 0x04])
+            .unwrap();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut chip8, "watch 0x302");
+        debugger.command(&mut chip8, "c");
+        let report = debugger.run_frame(&mut chip8, 100).unwrap();
+        assert!(report.starts_with("0x302 written at 0x204: 00 -> 05\n=> 0x206"));
+        debugger.command(&mut chip8, "c");
+        let report = debugger.run_frame(&mut chip8, 100).unwrap();
+        assert!(report.starts_with("0x302 written at 0x204: 05 -> 06"));
+
+        debugger.command(&mut chip8, "delete 0x302");
+        debugger.command(&mut chip8, "rwatch v0 if V0 == 9 || [0x302] > 7");
+        debugger.command(&mut chip8, "c");
+        let report = debugger.run_frame(&mut chip8, 100).unwrap();
+        assert!(report.starts_with("V0 read at 0x204: 08"));
+        assert_eq!(
+            debugger.command(&mut chip8, "info"),
+            "rwatch V0 if V0 == 9 || [0x302] > 7"
+        );
+
+        debugger.command(&mut chip8, "delete");
+        assert!(chip8.accesses().is_empty());
+        assert!(debugger
+            .command(&mut chip8, "watch 0x10000")
+            .starts_with("error"));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let (mut chip8, mut debugger) = machine();
+        debugger.command(&mut chip8, "b 0x20A if (V0 == 1 && !(I != 0)) && SP >= 1");
+        debugger.command(&mut chip8, "b 0x204 if V1 == 3");
+        debugger.command(&mut chip8, "c");
+        let report = debugger.run_frame(&mut chip8, 100).unwrap();
+        assert!(report.starts_with("breakpoint at 0x20A"));
+        debugger.command(&mut chip8, "c");
+        // V1 is never 3
+        assert_eq!(debugger.run_frame(&mut chip8, 100), None);
+
+        for bad in [
+            "b 0x200 if V0 ==",
+            "b 0x200 if (V0",
+            "b 0x200 if V0 @ 1",
+            "s if V0",
+        ] {
+            assert!(
+                debugger.command(&mut chip8, bad).starts_with("error"),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_fault_pauses() {
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&[0x00, 0xEE]).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut chip8, "c");
+        let report = debugger.run_frame(&mut chip8, 10).unwrap();
+        assert!(report.starts_with("Stack underflow"));
+        assert!(debugger.paused());
+        assert_eq!(chip8.pc(), 0x200);
+    }
+}
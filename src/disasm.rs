@@ -0,0 +1,396 @@
+use super::chip8::{Byte, Word, PROGRAM_START};
+use super::error::Error;
+use super::instruction::{decode, Instruction};
+use super::quirks::Platform;
+use super::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::str::FromStr;
+
+// The two common assembly syntaxes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    // the mnemonics of Cowgod's Chip-8 technical reference: `LD V0, 0x12`
+    Cowgod,
+    // the Octo language: `v0 := 0x12`
+    Octo,
+}
+
+impl FromStr for Syntax {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "cowgod" | "classic" => Ok(Syntax::Cowgod),
+            "octo" => Ok(Syntax::Octo),
+            _ => Err(Error::Config(format!("unknown syntax `{}`", s))),
+        }
+    }
+}
+
+// Render an instruction as assembly. Addresses are replaced by their label if they have one,
+// `long` is the address word of F000 NNNN.
+pub fn mnemonic(
+    instruction: Instruction,
+    syntax: Syntax,
+    labels: &BTreeMap<Word, String>,
+    long: Word,
+) -> String {
+    use Instruction::*;
+    let addr = |address: Word| match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("0x{:03X}", address),
+    };
+    match syntax {
+        Syntax::Cowgod => match instruction {
+            Sys(nnn) => format!("SYS {}", addr(nnn)),
+            Cls => "CLS".to_string(),
+            Ret => "RET".to_string(),
+            Scd(n) => format!("SCD {}", n),
+            Scu(n) => format!("SCU {}", n),
+            Scr => "SCR".to_string(),
+            Scl => "SCL".to_string(),
+            Exit => "EXIT".to_string(),
+            Low => "LOW".to_string(),
+            High => "HIGH".to_string(),
+            Jp(nnn) => format!("JP {}", addr(nnn)),
+            Call(nnn) => format!("CALL {}", addr(nnn)),
+            SeByte { x, nn } => format!("SE V{:X}, 0x{:02X}", x, nn),
+            SneByte { x, nn } => format!("SNE V{:X}, 0x{:02X}", x, nn),
+            Se { x, y } => format!("SE V{:X}, V{:X}", x, y),
+            SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+            LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+            LdByte { x, nn } => format!("LD V{:X}, 0x{:02X}", x, nn),
+            AddByte { x, nn } => format!("ADD V{:X}, 0x{:02X}", x, nn),
+            Ld { x, y } => format!("LD V{:X}, V{:X}", x, y),
+            Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+            And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+            Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+            Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+            Shr { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+            Subn { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+            Shl { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+            Sne { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => format!("LD I, {}", addr(nnn)),
+            JpV0(nnn) => format!("JP V0, {}", addr(nnn)),
+            Rnd { x, nn } => format!("RND V{:X}, 0x{:02X}", x, nn),
+            Drw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => format!("SKP V{:X}", x),
+            Sknp(x) => format!("SKNP V{:X}", x),
+            LdILong => format!("LD I, LONG {}", addr(long)),
+            Plane(n) => format!("PLANE {}", n),
+            Audio => "AUDIO".to_string(),
+            LdVxDt(x) => format!("LD V{:X}, DT", x),
+            LdVxK(x) => format!("LD V{:X}, K", x),
+            LdDtVx(x) => format!("LD DT, V{:X}", x),
+            LdStVx(x) => format!("LD ST, V{:X}", x),
+            AddI(x) => format!("ADD I, V{:X}", x),
+            LdF(x) => format!("LD F, V{:X}", x),
+            LdHf(x) => format!("LD HF, V{:X}", x),
+            LdB(x) => format!("LD B, V{:X}", x),
+            Pitch(x) => format!("PITCH V{:X}", x),
+            LdIVx(x) => format!("LD [I], V{:X}", x),
+            LdVxI(x) => format!("LD V{:X}, [I]", x),
+            LdRVx(x) => format!("LD R, V{:X}", x),
+            LdVxR(x) => format!("LD V{:X}, R", x),
+        },
+        Syntax::Octo => match instruction {
+            // Octo has no mnemonic for machine code routines
+            Sys(nnn) => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF),
+            Cls => "clear".to_string(),
+            Ret => "return".to_string(),
+            Scd(n) => format!("scroll-down {}", n),
+            Scu(n) => format!("scroll-up {}", n),
+            Scr => "scroll-right".to_string(),
+            Scl => "scroll-left".to_string(),
+            Exit => "exit".to_string(),
+            Low => "lores".to_string(),
+            High => "hires".to_string(),
+            Jp(nnn) => format!("jump {}", addr(nnn)),
+            Call(nnn) => format!(":call {}", addr(nnn)),
+            SeByte { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+            SneByte { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+            Se { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            LdByte { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+            AddByte { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+            Ld { x, y } => format!("v{:x} := v{:x}", x, y),
+            Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Add { x, y } => format!("v{:x} += v{:x}", x, y),
+            Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            Sne { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            LdI(nnn) => format!("i := {}", addr(nnn)),
+            JpV0(nnn) => format!("jump0 {}", addr(nnn)),
+            Rnd { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+            Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Skp(x) => format!("if v{:x} -key then", x),
+            Sknp(x) => format!("if v{:x} key then", x),
+            LdILong => format!("i := long {}", addr(long)),
+            Plane(n) => format!("plane {}", n),
+            Audio => "audio".to_string(),
+            LdVxDt(x) => format!("v{:x} := delay", x),
+            LdVxK(x) => format!("v{:x} := key", x),
+            LdDtVx(x) => format!("delay := v{:x}", x),
+            LdStVx(x) => format!("buzzer := v{:x}", x),
+            AddI(x) => format!("i += v{:x}", x),
+            LdF(x) => format!("i := hex v{:x}", x),
+            LdHf(x) => format!("i := bighex v{:x}", x),
+            LdB(x) => format!("bcd v{:x}", x),
+            Pitch(x) => format!("pitch := v{:x}", x),
+            LdIVx(x) => format!("save v{:x}", x),
+            LdVxI(x) => format!("load v{:x}", x),
+            LdRVx(x) => format!("saveflags v{:x}", x),
+            LdVxR(x) => format!("loadflags v{:x}", x),
+        },
+    }
+}
+
+// The result of following the control flow of a rom.
+struct Analysis {
+    // start address and length of every instruction reached
+    code: BTreeMap<Word, Word>,
+    // names of the addresses that are jumped to, called or loaded into I
+    labels: BTreeMap<Word, String>,
+}
+
+// Follow every path from the start of the program. Bytes that are never reached
+// as an instruction are data, like sprites.
+fn analyze(rom: &[Byte], platform: Platform) -> Analysis {
+    use Instruction::*;
+    let end = PROGRAM_START as usize + rom.len();
+    let word = |address: Word| -> Option<Word> {
+        let offset = (address as usize).checked_sub(PROGRAM_START as usize)?;
+        Some((*rom.get(offset)? as Word) << 8 | *rom.get(offset + 1)? as Word)
+    };
+    let in_rom = |address: Word| (PROGRAM_START as usize..end).contains(&(address as usize));
+    let length = |address: Word| match word(address).and_then(|w| decode(w, platform).ok()) {
+        Some(LdILong) => 4,
+        _ => 2,
+    };
+
+    let mut code = BTreeMap::new();
+    // addresses referenced by each kind of instruction, named after the analysis
+    let mut calls = BTreeSet::new();
+    let mut jumps = BTreeSet::new();
+    let mut tables = BTreeSet::new();
+    let mut data = BTreeSet::new();
+    let mut pending = vec![PROGRAM_START];
+
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) || !in_rom(address) {
+            continue;
+        }
+        let Some(instruction) = word(address).and_then(|w| decode(w, platform).ok()) else {
+            continue;
+        };
+        if instruction == LdILong && word(address + 2).is_none() {
+            continue;
+        }
+        let len = length(address);
+        code.insert(address, len);
+        let next = address.wrapping_add(len);
+
+        match instruction {
+            Jp(nnn) | Sys(nnn) => {
+                jumps.insert(nnn);
+                pending.push(nnn);
+            }
+            Call(nnn) => {
+                calls.insert(nnn);
+                pending.push(nnn);
+                pending.push(next);
+            }
+            // the target depends on V0, it can't be followed
+            JpV0(nnn) => {
+                tables.insert(nnn);
+            }
+            Ret | Exit => (),
+            LdI(nnn) => {
+                data.insert(nnn);
+                pending.push(next);
+            }
+            LdILong => {
+                data.extend(word(address + 2));
+                pending.push(next);
+            }
+            _ if instruction.is_skip() => {
+                pending.push(next);
+                pending.push(next.wrapping_add(length(next)));
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    // the first name given to an address wins
+    let mut labels = BTreeMap::new();
+    labels.insert(PROGRAM_START, "main".to_string());
+    for (addresses, prefix) in [
+        (calls, "sub"),
+        (jumps, "label"),
+        (tables, "table"),
+        (data, "data"),
+    ] {
+        for address in addresses.into_iter().filter(|&a| in_rom(a)) {
+            labels
+                .entry(address)
+                .or_insert_with(|| format!("{}_{:03X}", prefix, address));
+        }
+    }
+    Analysis { code, labels }
+}
+
+// Disassemble a rom loaded at 0x200 into a listing with addresses, raw bytes and labels.
+pub fn disassemble(rom: &[Byte], platform: Platform, syntax: Syntax) -> String {
+    let Analysis { code, mut labels } = analyze(rom, platform);
+    let start = PROGRAM_START as usize;
+    let byte = |address: usize| rom[address - start];
+
+    // split the rom into lines: one per instruction, data in rows of up to 8 bytes
+    // that also end at labels and code
+    let mut lines = Vec::new();
+    let mut address = start;
+    while address < start + rom.len() {
+        if let Some(&len) = code.get(&(address as Word)) {
+            lines.push((address, len as usize, true));
+            address += len as usize;
+            continue;
+        }
+        let mut len = 1;
+        while len < 8
+            && address + len < start + rom.len()
+            && !code.contains_key(&((address + len) as Word))
+            && !labels.contains_key(&((address + len) as Word))
+        {
+            len += 1;
+        }
+        lines.push((address, len, false));
+        address += len;
+    }
+    // labels in the middle of an instruction can't be written down
+    let starts: BTreeSet<usize> = lines.iter().map(|&(address, _, _)| address).collect();
+    labels.retain(|&address, _| starts.contains(&(address as usize)));
+
+    let mut listing = String::new();
+    for (address, len, is_code) in lines {
+        if let Some(label) = labels.get(&(address as Word)) {
+            match syntax {
+                Syntax::Cowgod => writeln!(listing, "{}:", label),
+                Syntax::Octo => writeln!(listing, ": {}", label),
+            }
+            .unwrap();
+        }
+        let bytes: Vec<Byte> = (address..address + len).map(byte).collect();
+        let raw: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let text = if is_code {
+            let opcode = (bytes[0] as Word) << 8 | bytes[1] as Word;
+            let long = if len == 4 {
+                (bytes[2] as Word) << 8 | bytes[3] as Word
+            } else {
+                0
+            };
+            let instruction = decode(opcode, platform).expect("analyzed as code");
+            mnemonic(instruction, syntax, &labels, long)
+        } else {
+            let values: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+            match syntax {
+                Syntax::Cowgod => format!("DB {}", values.join(", ")),
+                Syntax::Octo => values.join(" "),
+            }
+        };
+        let comment = match syntax {
+            Syntax::Cowgod => ';',
+            Syntax::Octo => '#',
+        };
+        writeln!(
+            listing,
+            "    {:<24} {} 0x{:03X}  {}",
+            text, comment, address, raw
+        )
+        .unwrap();
+    }
+    listing
+}
+
+#[cfg(test)]
+mod test {
+    use super::{analyze, disassemble, Syntax};
+    use crate::quirks::Platform;
+
+    #[test]
+    fn test_disassemble() {
+        let rom = [
+            0xA2, 0x0A, // LD I, sprite
+            0x22, 0x06, // CALL draw
+            0x12, 0x04, // JP self
+            0xD0, 0x01, // draw: DRW V0, V0, 1
+            0x00, 0xEE, // RET
+            0x00, 0xEE, 0xFF, 0x81, // sprite, never reached so not decoded as RET
+        ];
+        let listing = disassemble(&rom, Platform::CosmacVip, Syntax::Cowgod);
+        let lines: Vec<&str> = listing.lines().map(|l| l.trim()).collect();
+        assert_eq!(lines[0], "main:");
+        assert!(lines[1].starts_with("LD I, data_20A"));
+        assert!(lines[2].starts_with("CALL sub_206"));
+        assert_eq!(lines[3], "label_204:");
+        assert!(lines[4].starts_with("JP label_204"));
+        assert_eq!(lines[5], "sub_206:");
+        assert!(lines[6].starts_with("DRW V0, V0, 1"));
+        assert!(lines[7].starts_with("RET"));
+        assert_eq!(lines[8], "data_20A:");
+        assert!(lines[9].starts_with("DB 0x00, 0xEE, 0xFF, 0x81"));
+        assert!(lines[9].ends_with("; 0x20A  00EEFF81"));
+
+        let listing = disassemble(&rom, Platform::CosmacVip, Syntax::Octo);
+        assert!(listing.contains(": sub_206\n"));
+        assert!(listing.contains("sprite v0 v0 1"));
+    }
+
+    #[test]
+    fn test_analyze() {
+        let code = |rom: &[u8], platform| {
+            let analysis = analyze(rom, platform);
+            analysis.code.into_iter().collect::<Vec<_>>()
+        };
+
+        // a skip jumps over all 4 bytes of F000 NNNN
+        let rom = [
+            0x30, 0x00, // SE V0, 0
+            0xF0, 0x00, 0x03, 0x00, // LD I, LONG 0x300
+            0x00, 0xE0, // CLS
+            0x00, 0xFD, // EXIT
+        ];
+        assert_eq!(
+            code(&rom, Platform::XoChip),
+            [(0x200, 2), (0x202, 4), (0x206, 2), (0x208, 2)]
+        );
+
+        // the target of JP V0 depends on V0, so the table is only labelled
+        let rom = [
+            0xB2, 0x02, // JP V0, table
+            0x12, 0x06, // table: JP 0x206
+            0x12, 0x06, // JP 0x206
+            0x00, 0xEE, // RET
+        ];
+        let analysis = analyze(&rom, Platform::CosmacVip);
+        assert_eq!(analysis.code.keys().copied().collect::<Vec<_>>(), [0x200]);
+        assert_eq!(analysis.labels[&0x202], "table_202");
+        assert!(!analysis.labels.contains_key(&0x206));
+
+        // nothing falls through an unconditional jump
+        let rom = [
+            0x12, 0x04, // JP 0x204
+            0xFF, 0xFF, // data
+            0x12, 0x04, // JP 0x204
+        ];
+        assert_eq!(code(&rom, Platform::CosmacVip), [(0x200, 2), (0x204, 2)]);
+        let listing = disassemble(&rom, Platform::CosmacVip, Syntax::Cowgod);
+        assert!(listing.contains("DB 0xFF, 0xFF"));
+    }
+}
@@ -1,4 +1,6 @@
+#[cfg(feature = "sdl")]
 use sdl2::video::WindowBuildError;
+#[cfg(feature = "sdl")]
 use sdl2::IntegerOrSdlError;
 use thiserror::Error;
 
@@ -6,12 +8,54 @@ use thiserror::Error;
 pub enum Error {
     #[error("Can't not read rom")]
     RomErr(#[from] std::io::Error),
-    #[error("Opcode {0} error")]
-    Opcode(String),
+    #[error("Rom is too large: {0} bytes")]
+    RomSize(usize),
+    #[error("Config error: {0}")]
+    Config(String),
+    #[error("Opcode {opcode:04X} faulted at {pc:#05X}, I = {i:#05X}, V = {registers:02X?}")]
+    Opcode {
+        opcode: u16,
+        pc: u16,
+        i: u16,
+        registers: [u8; 16],
+    },
+    #[error("Opcode {0:04X} is not an instruction of the platform")]
+    Decode(u16),
+    #[error("Address {address:#06X} out of memory, opcode {opcode:04X} at {pc:#05X}")]
+    Address {
+        address: usize,
+        opcode: u16,
+        pc: u16,
+    },
+    #[error("Stack overflow, opcode {opcode:04X} at {pc:#05X}")]
+    StackOverflow { opcode: u16, pc: u16 },
+    #[error("Stack underflow, opcode {opcode:04X} at {pc:#05X}")]
+    StackUnderflow { opcode: u16, pc: u16 },
+    #[error("{file}:{line}: {message}")]
+    Asm {
+        file: String,
+        line: usize,
+        message: String,
+    },
+    #[error("{file}:{line}:{column}: {message}")]
+    Compile {
+        file: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Debugger error: {0}")]
+    Debugger(String),
+    #[error("Trace error: {0}")]
+    Trace(String),
+    #[error("Save state error: {0}")]
+    State(String),
     #[error("SDLERROR: {0}")]
     SdlError(String),
+    #[cfg(feature = "sdl")]
     #[error("Windows Builder Error")]
     WindowBuildError(#[from] WindowBuildError),
+    #[cfg(feature = "sdl")]
     #[error("Canvas Builder Error")]
     CavansBuilderError(#[from] IntegerOrSdlError),
 }
@@ -0,0 +1,741 @@
+use super::chip8::{Access, Byte, Chip8, Location, Word};
+use super::error::Error;
+use super::Result;
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+// The register file as gdb sees it, in the order of the `g` packet:
+// V0-VF, I, PC, SP, DT, ST. Multi-byte registers are little-endian.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 8),
+    ("v1", 8),
+    ("v2", 8),
+    ("v3", 8),
+    ("v4", 8),
+    ("v5", 8),
+    ("v6", 8),
+    ("v7", 8),
+    ("v8", 8),
+    ("v9", 8),
+    ("va", 8),
+    ("vb", 8),
+    ("vc", 8),
+    ("vd", 8),
+    ("ve", 8),
+    ("vf", 8),
+    ("i", 16),
+    ("pc", 16),
+    ("sp", 8),
+    ("dt", 8),
+    ("st", 8),
+];
+const PACKET_SIZE: usize = 0x1000;
+
+// Signals of stop replies.
+const SIGINT: Byte = 2;
+const SIGILL: Byte = 4;
+const SIGTRAP: Byte = 5;
+const SIGSEGV: Byte = 11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Run {
+    Stopped,
+    Continue,
+    Step,
+}
+
+// The kinds of gdb watchpoints, Z2-Z4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+struct Client {
+    stream: TcpStream,
+    // received bytes that don't make up a whole packet yet
+    input: Vec<u8>,
+    // after QStartNoAckMode, packets are not acknowledged with +
+    no_ack: bool,
+}
+
+// A stub of the GDB remote serial protocol, serving one debugger at a time over TCP.
+// The machine is stopped while a debugger attaches and runs only when it says so,
+// so breakpoints, watchpoints and stepping work on the state `execute()` mutates.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<Client>,
+    breakpoints: BTreeSet<Word>,
+    // memory watchpoints: kind, first address and length
+    watchpoints: Vec<(Watch, usize, usize)>,
+    run: Run,
+    // nothing stops the first instruction after resuming
+    resumed: bool,
+    // the debugger killed the program
+    quit: bool,
+}
+
+impl GdbStub {
+    // Listen on `address`. The machine waits for a debugger to attach before it runs.
+    pub fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(address).map_err(|e| Error::Debugger(e.to_string()))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| Error::Debugger(e.to_string()))?;
+        Ok(GdbStub {
+            listener,
+            client: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            run: Run::Stopped,
+            resumed: false,
+            quit: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    // whether the debugger holds the machine
+    pub fn stopped(&self) -> bool {
+        self.run == Run::Stopped
+    }
+
+    // whether the debugger ended the emulator with `kill`
+    pub fn quitting(&self) -> bool {
+        self.quit
+    }
+
+    // Serve the debugger, then run one frame unless the machine is stopped.
+    // Returns whether the machine ran.
+    pub fn run_frame(&mut self, chip8: &mut Chip8, ipf: u32) -> bool {
+        self.accept(chip8);
+        self.serve(chip8);
+        if self.stopped() {
+            return false;
+        }
+
+        let GdbStub {
+            breakpoints,
+            watchpoints,
+            run,
+            resumed,
+            ..
+        } = self;
+        let mut stop = None;
+        let result = chip8.run_frame_until(ipf, |chip8| {
+            if !*resumed {
+                for &access in chip8.accesses() {
+                    if let Some(reply) = watch_hit(watchpoints, access) {
+                        stop = Some(reply);
+                        return true;
+                    }
+                }
+                if breakpoints.contains(&chip8.pc()) {
+                    stop = Some(format!("T{:02x}swbreak:;", SIGTRAP));
+                    return true;
+                }
+                if *run == Run::Step {
+                    stop = Some(format!("S{:02x}", SIGTRAP));
+                    return true;
+                }
+            }
+            *resumed = false;
+            false
+        });
+        let stop = match result {
+            Err(Error::Opcode { .. }) => Some(format!("S{:02x}", SIGILL)),
+            Err(_) => Some(format!("S{:02x}", SIGSEGV)),
+            Ok(_) if chip8.halted() => Some("W00".to_string()),
+            Ok(_) => stop,
+        };
+        if let Some(reply) = stop {
+            self.run = Run::Stopped;
+            self.send(&reply);
+        }
+        true
+    }
+
+    fn accept(&mut self, chip8: &mut Chip8) {
+        if self.client.is_some() {
+            return;
+        }
+        if let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.client = Some(Client {
+                    stream,
+                    input: Vec::new(),
+                    no_ack: false,
+                });
+                // a debugger attaches to a stopped target
+                self.run = Run::Stopped;
+                chip8.set_watching(!self.watchpoints.is_empty());
+            }
+        }
+    }
+
+    // Let the machine run on its own once the debugger is gone.
+    fn detach(&mut self, chip8: &mut Chip8) {
+        self.client = None;
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        chip8.set_watching(false);
+        self.run = Run::Continue;
+        self.resumed = true;
+    }
+
+    // Handle everything the debugger sent since the last frame.
+    fn serve(&mut self, chip8: &mut Chip8) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let mut buffer = [0; 1024];
+        loop {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => return self.detach(chip8),
+                Ok(len) => client.input.extend_from_slice(&buffer[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return self.detach(chip8),
+            }
+        }
+
+        while let Some(client) = self.client.as_mut() {
+            let Some(&first) = client.input.first() else {
+                break;
+            };
+            match first {
+                b'$' => {
+                    let Some(end) = client.input.iter().position(|&b| b == b'#') else {
+                        break;
+                    };
+                    if client.input.len() < end + 3 {
+                        break;
+                    }
+                    let packet: Vec<u8> = client.input.drain(..end + 3).collect();
+                    let data = unescape(&packet[1..end]);
+                    let checksum = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    if !client.no_ack {
+                        // a damaged packet is sent again by the debugger
+                        let valid = checksum == Some(sum(&packet[1..end]));
+                        let _ = write_all(&mut client.stream, if valid { b"+" } else { b"-" });
+                        if !valid {
+                            continue;
+                        }
+                    }
+                    let data = String::from_utf8_lossy(&data).into_owned();
+                    if let Some(reply) = self.packet(chip8, &data) {
+                        self.send(&reply);
+                    }
+                }
+                // Ctrl-C in the debugger
+                0x03 => {
+                    client.input.remove(0);
+                    if !self.stopped() {
+                        self.run = Run::Stopped;
+                        self.send(&format!("S{:02x}", SIGINT));
+                    }
+                }
+                // acknowledgements, and anything else outside of a packet
+                _ => {
+                    client.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let data = escape(reply.as_bytes());
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", sum(&data)).as_bytes());
+        if write_all(&mut client.stream, &packet).is_err() {
+            self.client = None;
+        }
+    }
+
+    // Handle one packet, returns the reply unless it comes later, when the machine stops.
+    fn packet(&mut self, chip8: &mut Chip8, data: &str) -> Option<String> {
+        let error = || Some("E01".to_string());
+        let (command, args) = data.split_at(data.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => REGISTERS
+                .iter()
+                .enumerate()
+                .map(|(n, _)| hex(&read_register(chip8, n)))
+                .collect(),
+            "G" => {
+                let Some(bytes) = unhex(args) else {
+                    return error();
+                };
+                let mut offset = 0;
+                for (n, (_, bits)) in REGISTERS.iter().enumerate() {
+                    let len = bits / 8;
+                    if let Some(value) = bytes.get(offset..offset + len) {
+                        write_register(chip8, n, value);
+                    }
+                    offset += len;
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTERS.len() => hex(&read_register(chip8, n)),
+                _ => return error(),
+            },
+            "P" => {
+                let Some((n, value)) = args.split_once('=') else {
+                    return error();
+                };
+                match (usize::from_str_radix(n, 16), unhex(value)) {
+                    (Ok(n), Some(value))
+                        if n < REGISTERS.len() && value.len() == REGISTERS[n].1 / 8 =>
+                    {
+                        write_register(chip8, n, &value);
+                        "OK".to_string()
+                    }
+                    _ => return error(),
+                }
+            }
+            "m" => {
+                let Some((address, len)) = range(args) else {
+                    return error();
+                };
+                match chip8
+                    .memory()
+                    .get(address..address.saturating_add(len.min(PACKET_SIZE / 2)))
+                {
+                    Some(bytes) => hex(bytes),
+                    None => return error(),
+                }
+            }
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range_text, data)| Some((range(range_text)?, unhex(data)?)));
+                let Some(((address, len), bytes)) = parsed else {
+                    return error();
+                };
+                match chip8
+                    .memory_mut()
+                    .get_mut(address..address.saturating_add(len))
+                {
+                    Some(memory) if bytes.len() == len => {
+                        memory.copy_from_slice(&bytes);
+                        "OK".to_string()
+                    }
+                    _ => return error(),
+                }
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let address = fields
+                    .next()
+                    .and_then(|a| usize::from_str_radix(a, 16).ok());
+                let len = fields
+                    .next()
+                    .and_then(|l| usize::from_str_radix(l, 16).ok());
+                let (Some(kind), Some(address), Some(len)) = (kind, address, len) else {
+                    return error();
+                };
+                let watch = match kind {
+                    "0" | "1" => None,
+                    "2" => Some(Watch::Write),
+                    "3" => Some(Watch::Read),
+                    "4" => Some(Watch::Access),
+                    // not supported
+                    _ => return Some(String::new()),
+                };
+                if address >= chip8.memory().len() {
+                    return error();
+                }
+                match (watch, command) {
+                    (None, "Z") => {
+                        self.breakpoints.insert(address as Word);
+                    }
+                    (None, _) => {
+                        self.breakpoints.remove(&(address as Word));
+                    }
+                    (Some(watch), "Z") => self.watchpoints.push((watch, address, len.max(1))),
+                    (Some(watch), _) => {
+                        let target = (watch, address, len.max(1));
+                        self.watchpoints.retain(|&w| w != target);
+                    }
+                }
+                chip8.set_watching(!self.watchpoints.is_empty());
+                "OK".to_string()
+            }
+            "c" | "s" => {
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    chip8.set_pc(address);
+                }
+                self.run = if command == "c" {
+                    Run::Continue
+                } else {
+                    Run::Step
+                };
+                self.resumed = true;
+                return None;
+            }
+            "D" => {
+                self.send("OK");
+                self.detach(chip8);
+                return None;
+            }
+            "k" => {
+                self.detach(chip8);
+                self.run = Run::Stopped;
+                self.quit = true;
+                return None;
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(data)?,
+            // vCont and everything else not supported, gdb falls back to the basics
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, data: &str) -> Option<String> {
+        let reply = if data.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+;swbreak+",
+                PACKET_SIZE
+            )
+        } else if data == "QStartNoAckMode" {
+            self.send("OK");
+            if let Some(client) = self.client.as_mut() {
+                client.no_ack = true;
+            }
+            return None;
+        } else if let Some(request) = data.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = range(request)?;
+            let xml = target_xml();
+            let end = offset.saturating_add(len).min(xml.len());
+            let chunk = xml.get(offset.min(end)..end)?;
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            format!("{}{}", more, chunk)
+        } else {
+            match data {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            }
+        };
+        Some(reply)
+    }
+}
+
+// The stop reply for a watchpoint hit by `access`, if any.
+fn watch_hit(watchpoints: &[(Watch, usize, usize)], access: Access) -> Option<String> {
+    let (address, is_write) = match access {
+        Access::Read(Location::Memory(address)) => (address, false),
+        Access::Write(Location::Memory(address), _) => (address, true),
+        _ => return None,
+    };
+    let (watch, _, _) = watchpoints.iter().find(|&&(watch, start, len)| {
+        (start..start.saturating_add(len)).contains(&address)
+            && match watch {
+                Watch::Write => is_write,
+                Watch::Read => !is_write,
+                Watch::Access => true,
+            }
+    })?;
+    let name = match watch {
+        Watch::Write => "watch",
+        Watch::Read => "rwatch",
+        Watch::Access => "awatch",
+    };
+    Some(format!("T{:02x}{}:{:x};", SIGTRAP, name, address))
+}
+
+fn read_register(chip8: &Chip8, n: usize) -> Vec<Byte> {
+    match n {
+        0..=15 => vec![chip8.register(n)],
+        16 => chip8.index().to_le_bytes().to_vec(),
+        17 => chip8.pc().to_le_bytes().to_vec(),
+        18 => vec![chip8.stack().len() as Byte],
+        19 => vec![chip8.delay_timer()],
+        _ => vec![chip8.sound_timer()],
+    }
+}
+
+fn write_register(chip8: &mut Chip8, n: usize, value: &[Byte]) {
+    let word = || Word::from_le_bytes([value[0], value[1]]);
+    match n {
+        0..=15 => chip8.set_register(n, value[0]),
+        16 => chip8.set_index(word()),
+        17 => chip8.set_pc(word()),
+        18 => chip8.set_stack_depth(value[0] as usize),
+        19 => chip8.set_delay_timer(value[0]),
+        _ => chip8.set_sound_timer(value[0]),
+    }
+}
+
+// Describes the registers, so gdb knows their names and sizes.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.core\">",
+    );
+    for (n, (name, bits)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ => "int",
+        };
+        write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\"/>",
+            name, bits, n, kind
+        )
+        .unwrap();
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+// `ADDR,LEN` in hex
+fn range(text: &str) -> Option<(usize, usize)> {
+    let (address, len) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[Byte]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<Byte>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// `}` escapes the following byte, xor 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            out.push(bytes.next().map_or(0, |b| b ^ 0x20));
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if matches!(b, b'#' | b'$' | b'}' | b'*') {
+            out.extend_from_slice(&[b'}', b ^ 0x20]);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+// The socket is non-blocking, wait for room to write.
+pub(crate) fn write_all(stream: &mut TcpStream, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(len) => data = &data[len..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(std::time::Duration::from_millis(1))
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sum, GdbStub};
+    use crate::chip8::Chip8;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    // 0x200: V0 = 5, I = 0x300, BCD V0, V0 += 1, jump 0x204
+    const ROM: [u8; 10] = [0x60, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0x70, 0x01, 0x12, 0x04];
+
+    struct Session {
+        stub: GdbStub,
+        chip8: Chip8,
+        stream: TcpStream,
+        received: Vec<u8>,
+    }
+
+    impl Session {
+        fn new() -> Self {
+            let stub = GdbStub::bind("127.0.0.1:0").unwrap();
+            let mut chip8 = Chip8::new();
+            chip8.load_bytes(&ROM).unwrap();
+            let stream = TcpStream::connect(stub.local_addr().unwrap()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            Session {
+                stub,
+                chip8,
+                stream,
+                received: Vec::new(),
+            }
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+        }
+
+        // Run frames until a whole reply arrived and return its data.
+        fn reply(&mut self) -> String {
+            for _ in 0..500 {
+                self.stub.run_frame(&mut self.chip8, 10);
+                let mut buffer = [0; 4096];
+                if let Ok(len) = self.stream.read(&mut buffer) {
+                    self.received.extend_from_slice(&buffer[..len]);
+                }
+                while self.received.first() == Some(&b'+') {
+                    self.received.remove(0);
+                }
+                if let Some(end) = self.received.iter().position(|&b| b == b'#') {
+                    if self.received.len() >= end + 3 {
+                        let packet: Vec<u8> = self.received.drain(..end + 3).collect();
+                        assert_eq!(packet[0], b'$');
+                        let checksum = std::str::from_utf8(&packet[end + 1..]).unwrap();
+                        assert_eq!(
+                            u8::from_str_radix(checksum, 16).unwrap(),
+                            sum(&packet[1..end])
+                        );
+                        return String::from_utf8(packet[1..end].to_vec()).unwrap();
+                    }
+                }
+            }
+            panic!("no reply");
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut session = Session::new();
+        assert!(session
+            .request("qSupported:multiprocess+;swbreak+")
+            .contains("qXfer:features:read+"));
+        assert_eq!(session.request("?"), "S05");
+        assert!(session.stub.stopped());
+
+        let registers = session.request("g");
+        // V0-VF, then I and PC little-endian, then SP, DT and ST
+        assert_eq!(registers, format!("{}00000002000000", "00".repeat(16)));
+        assert_eq!(session.request("P3=2a"), "OK");
+        assert_eq!(session.request("P10=0403"), "OK");
+        assert_eq!(session.request("p3"), "2a");
+        assert_eq!(session.chip8.index(), 0x304);
+        assert_eq!(session.request("P11=0402"), "OK");
+        assert_eq!(session.chip8.pc(), 0x204);
+        assert_eq!(session.request("P11=04"), "E01");
+
+        assert_eq!(session.request("m200,4"), "6005a300");
+        assert_eq!(session.request("M300,2:beef"), "OK");
+        assert_eq!(&session.chip8.memory()[0x300..0x302], &[0xBE, 0xEF]);
+        assert_eq!(session.request("m10000,1"), "E01");
+
+        let xml = session.request("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" regnum=\"17\""));
+        assert_eq!(session.request("vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn test_run_control() {
+        let mut session = Session::new();
+        assert_eq!(session.request("QStartNoAckMode"), "OK");
+        assert_eq!(session.request("s"), "S05");
+        assert_eq!(session.chip8.pc(), 0x202);
+
+        assert_eq!(session.request("Z0,206,2"), "OK");
+        assert_eq!(session.request("c"), "T05swbreak:;");
+        assert_eq!(session.chip8.pc(), 0x206);
+        assert_eq!(&session.chip8.memory()[0x300..0x303], &[0, 0, 5]);
+        assert_eq!(session.request("z0,206,2"), "OK");
+
+        // stops after BCD writes the units of V0 = 6
+        assert_eq!(session.request("Z2,302,1"), "OK");
+        assert_eq!(session.request("c"), "T05watch:302;");
+        assert_eq!(session.chip8.pc(), 0x206);
+        assert_eq!(session.chip8.memory()[0x302], 6);
+        assert_eq!(session.request("z2,302,1"), "OK");
+
+        // Ctrl-C stops the running machine
+        session.send("c");
+        session.stub.run_frame(&mut session.chip8, 10);
+        assert!(!session.stub.stopped());
+        session.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(session.reply(), "S02");
+
+        // detaching lets the machine run on its own
+        assert_eq!(session.request("D"), "OK");
+        assert!(!session.stub.stopped());
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut session = Session::new();
+        assert_eq!(session.request("QStartNoAckMode"), "OK");
+        session.send("c");
+        for _ in 0..500 {
+            if session.stub.run_frame(&mut session.chip8, 10) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!session.stub.stopped());
+        // kill ends the emulator instead of letting the machine run on
+        session.send("k");
+        for _ in 0..500 {
+            if session.stub.quitting() {
+                break;
+            }
+            session.stub.run_frame(&mut session.chip8, 10);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(session.stub.quitting());
+        let pc = session.chip8.pc();
+        assert!(!session.stub.run_frame(&mut session.chip8, 10));
+        assert_eq!(session.chip8.pc(), pc);
+    }
+}
@@ -0,0 +1,325 @@
+use super::chip8::{Byte, Chip8, PALETTE};
+use super::error::Error;
+use super::state;
+use super::Result;
+
+// How long to run without a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    // 60Hz frames, each `ipf` instructions and one timer tick
+    Frames(u64),
+    // instructions
+    Cycles(u64),
+}
+
+// A key going down or up at the start of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Input {
+    pub frame: u64,
+    pub key: Byte,
+    pub down: bool,
+}
+
+// Parse an input timeline, one event per line:
+//
+//     # frame  action  key  [frames held]
+//     60       press   5
+//     120      down    A
+//     180      up      A
+//     200      press   F    10
+//
+// `press` holds the key for a few frames, 3 by default, long enough for FX0A to see it.
+pub fn parse_input(text: &str) -> Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let bad = |what: &str| Error::Config(format!("input line {}: {}", n + 1, what));
+        let frame: u64 = words[0].parse().map_err(|_| bad("bad frame number"))?;
+        let key = words
+            .get(2)
+            .and_then(|key| Byte::from_str_radix(key, 16).ok())
+            .filter(|&key| key < 16)
+            .ok_or_else(|| bad("the key has to be 0-F"))?;
+        match (words[1].to_ascii_lowercase().as_str(), words.get(3)) {
+            ("down", None) => inputs.push(Input {
+                frame,
+                key,
+                down: true,
+            }),
+            ("up", None) => inputs.push(Input {
+                frame,
+                key,
+                down: false,
+            }),
+            ("press", held) => {
+                let held: u64 = match held {
+                    Some(held) => held.parse().map_err(|_| bad("bad number of frames"))?,
+                    None => 3,
+                };
+                let release = frame
+                    .checked_add(held.max(1))
+                    .ok_or_else(|| bad("the key is held past the last frame"))?;
+                inputs.push(Input {
+                    frame,
+                    key,
+                    down: true,
+                });
+                inputs.push(Input {
+                    frame: release,
+                    key,
+                    down: false,
+                });
+            }
+            _ => {
+                return Err(bad(
+                    "expected `FRAME down|up KEY` or `FRAME press KEY [FRAMES]`",
+                ))
+            }
+        }
+    }
+    // stable, so events of the same frame keep their order
+    inputs.sort_by_key(|input| input.frame);
+    Ok(inputs)
+}
+
+// Runs a machine without a window, audio or real time, e.g. for tests and CI.
+pub struct Headless {
+    chip8: Chip8,
+    ipf: u32,
+    // sorted by frame, `next` is the first one not applied yet
+    inputs: Vec<Input>,
+    next: usize,
+    frame: u64,
+    cycles: u64,
+}
+
+impl Headless {
+    pub fn new(chip8: Chip8, ipf: u32) -> Self {
+        Headless {
+            chip8,
+            ipf,
+            inputs: Vec::new(),
+            next: 0,
+            frame: 0,
+            cycles: 0,
+        }
+    }
+
+    // Play `inputs` from parse_input, counted from the first frame.
+    pub fn set_input(&mut self, inputs: Vec<Input>) {
+        self.inputs = inputs;
+        self.next = 0;
+    }
+
+    // Run until the limit is reached or the program exits. A fault ends the run
+    // with the machine left at the faulting instruction.
+    pub fn run(&mut self, limit: Limit) -> Result<()> {
+        loop {
+            let done = match limit {
+                Limit::Frames(frames) => self.frame >= frames,
+                Limit::Cycles(cycles) => self.cycles >= cycles,
+            };
+            if done || self.chip8.halted() {
+                return Ok(());
+            }
+
+            while let Some(input) = self.inputs.get(self.next) {
+                if input.frame > self.frame {
+                    break;
+                }
+                if input.down {
+                    self.chip8.key_down(input.key);
+                } else {
+                    self.chip8.key_up(input.key);
+                }
+                self.next += 1;
+            }
+
+            let mut cycles = self.cycles;
+            let stopped = self.chip8.run_frame_until(self.ipf, |chip8| {
+                let stop = chip8.halted() || Limit::Cycles(cycles) == limit;
+                cycles += 1;
+                stop
+            });
+            // the stop closure is also asked before the instruction it stops at
+            self.cycles = if stopped.as_ref().is_ok_and(|&stopped| stopped) {
+                cycles - 1
+            } else {
+                cycles
+            };
+            if !stopped? {
+                self.frame += 1;
+            }
+        }
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn chip8_mut(&mut self) -> &mut Chip8 {
+        &mut self.chip8
+    }
+
+    pub fn into_chip8(self) -> Chip8 {
+        self.chip8
+    }
+
+    // frames run so far, a frame cut short by a cycle limit doesn't count
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+// The display as text, one character per pixel: `.` is off, `#` the first plane,
+// `o` the second XO-CHIP plane and `@` both.
+pub fn ascii(chip8: &Chip8) -> String {
+    let mut text = String::with_capacity((chip8.width() + 1) * chip8.height());
+    for y in 0..chip8.height() {
+        for x in 0..chip8.width() {
+            text.push(['.', '#', 'o', '@'][chip8.pixel(x, y) as usize & 0x3]);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// The display as a PNG image in the colours of the window, every pixel `scale` times as big.
+pub fn png(chip8: &Chip8, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let (width, height) = (chip8.width() * scale, chip8.height() * scale);
+    let mut pixels = Vec::with_capacity((width * 3 + 1) * height);
+    for y in 0..height {
+        // filter type of the row: none
+        pixels.push(0);
+        for x in 0..width {
+            pixels.extend_from_slice(&PALETTE[chip8.pixel(x / scale, y / scale) as usize & 0x3]);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bit RGB, deflate, no filters, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut data, b"IHDR", &header);
+    png_chunk(&mut data, b"IDAT", &zlib_stored(&pixels));
+    png_chunk(&mut data, b"IEND", &[]);
+    data
+}
+
+fn png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+    let crc = state::crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream of uncompressed deflate blocks. The images are small,
+// so compressing them isn't worth an encoder.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ascii, parse_input, png, Headless, Input, Limit};
+    use crate::chip8::Chip8;
+    use crate::state;
+
+    // draw the digit in V0, wait for a key, put it in V0, clear and loop
+    const ROM: [u8; 12] = [
+        0xF0, 0x29, 0xD1, 0x15, 0xF0, 0x0A, 0x00, 0xE0, 0x12, 0x00, 0x00, 0x00,
+    ];
+
+    fn machine() -> Headless {
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&ROM).unwrap();
+        Headless::new(chip8, 10)
+    }
+
+    #[test]
+    fn test_run() {
+        let mut headless = machine();
+        headless.run(Limit::Cycles(2)).unwrap();
+        assert_eq!(headless.cycles(), 2);
+        // on the VIP drawing waits for the end of the frame
+        assert_eq!(headless.frame(), 1);
+        assert_eq!(headless.chip8().pc(), 0x204);
+        let text = ascii(headless.chip8());
+        assert_eq!(text.lines().count(), 32);
+        assert!(text.starts_with("####...."));
+        assert!(text.lines().nth(1).unwrap().starts_with("#..#...."));
+
+        // waiting for a key, frames go on
+        headless.run(Limit::Frames(5)).unwrap();
+        assert_eq!(headless.frame(), 5);
+        assert_eq!(headless.chip8().pc(), 0x204);
+
+        headless.set_input(parse_input("6 press 7 2").unwrap());
+        headless.run(Limit::Frames(20)).unwrap();
+        assert_eq!(headless.chip8().register(0), 7);
+        let hash = headless.chip8().display_hash();
+        assert_ne!(hash, Chip8::new().display_hash());
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let inputs = parse_input("# comment\n10 down a\n\n5 press 1 # tap\n30 up A\n").unwrap();
+        let input = |frame, key, down| Input { frame, key, down };
+        assert_eq!(
+            inputs,
+            [
+                input(5, 1, true),
+                input(8, 1, false),
+                input(10, 0xA, true),
+                input(30, 0xA, false)
+            ]
+        );
+        assert!(parse_input("5 press G").is_err());
+        assert!(parse_input("5 hold 1").is_err());
+        assert!(parse_input("x down 1").is_err());
+        assert!(parse_input("18446744073709551615 press 1").is_err());
+    }
+
+    #[test]
+    fn test_png() {
+        let headless = machine();
+        let data = png(headless.chip8(), 2);
+        assert_eq!(&data[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&data[12..16], b"IHDR");
+        assert_eq!(&data[16..24], &[0, 0, 0, 128, 0, 0, 0, 64]);
+        assert_eq!(&data[29..33], &state::crc32(&data[12..29]).to_be_bytes());
+        assert!(data.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
@@ -0,0 +1,398 @@
+use super::chip8::{Byte, Word};
+use super::error::Error;
+use super::quirks::Platform;
+use super::Result;
+use std::str::FromStr;
+
+// One chip8 instruction, named after the classic mnemonics.
+// The interpreter, the disassembler and the assembler all go through it.
+// X and Y are register numbers, N/NN/NNN the immediate nibble, byte and address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    //0NNN - machine code routine, this interpreter jumps to NNN
+    Sys(Word),
+    //00E0
+    Cls,
+    //00EE
+    Ret,
+    //00CN
+    Scd(Byte),
+    //00DN
+    Scu(Byte),
+    //00FB
+    Scr,
+    //00FC
+    Scl,
+    //00FD
+    Exit,
+    //00FE
+    Low,
+    //00FF
+    High,
+    //1NNN
+    Jp(Word),
+    //2NNN
+    Call(Word),
+    //3XNN
+    SeByte { x: Byte, nn: Byte },
+    //4XNN
+    SneByte { x: Byte, nn: Byte },
+    //5XY0
+    Se { x: Byte, y: Byte },
+    //5XY2
+    SaveRange { x: Byte, y: Byte },
+    //5XY3
+    LoadRange { x: Byte, y: Byte },
+    //6XNN
+    LdByte { x: Byte, nn: Byte },
+    //7XNN
+    AddByte { x: Byte, nn: Byte },
+    //8XY0
+    Ld { x: Byte, y: Byte },
+    //8XY1
+    Or { x: Byte, y: Byte },
+    //8XY2
+    And { x: Byte, y: Byte },
+    //8XY3
+    Xor { x: Byte, y: Byte },
+    //8XY4
+    Add { x: Byte, y: Byte },
+    //8XY5
+    Sub { x: Byte, y: Byte },
+    //8XY6
+    Shr { x: Byte, y: Byte },
+    //8XY7
+    Subn { x: Byte, y: Byte },
+    //8XYE
+    Shl { x: Byte, y: Byte },
+    //9XY0
+    Sne { x: Byte, y: Byte },
+    //ANNN
+    LdI(Word),
+    //BNNN
+    JpV0(Word),
+    //CXNN
+    Rnd { x: Byte, nn: Byte },
+    //DXYN
+    Drw { x: Byte, y: Byte, n: Byte },
+    //EX9E
+    Skp(Byte),
+    //EXA1
+    Sknp(Byte),
+    //F000 NNNN - the address is the word after the instruction
+    LdILong,
+    //FN01
+    Plane(Byte),
+    //F002
+    Audio,
+    //FX07
+    LdVxDt(Byte),
+    //FX0A
+    LdVxK(Byte),
+    //FX15
+    LdDtVx(Byte),
+    //FX18
+    LdStVx(Byte),
+    //FX1E
+    AddI(Byte),
+    //FX29
+    LdF(Byte),
+    //FX30
+    LdHf(Byte),
+    //FX33
+    LdB(Byte),
+    //FX3A
+    Pitch(Byte),
+    //FX55
+    LdIVx(Byte),
+    //FX65
+    LdVxI(Byte),
+    //FX75
+    LdRVx(Byte),
+    //FX85
+    LdVxR(Byte),
+}
+
+// Coarse groups of instructions, to filter execution traces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    // jumps, calls, returns and exit
+    Flow,
+    // conditional skips on registers
+    Skip,
+    // arithmetic and logic on registers, and random numbers
+    Alu,
+    // I, memory loads and stores, fonts and flags
+    Memory,
+    // clearing, drawing, scrolling, resolution and planes
+    Display,
+    Timer,
+    // key skips and waits
+    Key,
+    // XO-CHIP audio pattern and pitch
+    Sound,
+}
+
+impl FromStr for Class {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "flow" => Ok(Class::Flow),
+            "skip" => Ok(Class::Skip),
+            "alu" => Ok(Class::Alu),
+            "memory" => Ok(Class::Memory),
+            "display" => Ok(Class::Display),
+            "timer" => Ok(Class::Timer),
+            "key" => Ok(Class::Key),
+            "sound" => Ok(Class::Sound),
+            _ => Err(Error::Config(format!("unknown instruction class `{}`", s))),
+        }
+    }
+}
+
+// Decode an opcode of `platform`, opcodes that don't exist there are an error.
+pub fn decode(opcode: Word, platform: Platform) -> Result<Instruction> {
+    use Instruction::*;
+    let schip = matches!(platform, Platform::SuperChip | Platform::XoChip);
+    let xochip = platform == Platform::XoChip;
+    let x = ((opcode & 0x0F00) >> 8) as Byte;
+    let y = ((opcode & 0x00F0) >> 4) as Byte;
+    let n = (opcode & 0x000F) as Byte;
+    let nn = (opcode & 0x00FF) as Byte;
+    let nnn = opcode & 0x0FFF;
+
+    Ok(match opcode >> 12 {
+        0 => match opcode {
+            0x00E0 => Cls,
+            0x00EE => Ret,
+            0x00C0..=0x00CF if schip => Scd(n),
+            0x00D0..=0x00DF if xochip => Scu(n),
+            0x00FB if schip => Scr,
+            0x00FC if schip => Scl,
+            0x00FD if schip => Exit,
+            0x00FE if schip => Low,
+            0x00FF if schip => High,
+            _ => Sys(nnn),
+        },
+        1 => Jp(nnn),
+        2 => Call(nnn),
+        3 => SeByte { x, nn },
+        4 => SneByte { x, nn },
+        5 => match n {
+            0 => Se { x, y },
+            2 if xochip => SaveRange { x, y },
+            3 if xochip => LoadRange { x, y },
+            _ => return Err(Error::Decode(opcode)),
+        },
+        6 => LdByte { x, nn },
+        7 => AddByte { x, nn },
+        8 => match n {
+            0 => Ld { x, y },
+            1 => Or { x, y },
+            2 => And { x, y },
+            3 => Xor { x, y },
+            4 => Add { x, y },
+            5 => Sub { x, y },
+            6 => Shr { x, y },
+            7 => Subn { x, y },
+            0xE => Shl { x, y },
+            _ => return Err(Error::Decode(opcode)),
+        },
+        9 if n == 0 => Sne { x, y },
+        0xA => LdI(nnn),
+        0xB => JpV0(nnn),
+        0xC => Rnd { x, nn },
+        0xD => Drw { x, y, n },
+        0xE => match nn {
+            0x9E => Skp(x),
+            0xA1 => Sknp(x),
+            _ => return Err(Error::Decode(opcode)),
+        },
+        0xF => match nn {
+            0x00 if opcode == 0xF000 && xochip => LdILong,
+            0x01 if xochip => Plane(x),
+            0x02 if opcode == 0xF002 && xochip => Audio,
+            0x07 => LdVxDt(x),
+            0x0A => LdVxK(x),
+            0x15 => LdDtVx(x),
+            0x18 => LdStVx(x),
+            0x1E => AddI(x),
+            0x29 => LdF(x),
+            0x30 if schip => LdHf(x),
+            0x33 => LdB(x),
+            0x3A if xochip => Pitch(x),
+            0x55 => LdIVx(x),
+            0x65 => LdVxI(x),
+            0x75 if schip => LdRVx(x),
+            0x85 if schip => LdVxR(x),
+            _ => return Err(Error::Decode(opcode)),
+        },
+        _ => return Err(Error::Decode(opcode)),
+    })
+}
+
+// Encode an instruction back into its opcode, `encode(decode(op, platform)?) == op`
+// for every opcode the platform decodes. The other way round doesn't always hold,
+// `Sys(0x0E0)` encodes to 00E0 which decodes as `Cls`. F000 NNNN only encodes the first word.
+pub fn encode(instruction: &Instruction) -> Word {
+    use Instruction::*;
+    let x = |x: Byte| ((x & 0xF) as Word) << 8;
+    let y = |y: Byte| ((y & 0xF) as Word) << 4;
+    let n = |n: Byte| (n & 0xF) as Word;
+    let nnn = |nnn: Word| nnn & 0x0FFF;
+    match *instruction {
+        Sys(addr) => nnn(addr),
+        Cls => 0x00E0,
+        Ret => 0x00EE,
+        Scd(rows) => 0x00C0 | n(rows),
+        Scu(rows) => 0x00D0 | n(rows),
+        Scr => 0x00FB,
+        Scl => 0x00FC,
+        Exit => 0x00FD,
+        Low => 0x00FE,
+        High => 0x00FF,
+        Jp(addr) => 0x1000 | nnn(addr),
+        Call(addr) => 0x2000 | nnn(addr),
+        SeByte { x: vx, nn } => 0x3000 | x(vx) | nn as Word,
+        SneByte { x: vx, nn } => 0x4000 | x(vx) | nn as Word,
+        Se { x: vx, y: vy } => 0x5000 | x(vx) | y(vy),
+        SaveRange { x: vx, y: vy } => 0x5002 | x(vx) | y(vy),
+        LoadRange { x: vx, y: vy } => 0x5003 | x(vx) | y(vy),
+        LdByte { x: vx, nn } => 0x6000 | x(vx) | nn as Word,
+        AddByte { x: vx, nn } => 0x7000 | x(vx) | nn as Word,
+        Ld { x: vx, y: vy } => 0x8000 | x(vx) | y(vy),
+        Or { x: vx, y: vy } => 0x8001 | x(vx) | y(vy),
+        And { x: vx, y: vy } => 0x8002 | x(vx) | y(vy),
+        Xor { x: vx, y: vy } => 0x8003 | x(vx) | y(vy),
+        Add { x: vx, y: vy } => 0x8004 | x(vx) | y(vy),
+        Sub { x: vx, y: vy } => 0x8005 | x(vx) | y(vy),
+        Shr { x: vx, y: vy } => 0x8006 | x(vx) | y(vy),
+        Subn { x: vx, y: vy } => 0x8007 | x(vx) | y(vy),
+        Shl { x: vx, y: vy } => 0x800E | x(vx) | y(vy),
+        Sne { x: vx, y: vy } => 0x9000 | x(vx) | y(vy),
+        LdI(addr) => 0xA000 | nnn(addr),
+        JpV0(addr) => 0xB000 | nnn(addr),
+        Rnd { x: vx, nn } => 0xC000 | x(vx) | nn as Word,
+        Drw {
+            x: vx,
+            y: vy,
+            n: rows,
+        } => 0xD000 | x(vx) | y(vy) | n(rows),
+        Skp(vx) => 0xE09E | x(vx),
+        Sknp(vx) => 0xE0A1 | x(vx),
+        LdILong => 0xF000,
+        Plane(planes) => 0xF001 | x(planes),
+        Audio => 0xF002,
+        LdVxDt(vx) => 0xF007 | x(vx),
+        LdVxK(vx) => 0xF00A | x(vx),
+        LdDtVx(vx) => 0xF015 | x(vx),
+        LdStVx(vx) => 0xF018 | x(vx),
+        AddI(vx) => 0xF01E | x(vx),
+        LdF(vx) => 0xF029 | x(vx),
+        LdHf(vx) => 0xF030 | x(vx),
+        LdB(vx) => 0xF033 | x(vx),
+        Pitch(vx) => 0xF03A | x(vx),
+        LdIVx(vx) => 0xF055 | x(vx),
+        LdVxI(vx) => 0xF065 | x(vx),
+        LdRVx(vx) => 0xF075 | x(vx),
+        LdVxR(vx) => 0xF085 | x(vx),
+    }
+}
+
+impl Instruction {
+    // Whether the instruction skips the next one when its condition holds.
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            SeByte { .. } | SneByte { .. } | Se { .. } | Sne { .. } | Skp(_) | Sknp(_)
+        )
+    }
+
+    pub fn class(&self) -> Class {
+        use Instruction::*;
+        match self {
+            Sys(_) | Ret | Exit | Jp(_) | Call(_) | JpV0(_) => Class::Flow,
+            SeByte { .. } | SneByte { .. } | Se { .. } | Sne { .. } => Class::Skip,
+            LdByte { .. }
+            | AddByte { .. }
+            | Ld { .. }
+            | Or { .. }
+            | And { .. }
+            | Xor { .. }
+            | Add { .. }
+            | Sub { .. }
+            | Shr { .. }
+            | Subn { .. }
+            | Shl { .. }
+            | Rnd { .. } => Class::Alu,
+            SaveRange { .. }
+            | LoadRange { .. }
+            | LdI(_)
+            | LdILong
+            | AddI(_)
+            | LdF(_)
+            | LdHf(_)
+            | LdB(_)
+            | LdIVx(_)
+            | LdVxI(_)
+            | LdRVx(_)
+            | LdVxR(_) => Class::Memory,
+            Cls | Scd(_) | Scu(_) | Scr | Scl | Low | High | Drw { .. } | Plane(_) => {
+                Class::Display
+            }
+            LdVxDt(_) | LdDtVx(_) | LdStVx(_) => Class::Timer,
+            Skp(_) | Sknp(_) | LdVxK(_) => Class::Key,
+            Audio | Pitch(_) => Class::Sound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, Instruction};
+    use crate::quirks::Platform;
+
+    #[test]
+    fn test_decode() {
+        let vip = Platform::CosmacVip;
+        assert_eq!(decode(0x00E0, vip).unwrap(), Instruction::Cls);
+        assert_eq!(
+            decode(0xD125, vip).unwrap(),
+            Instruction::Drw { x: 1, y: 2, n: 5 }
+        );
+        assert_eq!(
+            decode(0x8AB6, vip).unwrap(),
+            Instruction::Shr { x: 0xA, y: 0xB }
+        );
+        assert_eq!(decode(0x00FF, vip).unwrap(), Instruction::Sys(0x0FF));
+        assert_eq!(
+            decode(0x00FF, Platform::SuperChip).unwrap(),
+            Instruction::High
+        );
+        assert!(decode(0xF000, vip).is_err());
+        assert_eq!(
+            decode(0xF000, Platform::XoChip).unwrap(),
+            Instruction::LdILong
+        );
+        assert!(decode(0x8008, vip).is_err());
+        assert!(decode(0xE000, vip).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for platform in [
+            Platform::CosmacVip,
+            Platform::Chip48,
+            Platform::SuperChip,
+            Platform::XoChip,
+        ] {
+            for opcode in 0..=0xFFFF {
+                if let Ok(instruction) = decode(opcode, platform) {
+                    assert_eq!(encode(&instruction), opcode);
+                }
+            }
+        }
+    }
+}
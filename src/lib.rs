@@ -1,3 +1,15 @@
+pub mod asm;
 pub mod chip8;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
 pub mod error;
+pub mod gdb;
+pub mod headless;
+pub mod instruction;
+pub mod octo;
+pub mod quirks;
+pub mod rewind;
+pub mod state;
+pub mod trace;
 pub use error::Result;
@@ -1,122 +1,357 @@
-use chip8_emulator::chip8;
+use chip8_emulator::asm;
+use chip8_emulator::chip8::{Chip8, FaultPolicy};
+use chip8_emulator::debugger;
+use chip8_emulator::disasm::{self, Syntax};
 use chip8_emulator::error::Error;
+use chip8_emulator::headless::{self, Headless, Limit};
+use chip8_emulator::octo;
+use chip8_emulator::quirks::Platform;
+use chip8_emulator::trace::{self, Tracer};
 use chip8_emulator::Result;
 use clap::*;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::sys::exit;
-use sdl2::video::Window;
-use sdl2::EventPump;
+
+#[cfg(feature = "sdl")]
+mod window;
+
 fn main() -> Result<()> {
     let app = Command::new("My app")
-        .arg(arg!([file] "Path of your rom"))
+        .arg(
+            arg!([file] "Path of your rom, or of an Octo program ending in .8o")
+                .required_unless_present("dap"),
+        )
+        .args(machine_args())
+        .arg(
+            arg!(--volume <PERCENT> "Volume of the buzzer")
+                .value_parser(value_parser!(u8).range(0..=100))
+                .default_value("25"),
+        )
+        .arg(
+            arg!(--tone <HZ> "Frequency of the buzzer, 20-20000")
+                .value_parser(parse_tone)
+                .default_value("440"),
+        )
+        .arg(arg!(--mute "Start with the buzzer muted"))
+        .arg(
+            arg!(--rewind <SECONDS> "How far back Backspace can rewind, 0 to disable")
+                .value_parser(value_parser!(u32))
+                .default_value("10"),
+        )
+        .arg(arg!(--debug "Stop before the first instruction and read debugger commands from the terminal"))
+        .arg(
+            arg!(--gdb <PORT> "Wait for a GDB remote protocol debugger on localhost:PORT")
+                .value_parser(value_parser!(u16))
+                .conflicts_with("debug"),
+        )
+        .arg(
+            arg!(--dap <PORT> "Wait for an editor speaking the Debug Adapter Protocol on localhost:PORT")
+                .value_parser(value_parser!(u16))
+                .conflicts_with_all(["debug", "gdb"]),
+        )
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("disasm")
+                .about("Print the assembly listing of a rom")
+                .arg(arg!(<file> "Path of the rom"))
+                .arg(
+                    arg!(--platform <PLATFORM> "Platform whose instructions to decode")
+                        .default_value("vip"),
+                )
+                .arg(arg!(--syntax <SYNTAX> "cowgod or octo").default_value("cowgod")),
+        )
+        .subcommand(
+            Command::new("asm")
+                .about("Assemble a source file into a rom")
+                .arg(arg!(<file> "Path of the assembly source"))
+                .arg(arg!(-o --output <FILE> "Path of the rom, defaults to the source with .ch8"))
+                .arg(
+                    arg!(--platform <PLATFORM> "Platform whose instructions are allowed")
+                        .default_value("vip"),
+                )
+                .arg(arg!(--symbols <FILE> "Also write the labels and constants to FILE")),
+        )
+        .subcommand(
+            Command::new("trace-diff")
+                .about("Find the first instruction where two execution traces differ")
+                .arg(arg!(<a> "Trace written with --trace, or by another emulator"))
+                .arg(arg!(<b> "Trace to compare with"))
+                .arg(
+                    arg!(--context <N> "Instructions to show before the divergence")
+                        .value_parser(value_parser!(usize))
+                        .default_value("5"),
+                )
+                .arg(arg!(--ignore <FIELDS> "Fields not to compare, comma separated: pc, op, v, v0-vf, i, sp, dt, st, timers, display")),
+        )
+        .subcommand(
+            Command::new("headless")
+                .about("Run a rom without a window and print its display")
+                .arg(arg!(<file> "Path of the rom, or of an Octo program ending in .8o"))
+                .args(machine_args())
+                .arg(
+                    arg!(--frames <N> "Frames to run")
+                        .value_parser(value_parser!(u64))
+                        .default_value("60"),
+                )
+                .arg(
+                    arg!(--cycles <N> "Instructions to run, instead of frames")
+                        .value_parser(value_parser!(u64))
+                        .conflicts_with("frames"),
+                )
+                .arg(arg!(--input <FILE> "Timeline of key presses, lines like `60 press 5` or `90 down A`"))
+                .arg(
+                    arg!(--display <FORMAT> "How to dump the display at the end")
+                        .value_parser(["ascii", "png", "hash", "none"])
+                        .default_value("ascii"),
+                )
+                .arg(arg!(-o --output <FILE> "Write the display to FILE instead of the terminal, required for png"))
+                .arg(
+                    arg!(--scale <N> "Size of a pixel in the png")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .default_value("4"),
+                )
+                .arg(arg!(--registers "Also print the registers"))
+                .arg(arg!(--memory <RANGE> "Also print the memory at START-END, e.g. 0x200-0x2FF")),
+        )
         .get_matches();
-    let path: String = app.get_one::<String>("file").expect("required").to_string();
+    match app.subcommand() {
+        Some(("disasm", matches)) => return disasm_command(matches),
+        Some(("asm", matches)) => return asm_command(matches),
+        Some(("trace-diff", matches)) => return trace_diff_command(matches),
+        Some(("headless", matches)) => return headless_command(matches),
+        _ => (),
+    }
+    // with --dap the editor may launch the program instead
+    let path = app.get_one::<String>("file").cloned();
+    #[cfg_attr(not(feature = "sdl"), allow(unused_variables))]
+    let (mut chip8, ipf) = machine(&app, path.as_deref())?;
 
-    run(path)?;
-    Ok(())
+    #[cfg(feature = "sdl")]
+    let result = window::play(&app, &mut chip8, ipf, path);
+    #[cfg(not(feature = "sdl"))]
+    let result = Err(Error::Config(
+        "built without SDL, only the subcommands are available".to_string(),
+    ));
+    if let Some(tracer) = chip8.take_tracer() {
+        tracer.finish()?;
+    }
+    result
 }
 
-fn run(path: String) -> Result<()> {
-    let sdl2_context = sdl2::init().map_err(|s| Error::SdlError(s))?;
-    let video_subsystem = sdl2_context.video().map_err(|s| Error::SdlError(s))?;
-    let window = video_subsystem
-        .window("chip8_emulator", 640, 320)
-        .position_centered()
-        .build()?;
+// Options that set up the machine, shared by the window and `headless`.
+// A buzzer frequency that can be heard, anything else makes no sound or no sense.
+fn parse_tone(text: &str) -> std::result::Result<f32, String> {
+    let tone: f32 = text
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", text))?;
+    if (20.0..=20000.0).contains(&tone) {
+        Ok(tone)
+    } else {
+        Err("the tone has to be 20-20000 Hz".to_string())
+    }
+}
 
-    let mut canvas = window.into_canvas().build()?;
-    let mut event_pump = sdl2_context.event_pump().map_err(|s| Error::SdlError(s))?;
+fn machine_args() -> Vec<Arg> {
+    vec![
+        arg!(--platform <PLATFORM> "Platform whose quirks to emulate: vip, chip48, schip or xochip")
+            .default_value("vip"),
+        arg!(--quirks <FILE> "Config file overriding single quirks of the platform"),
+        arg!(--ipf <N> "Instructions per frame, defaults to the usual speed of the platform")
+            .value_parser(value_parser!(u32).range(1..)),
+        arg!(--"on-fault" <POLICY> "What to do on an illegal opcode: halt, skip or nop")
+            .default_value("halt"),
+        arg!(--trace <FILE> "Write every executed instruction and the registers to FILE"),
+        arg!(--"trace-format" <FORMAT> "text, or binary for compact fixed size records")
+            .default_value("text")
+            .requires("trace"),
+        arg!(--"trace-range" <RANGE> "Only trace instructions at addresses START-END, e.g. 0x200-0x2FF")
+            .requires("trace"),
+        arg!(--"trace-only" <CLASSES> "Only trace these instruction classes, comma separated: flow, skip, alu, memory, display, timer, key, sound")
+            .requires("trace"),
+    ]
+}
 
-    chip8::cpu_reset(path)?;
+// Build the machine from the options of `machine_args` and load the program at `path`.
+// Returns it with the instructions per frame to run it at.
+fn machine(matches: &ArgMatches, path: Option<&str>) -> Result<(Chip8, u32)> {
+    let platform: Platform = matches
+        .get_one::<String>("platform")
+        .expect("default")
+        .parse()?;
+    let mut quirks = platform.quirks();
+    if let Some(file) = matches.get_one::<String>("quirks") {
+        quirks = quirks.apply_config(&std::fs::read_to_string(file)?)?;
+    }
+    let ipf = matches
+        .get_one::<u32>("ipf")
+        .copied()
+        .unwrap_or_else(|| platform.ipf());
 
-    let dura = std::time::Duration::from_millis(1);
+    let fault_policy: FaultPolicy = matches
+        .get_one::<String>("on-fault")
+        .expect("default")
+        .parse()?;
 
-    loop {
-        canvas_draw(&mut canvas)?;
-        unsafe {
-            chip8::execute()?;
+    let mut chip8 = Chip8::with_platform(platform);
+    chip8.set_quirks(quirks);
+    chip8.set_fault_policy(fault_policy);
+    match path {
+        Some(path) if path.ends_with(".8o") => {
+            // Octo source, compiled in place of a rom
+            let program = octo::compile_file(path, platform).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            });
+            chip8.load_bytes(&program.rom)?;
         }
-        key_event(&mut event_pump);
+        Some(path) => chip8.load_rom(path)?,
+        None => (),
+    }
 
-        std::thread::sleep(dura);
+    if let Some(file) = matches.get_one::<String>("trace") {
+        let format = matches
+            .get_one::<String>("trace-format")
+            .expect("default")
+            .parse()?;
+        let mut tracer = Tracer::create(file, format)?;
+        if let Some(range) = matches.get_one::<String>("trace-range") {
+            tracer.set_range(Some(trace::parse_range(range)?));
+        }
+        if let Some(classes) = matches.get_one::<String>("trace-only") {
+            let classes = classes.split(',').map(|class| class.trim().parse());
+            tracer.set_classes(Some(classes.collect::<Result<_>>()?));
+        }
+        chip8.set_tracer(Some(tracer));
     }
 
+    Ok((chip8, ipf))
+}
+
+fn disasm_command(matches: &ArgMatches) -> Result<()> {
+    let rom = std::fs::read(matches.get_one::<String>("file").expect("required"))?;
+    let platform: Platform = matches
+        .get_one::<String>("platform")
+        .expect("default")
+        .parse()?;
+    let syntax: Syntax = matches
+        .get_one::<String>("syntax")
+        .expect("default")
+        .parse()?;
+    print!("{}", disasm::disassemble(&rom, platform, syntax));
     Ok(())
 }
 
-fn canvas_draw(canvas: &mut Canvas<Window>) -> Result<()> {
-    canvas.set_draw_color(Color::BLACK);
-    canvas.clear();
-
-    for i in 0..64 {
-        for j in 0..32 {
-            let pixel = chip8::view(i, j);
-            if pixel == 1 {
-                canvas.set_draw_color(Color::WHITE);
-                canvas
-                    .fill_rect(Rect::new(i as i32 * 10, j as i32 * 10, 10, 10))
-                    .map_err(|s| Error::SdlError(s))?;
-            }
-        }
+fn asm_command(matches: &ArgMatches) -> Result<()> {
+    let source = matches.get_one::<String>("file").expect("required");
+    let platform: Platform = matches
+        .get_one::<String>("platform")
+        .expect("default")
+        .parse()?;
+    // print assembly errors as `file:line: message`, like a compiler
+    let program = asm::assemble_file(source, platform).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let output = match matches.get_one::<String>("output") {
+        Some(output) => output.clone(),
+        None => std::path::Path::new(source)
+            .with_extension("ch8")
+            .display()
+            .to_string(),
+    };
+    std::fs::write(&output, &program.rom)?;
+    if let Some(symbols) = matches.get_one::<String>("symbols") {
+        std::fs::write(symbols, program.symbol_file())?;
     }
-
-    canvas.present();
+    println!("wrote {} bytes to {}", program.rom.len(), output);
     Ok(())
 }
 
-fn key_event(event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::KeyDown {
-                keycode: Some(keycode),
-                ..
-            } => match keycode {
-                Keycode::X => chip8::key_pressed(0),
-                Keycode::Num1 => chip8::key_pressed(1),
-                Keycode::Num2 => chip8::key_pressed(2),
-                Keycode::Num3 => chip8::key_pressed(3),
-                Keycode::Q => chip8::key_pressed(4),
-                Keycode::W => chip8::key_pressed(5),
-                Keycode::E => chip8::key_pressed(6),
-                Keycode::A => chip8::key_pressed(7),
-                Keycode::S => chip8::key_pressed(8),
-                Keycode::D => chip8::key_pressed(9),
-                Keycode::Z => chip8::key_pressed(10),
-                Keycode::C => chip8::key_pressed(11),
-                Keycode::Num4 => chip8::key_pressed(12),
-                Keycode::R => chip8::key_pressed(13),
-                Keycode::F => chip8::key_pressed(14),
-                Keycode::V => chip8::key_pressed(15),
-                Keycode::Escape => unsafe { exit(0) },
-                _ => (),
-            },
-            Event::KeyUp {
-                keycode: Some(keycode),
-                ..
-            } => match keycode {
-                Keycode::X => chip8::key_released(0),
-                Keycode::Num1 => chip8::key_released(1),
-                Keycode::Num2 => chip8::key_released(2),
-                Keycode::Num3 => chip8::key_released(3),
-                Keycode::Q => chip8::key_released(4),
-                Keycode::W => chip8::key_released(5),
-                Keycode::E => chip8::key_released(6),
-                Keycode::A => chip8::key_released(7),
-                Keycode::S => chip8::key_released(8),
-                Keycode::D => chip8::key_released(9),
-                Keycode::Z => chip8::key_released(10),
-                Keycode::C => chip8::key_released(11),
-                Keycode::Num4 => chip8::key_released(12),
-                Keycode::R => chip8::key_released(13),
-                Keycode::F => chip8::key_released(14),
-                Keycode::V => chip8::key_released(15),
-                _ => (),
-            },
-            _ => (),
+fn trace_diff_command(matches: &ArgMatches) -> Result<()> {
+    let name_a = matches.get_one::<String>("a").expect("required");
+    let name_b = matches.get_one::<String>("b").expect("required");
+    let a = trace::read_trace(&std::fs::read(name_a)?)?;
+    let b = trace::read_trace(&std::fs::read(name_b)?)?;
+    let ignored = match matches.get_one::<String>("ignore") {
+        Some(fields) => trace::parse_fields(fields)?,
+        None => Vec::new(),
+    };
+    let context = *matches.get_one::<usize>("context").expect("default");
+    match trace::diff(&a, &b, &ignored, context) {
+        (Some(divergence), _) => {
+            print!("{}", divergence.report(name_a, name_b));
+            // like diff, a difference is exit code 1
+            std::process::exit(1)
+        }
+        (None, compared) => {
+            println!(
+                "no divergence in {} instructions ({} has {}, {} has {})",
+                compared,
+                name_a,
+                a.len(),
+                name_b,
+                b.len()
+            );
+            Ok(())
         }
     }
 }
+
+fn headless_command(matches: &ArgMatches) -> Result<()> {
+    let path = matches.get_one::<String>("file").expect("required");
+    let (chip8, ipf) = machine(matches, Some(path))?;
+    let limit = match matches.get_one::<u64>("cycles") {
+        Some(&cycles) => Limit::Cycles(cycles),
+        None => Limit::Frames(*matches.get_one::<u64>("frames").expect("default")),
+    };
+    let format = matches.get_one::<String>("display").expect("default");
+    let output = matches.get_one::<String>("output");
+    if format == "png" && output.is_none() {
+        return Err(Error::Config(
+            "--display png needs an --output file".to_string(),
+        ));
+    }
+
+    let mut headless = Headless::new(chip8, ipf);
+    if let Some(file) = matches.get_one::<String>("input") {
+        headless.set_input(headless::parse_input(&std::fs::read_to_string(file)?)?);
+    }
+    let result = headless.run(limit);
+    eprintln!(
+        "ran {} frames, {} instructions",
+        headless.frame(),
+        headless.cycles()
+    );
+    let mut chip8 = headless.into_chip8();
+    if let Some(tracer) = chip8.take_tracer() {
+        tracer.finish()?;
+    }
+    // the state is still dumped after a fault, to see what went wrong
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+
+    let display = match format.as_str() {
+        "ascii" => headless::ascii(&chip8).into_bytes(),
+        "png" => headless::png(
+            &chip8,
+            *matches.get_one::<u32>("scale").expect("default") as usize,
+        ),
+        "hash" => format!("{:08X}\n", chip8.display_hash()).into_bytes(),
+        _ => Vec::new(),
+    };
+    match output {
+        Some(file) => std::fs::write(file, display)?,
+        None => print!("{}", String::from_utf8_lossy(&display)),
+    }
+    if matches.get_flag("registers") {
+        println!("{}", debugger::registers(&chip8));
+    }
+    if let Some(range) = matches.get_one::<String>("memory") {
+        let range = trace::parse_range(range)?;
+        let len = (*range.end() as u32 + 1).saturating_sub(*range.start() as u32);
+        println!("{}", debugger::hex_dump(&chip8, *range.start(), len));
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
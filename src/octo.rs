@@ -0,0 +1,1102 @@
+use super::asm::Program;
+use super::chip8::{Byte, Word, PROGRAM_START};
+use super::error::Error;
+use super::instruction::{self, Instruction};
+use super::quirks::Platform;
+use super::Result;
+use std::collections::{BTreeMap, HashMap};
+
+// Compiler for the Octo language, https://github.com/JohnEarnest/Octo
+//
+//     :alias x v1
+//     :const SPEED 2
+//     : main
+//         i := sprite
+//         loop
+//             x += SPEED
+//             if x == 60 then x := 0
+//             sprite x v2 3
+//         again
+//     : sprite
+//         0b11100000 0b10100000 0b11100000
+//
+// Covers labels, `:=` and the other assignments, `if ... then`, `if ... begin ... else ... end`,
+// `loop ... while ... again`, `:macro`, `:calc`, `:const`, `:alias`, `:unpack`, `:next`,
+// `:org`, `:byte`, `:call`, `:assert` and the SUPER-CHIP and XO-CHIP statements.
+// Unlike Octo, instructions the platform doesn't have are compile errors.
+
+// Compile Octo source into a rom, errors name `file`.
+pub fn compile(source: &str, file: &str, platform: Platform) -> Result<Program> {
+    let mut tokens = tokenize(source);
+    let end = tokens.last().cloned().unwrap_or(Token {
+        text: String::new(),
+        line: 1,
+        column: 1,
+        depth: 0,
+    });
+    tokens.reverse();
+    let mut compiler = Compiler {
+        file: file.to_string(),
+        platform,
+        tokens,
+        end,
+        rom: Vec::new(),
+        here: PROGRAM_START as usize,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+        started: false,
+        lines: BTreeMap::new(),
+    };
+    compiler.program()?;
+    let symbols = compiler
+        .labels
+        .iter()
+        .map(|(name, &address)| (name.clone(), address as i64))
+        .collect::<BTreeMap<_, _>>();
+    let lines = compiler
+        .lines
+        .iter()
+        .map(|(&address, &line)| (address, (file.to_string(), line)))
+        .collect();
+    Ok(Program {
+        rom: compiler.rom,
+        symbols,
+        lines,
+    })
+}
+
+// Compile an `.8o` file.
+pub fn compile_file(path: &str, platform: Platform) -> Result<Program> {
+    compile(&std::fs::read_to_string(path)?, path, platform)
+}
+
+// macros nested deeper than this are assumed to recurse forever
+const MAX_DEPTH: usize = 1_000;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // how many macro expansions deep the token came from
+    depth: usize,
+}
+
+// Split the source at whitespace, `#` starts a comment.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut start = None;
+        for (column, c) in line.char_indices().chain([(line.len(), ' ')]) {
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(begin)) => {
+                    tokens.push(Token {
+                        text: line[begin..column].to_string(),
+                        line: number + 1,
+                        column: begin + 1,
+                        depth: 0,
+                    });
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+    tokens
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How to patch in a label that is defined later.
+#[derive(Clone, Copy)]
+enum Fixup {
+    // the NNN of the instruction at the address
+    Address,
+    // the 16 bit word at the address
+    Long,
+    // `:unpack`, the high nibble of the address into the low nibble of the first
+    // instruction and the low byte into the second
+    Unpack,
+}
+
+// A condition of `if` or `while`, compiled to the instructions that set up the
+// comparison and two skips: one skipping when it is false, one when it is true.
+struct Condition {
+    setup: Vec<Instruction>,
+    skip_if_false: Instruction,
+    skip_if_true: Instruction,
+}
+
+// The right hand side of a comparison or assignment.
+enum Operand {
+    Register(Byte),
+    Value(i64),
+}
+
+struct Compiler {
+    file: String,
+    platform: Platform,
+    // the tokens still to compile, the next one last
+    tokens: Vec<Token>,
+    // the last token of the source, for errors at the end
+    end: Token,
+    // the program, starting at 0x200
+    rom: Vec<Byte>,
+    here: usize,
+    labels: HashMap<String, Word>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, Byte>,
+    macros: HashMap<String, Macro>,
+    // places to patch once a label is known
+    fixups: Vec<(usize, String, Fixup, Token)>,
+    // start of every open `loop` and the jumps of its `while`s
+    loops: Vec<(Word, Vec<usize>)>,
+    // the jump of every open `if ... begin` or `else`
+    branches: Vec<(usize, Token)>,
+    // set once the first byte is emitted
+    started: bool,
+    // source line of every instruction
+    lines: BTreeMap<Word, usize>,
+}
+
+impl Compiler {
+    fn error(&self, token: &Token, message: impl Into<String>) -> Error {
+        Error::Compile {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        self.tokens
+            .pop()
+            .ok_or_else(|| self.error(&self.end, "unexpected end of the program"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected `{}`", text)));
+        }
+        Ok(token)
+    }
+
+    fn program(&mut self) -> Result<()> {
+        while !self.tokens.is_empty() {
+            let token = self.next()?;
+            if let Some(expansion) = self.macros.get(&token.text) {
+                if token.depth >= MAX_DEPTH {
+                    return Err(self.error(&token, "the macro expands forever"));
+                }
+                let (args, mut body) = (expansion.args.clone(), expansion.body.clone());
+                let mut values = HashMap::new();
+                for arg in args {
+                    values.insert(arg, self.next()?.text);
+                }
+                for t in body.iter_mut() {
+                    if let Some(value) = values.get(&t.text) {
+                        t.text = value.clone();
+                    }
+                    t.depth = token.depth + 1;
+                }
+                self.tokens.extend(body.into_iter().rev());
+                continue;
+            }
+            self.statement(token)?;
+        }
+
+        if let Some((_, token)) = self.branches.last() {
+            return Err(self.error(token, "`begin` without `end`"));
+        }
+        if !self.loops.is_empty() {
+            return Err(self.error(&self.end, "`loop` without `again`"));
+        }
+        for (address, label, fixup, token) in std::mem::take(&mut self.fixups) {
+            let Some(&target) = self.labels.get(&label) else {
+                return Err(self.error(&token, format!("undefined label `{}`", label)));
+            };
+            let offset = address - PROGRAM_START as usize;
+            match fixup {
+                Fixup::Address => {
+                    if target > 0xFFF {
+                        return Err(self.error(&token, format!("`{}` is above 0xFFF", label)));
+                    }
+                    self.rom[offset] |= (target >> 8) as Byte;
+                    self.rom[offset + 1] = target as Byte;
+                }
+                Fixup::Long => {
+                    self.rom[offset..offset + 2].copy_from_slice(&target.to_be_bytes());
+                }
+                Fixup::Unpack => {
+                    self.rom[offset + 1] |= (target >> 8) as Byte & 0x0F;
+                    self.rom[offset + 3] = target as Byte;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<()> {
+        use Instruction::*;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                // label the operand byte of the next instruction, for self-modifying code
+                let name = self.next()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.next()?;
+                let register = self.register(&register)?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.number()?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => {
+                let name = self.next()?;
+                let mut args = Vec::new();
+                while self.peek() != Some("{") {
+                    args.push(self.next()?.text);
+                }
+                self.expect("{")?;
+                let mut body = Vec::new();
+                let mut depth = 1;
+                loop {
+                    let t = self.next()?;
+                    match t.text.as_str() {
+                        "{" => depth += 1,
+                        "}" => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    body.push(t);
+                }
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":org" => {
+                let value = self.number()? as i64;
+                if value < PROGRAM_START as i64 || value > 0xFFFF {
+                    return Err(self.error(&token, format!(":org {:#X} is out of memory", value)));
+                }
+                self.here = value as usize;
+            }
+            ":byte" => {
+                let value = self.number()? as i64;
+                let byte = self.byte(&token, value)?;
+                self.emit_bytes(&[byte], &token)?;
+            }
+            ":call" => {
+                let target = self.next()?;
+                self.address_instruction(Call(0), &target)?;
+            }
+            ":unpack" => {
+                // v0 := N << 4 | high nibble of the label, v1 := low byte of the label
+                let nibble = self.number()? as i64 as Byte & 0x0F;
+                let label = self.next()?;
+                self.start()?;
+                let address = self.here;
+                self.emit(
+                    LdByte {
+                        x: 0,
+                        nn: nibble << 4,
+                    },
+                    &token,
+                )?;
+                self.emit(LdByte { x: 1, nn: 0 }, &token)?;
+                match self.labels.get(&label.text) {
+                    Some(&target) => {
+                        let offset = address - PROGRAM_START as usize;
+                        self.rom[offset + 1] |= (target >> 8) as Byte & 0x0F;
+                        self.rom[offset + 3] = target as Byte;
+                    }
+                    None => self
+                        .fixups
+                        .push((address, label.text.clone(), Fixup::Unpack, label)),
+                }
+            }
+            ":assert" => {
+                if self.calc()? == 0.0 {
+                    return Err(self.error(&token, "assertion failed"));
+                }
+            }
+            // debugger hints of Octo, they don't produce code
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.emit(Ret, &token)?,
+            "clear" => self.emit(Cls, &token)?,
+            "hires" => self.emit(High, &token)?,
+            "lores" => self.emit(Low, &token)?,
+            "exit" => self.emit(Exit, &token)?,
+            "scroll-left" => self.emit(Scl, &token)?,
+            "scroll-right" => self.emit(Scr, &token)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let value = self.number()? as i64;
+                if !(0..=15).contains(&value) {
+                    return Err(self.error(&token, format!("{} is not a nibble", value)));
+                }
+                let n = value as Byte;
+                let instruction = match token.text.as_str() {
+                    "scroll-down" => Scd(n),
+                    "scroll-up" => Scu(n),
+                    _ => Plane(n),
+                };
+                self.emit(instruction, &token)?;
+            }
+            "audio" => self.emit(Audio, &token)?,
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.next_register()?;
+                let instruction = match token.text.as_str() {
+                    "bcd" => LdB(x),
+                    "saveflags" => LdRVx(x),
+                    _ => LdVxR(x),
+                };
+                self.emit(instruction, &token)?;
+            }
+            "save" | "load" => {
+                let x = self.next_register()?;
+                let save = token.text == "save";
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.next_register()?;
+                    if save {
+                        SaveRange { x, y }
+                    } else {
+                        LoadRange { x, y }
+                    }
+                } else if save {
+                    LdIVx(x)
+                } else {
+                    LdVxI(x)
+                };
+                self.emit(instruction, &token)?;
+            }
+            "sprite" => {
+                let x = self.next_register()?;
+                let y = self.next_register()?;
+                let n = self.number()? as i64;
+                if !(0..=15).contains(&n) {
+                    return Err(self.error(&token, format!("sprite height {} is too big", n)));
+                }
+                self.emit(Drw { x, y, n: n as Byte }, &token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.next()?;
+                let instruction = match token.text.as_str() {
+                    "jump" => Jp(0),
+                    "jump0" => JpV0(0),
+                    _ => Sys(0),
+                };
+                self.address_instruction(instruction, &target)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next_register()?;
+                let instruction = match token.text.as_str() {
+                    "delay" => LdDtVx(x),
+                    "buzzer" => LdStVx(x),
+                    _ => Pitch(x),
+                };
+                self.emit(instruction, &token)?;
+            }
+            "i" => self.index(&token)?,
+            "if" => {
+                let condition = self.condition()?;
+                let keyword = self.next()?;
+                for instruction in &condition.setup {
+                    self.emit(*instruction, &keyword)?;
+                }
+                match keyword.text.as_str() {
+                    "then" => self.emit(condition.skip_if_false, &keyword)?,
+                    "begin" => {
+                        self.emit(condition.skip_if_true, &keyword)?;
+                        self.branches.push((self.here, keyword.clone()));
+                        self.emit(Jp(0), &keyword)?;
+                    }
+                    _ => return Err(self.error(&keyword, "expected `then` or `begin`")),
+                }
+            }
+            "else" => {
+                let Some((jump, _)) = self.branches.pop() else {
+                    return Err(self.error(&token, "`else` without `if ... begin`"));
+                };
+                self.branches.push((self.here, token.clone()));
+                self.emit(Jp(0), &token)?;
+                self.patch_jump(jump, &token)?;
+            }
+            "end" => {
+                let Some((jump, _)) = self.branches.pop() else {
+                    return Err(self.error(&token, "`end` without `if ... begin`"));
+                };
+                self.patch_jump(jump, &token)?;
+            }
+            "loop" => {
+                self.start()?;
+                self.loops.push((self.here as Word, Vec::new()));
+            }
+            "while" => {
+                let condition = self.condition()?;
+                for instruction in &condition.setup {
+                    self.emit(*instruction, &token)?;
+                }
+                self.emit(condition.skip_if_true, &token)?;
+                let jump = self.here;
+                let Some((_, whiles)) = self.loops.last_mut() else {
+                    return Err(self.error(&token, "`while` outside of a loop"));
+                };
+                whiles.push(jump);
+                self.emit(Jp(0), &token)?;
+            }
+            "again" => {
+                let Some((start, whiles)) = self.loops.pop() else {
+                    return Err(self.error(&token, "`again` without `loop`"));
+                };
+                self.emit(Jp(start), &token)?;
+                for jump in whiles {
+                    self.patch_jump(jump, &token)?;
+                }
+            }
+            _ => {
+                if let Ok(x) = self.register(&token) {
+                    return self.assignment(x, &token);
+                }
+                match self.value(&token) {
+                    // numbers in the code are data, like sprites
+                    Some(value) => {
+                        let byte = self.byte(&token, value as i64)?;
+                        self.emit_bytes(&[byte], &token)?;
+                    }
+                    // anything else calls a label, which may be defined later
+                    None => self.address_instruction(Call(0), &token)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, address: usize) -> Result<()> {
+        if self.labels.contains_key(&name.text) {
+            return Err(self.error(name, format!("`{}` is already defined", name.text)));
+        }
+        if address > 0xFFFF {
+            return Err(self.error(name, format!("`{}` is past the end of memory", name.text)));
+        }
+        self.labels.insert(name.text.clone(), address as Word);
+        Ok(())
+    }
+
+    // `vx op ...`
+    fn assignment(&mut self, x: Byte, token: &Token) -> Result<()> {
+        use Instruction::*;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let instruction = match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "random") => {
+                let value = self.number()? as i64;
+                Rnd {
+                    x,
+                    nn: self.byte(&rhs, value)?,
+                }
+            }
+            (":=", "key") => LdVxK(x),
+            (":=", "delay") => LdVxDt(x),
+            _ => match (op.text.as_str(), self.operand(&rhs)?) {
+                (":=", Operand::Register(y)) => Ld { x, y },
+                (":=", Operand::Value(nn)) => LdByte {
+                    x,
+                    nn: self.byte(&rhs, nn)?,
+                },
+                ("+=", Operand::Register(y)) => Add { x, y },
+                ("+=", Operand::Value(nn)) => AddByte {
+                    x,
+                    nn: self.byte(&rhs, nn)?,
+                },
+                ("-=", Operand::Register(y)) => Sub { x, y },
+                ("-=", Operand::Value(nn)) => AddByte {
+                    x,
+                    nn: self.byte(&rhs, nn)?.wrapping_neg(),
+                },
+                ("=-", Operand::Register(y)) => Subn { x, y },
+                ("|=", Operand::Register(y)) => Or { x, y },
+                ("&=", Operand::Register(y)) => And { x, y },
+                ("^=", Operand::Register(y)) => Xor { x, y },
+                (">>=", Operand::Register(y)) => Shr { x, y },
+                ("<<=", Operand::Register(y)) => Shl { x, y },
+                _ => {
+                    return Err(
+                        self.error(&op, format!("can't use `{}` with `{}`", op.text, rhs.text))
+                    )
+                }
+            },
+        };
+        self.emit(instruction, token)
+    }
+
+    // `i := ...` and `i += vx`
+    fn index(&mut self, token: &Token) -> Result<()> {
+        use Instruction::*;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        match (op.text.as_str(), rhs.text.as_str()) {
+            ("+=", _) => {
+                let x = self.register(&rhs)?;
+                self.emit(AddI(x), token)
+            }
+            (":=", "hex") => {
+                let x = self.next_register()?;
+                self.emit(LdF(x), token)
+            }
+            (":=", "bighex") => {
+                let x = self.next_register()?;
+                self.emit(LdHf(x), token)
+            }
+            (":=", "long") => {
+                let target = self.next()?;
+                self.emit(LdILong, token)?;
+                let address = self.here;
+                let value = match self.labels.get(&target.text) {
+                    Some(&address) => address,
+                    None => match self.value(&target) {
+                        Some(value) if (0.0..=65535.0).contains(&value) => value as Word,
+                        Some(_) => return Err(self.error(&target, "address out of range")),
+                        None => {
+                            self.fixups.push((
+                                address,
+                                target.text.clone(),
+                                Fixup::Long,
+                                target.clone(),
+                            ));
+                            0
+                        }
+                    },
+                };
+                self.emit_bytes(&value.to_be_bytes(), token)
+            }
+            (":=", _) => self.address_instruction(LdI(0), &rhs),
+            _ => Err(self.error(&op, format!("can't use `{}` with `i`", op.text))),
+        }
+    }
+
+    // `vx == 3`, `vx != vy`, `vx < 3`, `vx key`, ...
+    fn condition(&mut self) -> Result<Condition> {
+        use Instruction::*;
+        let lhs = self.next()?;
+        let x = self.register(&lhs)?;
+        let op = self.next()?;
+        match op.text.as_str() {
+            "key" => {
+                return Ok(Condition {
+                    setup: Vec::new(),
+                    skip_if_false: Sknp(x),
+                    skip_if_true: Skp(x),
+                })
+            }
+            "-key" => {
+                return Ok(Condition {
+                    setup: Vec::new(),
+                    skip_if_false: Skp(x),
+                    skip_if_true: Sknp(x),
+                })
+            }
+            _ => (),
+        }
+        let rhs = self.next()?;
+        let operand = self.operand(&rhs)?;
+        let (equal, not_equal) = match operand {
+            Operand::Register(y) => (Se { x, y }, Sne { x, y }),
+            Operand::Value(nn) => {
+                let nn = self.byte(&rhs, nn)?;
+                (SeByte { x, nn }, SneByte { x, nn })
+            }
+        };
+        // The ordered comparisons subtract in VF and test the borrow flag,
+        // which is 1 for `p >= q`.
+        let greater_equal = |p: &Operand, q: &Operand| -> Vec<Instruction> {
+            match (p, q) {
+                (Operand::Register(p), Operand::Register(q)) => {
+                    vec![Ld { x: 0xF, y: *p }, Sub { x: 0xF, y: *q }]
+                }
+                (Operand::Register(p), Operand::Value(q)) => vec![
+                    LdByte {
+                        x: 0xF,
+                        nn: *q as Byte,
+                    },
+                    Subn { x: 0xF, y: *p },
+                ],
+                (Operand::Value(p), Operand::Register(q)) => vec![
+                    LdByte {
+                        x: 0xF,
+                        nn: *p as Byte,
+                    },
+                    Sub { x: 0xF, y: *q },
+                ],
+                (Operand::Value(_), Operand::Value(_)) => unreachable!("lhs is a register"),
+            }
+        };
+        let lhs = Operand::Register(x);
+        let flag_set = (SneByte { x: 0xF, nn: 1 }, SeByte { x: 0xF, nn: 1 });
+        let flag_clear = (SneByte { x: 0xF, nn: 0 }, SeByte { x: 0xF, nn: 0 });
+        let (setup, (skip_if_false, skip_if_true)) = match op.text.as_str() {
+            "==" => (Vec::new(), (not_equal, equal)),
+            "!=" => (Vec::new(), (equal, not_equal)),
+            ">=" => (greater_equal(&lhs, &operand), flag_set),
+            "<" => (greater_equal(&lhs, &operand), flag_clear),
+            "<=" => (greater_equal(&operand, &lhs), flag_set),
+            ">" => (greater_equal(&operand, &lhs), flag_clear),
+            _ => return Err(self.error(&op, format!("unknown comparison `{}`", op.text))),
+        };
+        Ok(Condition {
+            setup,
+            skip_if_false,
+            skip_if_true,
+        })
+    }
+
+    // An instruction with a 12 bit address, the label may be defined later.
+    fn address_instruction(&mut self, instruction: Instruction, target: &Token) -> Result<()> {
+        self.start()?;
+        let address = match self.labels.get(&target.text) {
+            Some(&address) => Some(address as i64),
+            None => self.value(target).map(|v| v as i64),
+        };
+        let address = match address {
+            Some(address) if !(0..=0xFFF).contains(&address) => {
+                return Err(self.error(
+                    target,
+                    format!("address {} is out of 0x000-0xFFF", target.text),
+                ))
+            }
+            Some(address) => address as Word,
+            None => {
+                if !is_name(&target.text) {
+                    return Err(self.error(target, format!("bad address `{}`", target.text)));
+                }
+                self.fixups.push((
+                    self.here,
+                    target.text.clone(),
+                    Fixup::Address,
+                    target.clone(),
+                ));
+                0
+            }
+        };
+        let opcode = instruction::encode(&instruction) | address;
+        let instruction = instruction::decode(opcode, self.platform)
+            .map_err(|e| self.error(target, e.to_string()))?;
+        self.emit(instruction, target)
+    }
+
+    fn patch_jump(&mut self, jump: usize, token: &Token) -> Result<()> {
+        if self.here > 0xFFF {
+            return Err(self.error(token, "jump target is above 0xFFF"));
+        }
+        let offset = jump - PROGRAM_START as usize;
+        self.rom[offset] |= (self.here >> 8) as Byte;
+        self.rom[offset + 1] = self.here as Byte;
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction, token: &Token) -> Result<()> {
+        let opcode = instruction::encode(&instruction);
+        if instruction::decode(opcode, self.platform).ok() != Some(instruction) {
+            return Err(self.error(
+                token,
+                format!(
+                    "`{}` needs another platform than {:?}",
+                    token.text, self.platform
+                ),
+            ));
+        }
+        self.start()?;
+        self.lines.insert(self.here as Word, token.line);
+        self.emit_bytes(&opcode.to_be_bytes(), token)
+    }
+
+    // Execution starts at 0x200, so unless `main` is there,
+    // the program begins with a jump to it. Called before anything refers to `here`.
+    fn start(&mut self) -> Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        if self.labels.get("main") == Some(&PROGRAM_START) {
+            return Ok(());
+        }
+        let here = self.here;
+        self.here = PROGRAM_START as usize;
+        let token = Token {
+            text: "main".to_string(),
+            line: 1,
+            column: 1,
+            depth: 0,
+        };
+        self.address_instruction(Instruction::Jp(0), &token)?;
+        if here == PROGRAM_START as usize {
+            // labels without code yet, like `: sprite`, move behind the jump
+            for address in self.labels.values_mut() {
+                if *address == PROGRAM_START {
+                    *address += 2;
+                }
+            }
+        } else {
+            self.here = here;
+        }
+        Ok(())
+    }
+
+    fn emit_bytes(&mut self, bytes: &[Byte], token: &Token) -> Result<()> {
+        self.start()?;
+        let offset = self.here - PROGRAM_START as usize;
+        if self.here + bytes.len() > 0x10000 {
+            return Err(self.error(token, "the program doesn't fit into memory"));
+        }
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Result<Byte> {
+        if let Some(&register) = self.aliases.get(&token.text) {
+            return Ok(register);
+        }
+        let text = token.text.to_ascii_lowercase();
+        match text.strip_prefix('v') {
+            Some(digit) if digit.len() == 1 => Byte::from_str_radix(digit, 16)
+                .map_err(|_| self.error(token, format!("`{}` is not a register", token.text))),
+            _ => Err(self.error(token, format!("`{}` is not a register", token.text))),
+        }
+    }
+
+    fn next_register(&mut self) -> Result<Byte> {
+        let token = self.next()?;
+        self.register(&token)
+    }
+
+    fn operand(&self, token: &Token) -> Result<Operand> {
+        if let Ok(register) = self.register(token) {
+            return Ok(Operand::Register(register));
+        }
+        match self.value(token) {
+            Some(value) => Ok(Operand::Value(value as i64)),
+            None => Err(self.error(token, format!("unknown value `{}`", token.text))),
+        }
+    }
+
+    fn byte(&self, token: &Token, value: i64) -> Result<Byte> {
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(token, format!("{} doesn't fit into a byte", value)));
+        }
+        Ok(value as Byte)
+    }
+
+    // A number, constant or already defined label.
+    fn value(&self, token: &Token) -> Option<f64> {
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Some(value);
+        }
+        if let Some(&address) = self.labels.get(&token.text) {
+            return Some(address as f64);
+        }
+        parse_number(&token.text)
+    }
+
+    // A value or a `{ ... }` calculation.
+    fn number(&mut self) -> Result<f64> {
+        if self.peek() == Some("{") {
+            return self.calc();
+        }
+        let token = self.next()?;
+        self.value(&token)
+            .ok_or_else(|| self.error(&token, format!("unknown value `{}`", token.text)))
+    }
+
+    // `{ expression }` of `:calc`. Like in Octo, operators have no precedence
+    // and are evaluated from right to left, `2 * 3 + 1` is 8.
+    fn calc(&mut self) -> Result<f64> {
+        self.expect("{")?;
+        let value = self.expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn expression(&mut self) -> Result<f64> {
+        let lhs = self.term()?;
+        let Some(op) = self.peek() else {
+            return Ok(lhs);
+        };
+        let op = op.to_string();
+        let f: fn(f64, f64) -> f64 = match op.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| (a as i64).wrapping_shl(b as i64 as u32) as f64,
+            ">>" => |a, b| (a as i64).wrapping_shr(b as i64 as u32) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as i64 as f64,
+            ">" => |a, b| (a > b) as i64 as f64,
+            "<=" => |a, b| (a <= b) as i64 as f64,
+            ">=" => |a, b| (a >= b) as i64 as f64,
+            "==" => |a, b| (a == b) as i64 as f64,
+            "!=" => |a, b| (a != b) as i64 as f64,
+            _ => return Ok(lhs),
+        };
+        self.next()?;
+        let rhs = self.expression()?;
+        Ok(f(lhs, rhs))
+    }
+
+    fn term(&mut self) -> Result<f64> {
+        let token = self.next()?;
+        let f: fn(f64) -> f64 = match token.text.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                return Ok(value);
+            }
+            "-" => |a| -a,
+            "~" => |a| !(a as i64) as f64,
+            "!" => |a| (a == 0.0) as i64 as f64,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            _ => {
+                return self
+                    .value(&token)
+                    .ok_or_else(|| self.error(&token, format!("unknown value `{}`", token.text)))
+            }
+        };
+        Ok(f(self.term()?))
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod test {
+    use super::compile;
+    use crate::chip8::Chip8;
+    use crate::quirks::Platform;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source, "test.8o", Platform::CosmacVip).unwrap().rom
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = "
+            :alias x v1
+            :const SPEED 2
+            : main
+                i := sprite     # load the sprite
+                x := SPEED
+                x += vf
+                x -= 1
+                v2 := random 0x0F
+                sprite x v2 3
+                draw
+                jump main
+            : draw ;
+            : sprite
+                0b11100000 0xA0 224
+        ";
+        assert_eq!(
+            rom(source),
+            [
+                0xA2, 0x12, 0x61, 0x02, 0x81, 0xF4, 0x71, 0xFF, 0xC2, 0x0F, 0xD1, 0x23, 0x22, 0x10,
+                0x12, 0x00, 0x00, 0xEE, 0xE0, 0xA0, 0xE0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            : main
+                loop
+                    if v0 == 3 then v1 := 4
+                    while v2 != v3
+                    if v0 key begin
+                        clear
+                    else
+                        v0 += 1
+                    end
+                again
+        ";
+        assert_eq!(
+            rom(source),
+            [
+                0x40, 0x03, 0x61, 0x04, // if then
+                0x92, 0x30, 0x12, 0x14, // while
+                0xE0, 0x9E, 0x12, 0x10, 0x00, 0xE0, 0x12, 0x12, // if begin else
+                0x70, 0x01, // end
+                0x12, 0x00, // again
+            ]
+        );
+        let program = compile(source, "loop.8o", Platform::CosmacVip).unwrap();
+        assert_eq!(program.lines[&0x200], ("loop.8o".to_string(), 4));
+        assert_eq!(program.lines[&0x210], ("loop.8o".to_string(), 9));
+    }
+
+    #[test]
+    fn test_macros_and_calc() {
+        let source = "
+            :macro twice reg { reg += reg reg += reg }
+            :calc WIDTH { 2 * 3 + 1 }
+            jump start
+            : main
+            : start
+                twice v3
+                v0 := WIDTH
+                :unpack 0xA start
+        ";
+        assert_eq!(
+            rom(source),
+            [0x12, 0x04, 0x12, 0x04, 0x83, 0x34, 0x83, 0x34, 0x60, 0x08, 0x60, 0xA2, 0x61, 0x04]
+        );
+        // shift counts out of range wrap instead of panicking
+        assert_eq!(
+            rom(": main :calc A { 1 << 64 } :calc B { 8 >> -1 } v0 := A v1 := B"),
+            [0x60, 0x01, 0x61, 0x00]
+        );
+        // only the nesting of macros is limited, not how often they are used
+        let source = format!(":macro inc {{ v0 += 1 }} : main {}", "inc ".repeat(20_000));
+        assert_eq!(rom(&source).len(), 40_000);
+    }
+
+    #[test]
+    fn test_comparisons_run() {
+        // v2 counts the comparisons that hold for v0 = 5, v1 = 7
+        let source = "
+            : main
+                v0 := 5 v1 := 7
+                if v0 < v1 then v2 += 1
+                if v0 <= 5 then v2 += 1
+                if v1 > 6 then v2 += 1
+                if v1 >= v0 then v2 += 1
+                if v0 > v1 then v2 += 0x10
+                if v1 < 7 then v2 += 0x10
+                exit
+        ";
+        let program = compile(source, "test.8o", Platform::SuperChip).unwrap();
+        let mut chip8 = Chip8::with_platform(Platform::SuperChip);
+        chip8.load_bytes(&program.rom).unwrap();
+        while !chip8.halted() {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.register(2), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| {
+            compile(source, "test.8o", Platform::CosmacVip)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            "test.8o:2:8: undefined label `nowhere`"
+        );
+        assert_eq!(
+            error(": main\n v0 := 256"),
+            "test.8o:2:8: 256 doesn't fit into a byte"
+        );
+        assert_eq!(
+            error(": main hires"),
+            "test.8o:1:8: `hires` needs another platform than CosmacVip"
+        );
+        assert_eq!(error(": main loop"), "test.8o:1:8: `loop` without `again`");
+        assert_eq!(
+            error(": main v0 <<= 1"),
+            "test.8o:1:11: can't use `<<=` with `1`"
+        );
+        assert_eq!(
+            error(": main jump 0x10200"),
+            "test.8o:1:13: address 0x10200 is out of 0x000-0xFFF"
+        );
+        assert_eq!(
+            error(": main :call -1"),
+            "test.8o:1:14: address -1 is out of 0x000-0xFFF"
+        );
+        assert_eq!(
+            error(":macro m { m }\n: main m"),
+            "test.8o:1:12: the macro expands forever"
+        );
+        assert_eq!(
+            error(": main :org 0xFFFF :byte 1 : end"),
+            "test.8o:1:30: `end` is past the end of memory"
+        );
+    }
+}
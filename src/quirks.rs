@@ -0,0 +1,210 @@
+use super::error::Error;
+use super::Result;
+use std::str::FromStr;
+
+// The machines chip8 programs were written for.
+// Each of them resolves the ambiguous instructions in its own way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+// How FX55/FX65 change I after storing/loading the registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexIncrement {
+    // I += X + 1, like the COSMAC VIP
+    XPlusOne,
+    // I += X, like CHIP-48
+    X,
+    // I is left unchanged, like SUPER-CHIP
+    None,
+}
+
+// Behaviour of every instruction that differs between platforms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    // FX55/FX65 increment of I
+    pub index_increment: IndexIncrement,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    // BNNN behaves as BXNN, jumping to XNN + VX instead of NNN + V0
+    pub jump_vx: bool,
+    // sprites wrap around the edges of the screen instead of being clipped
+    pub wrap_sprites: bool,
+    // DXYN waits for the next frame, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    // memory accesses past the end of memory wrap around instead of faulting
+    pub memory_wrap: bool,
+}
+
+impl Platform {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks {
+                shift_vy: true,
+                index_increment: IndexIncrement::XPlusOne,
+                vf_reset: true,
+                jump_vx: false,
+                wrap_sprites: false,
+                display_wait: true,
+                memory_wrap: true,
+            },
+            Platform::Chip48 => Quirks {
+                shift_vy: false,
+                index_increment: IndexIncrement::X,
+                vf_reset: false,
+                jump_vx: true,
+                wrap_sprites: false,
+                display_wait: false,
+                memory_wrap: false,
+            },
+            Platform::SuperChip => Quirks {
+                shift_vy: false,
+                index_increment: IndexIncrement::None,
+                vf_reset: false,
+                jump_vx: true,
+                wrap_sprites: false,
+                display_wait: false,
+                memory_wrap: false,
+            },
+            Platform::XoChip => Quirks {
+                shift_vy: true,
+                index_increment: IndexIncrement::XPlusOne,
+                vf_reset: false,
+                jump_vx: false,
+                wrap_sprites: true,
+                display_wait: false,
+                memory_wrap: true,
+            },
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000,
+        }
+    }
+
+    // The usual instructions per frame for programs of this platform.
+    pub fn ipf(self) -> u32 {
+        match self {
+            Platform::CosmacVip => 11,
+            Platform::Chip48 | Platform::SuperChip => 30,
+            Platform::XoChip => 1000,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Ok(Platform::CosmacVip),
+            "chip48" | "chip-48" => Ok(Platform::Chip48),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(Error::Config(format!("unknown platform `{}`", s))),
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Platform::CosmacVip.quirks()
+    }
+}
+
+impl Quirks {
+    // Read a quirks config file on top of `self`.
+    // The file has one `key = value` per line, `#` starts a comment:
+    //
+    //     platform = schip
+    //     shift_vy = true
+    //     index_increment = x
+    //
+    // `platform` replaces every quirk with the preset, so it should come first.
+    pub fn apply_config(mut self, config: &str) -> Result<Quirks> {
+        for (number, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| {
+                    Error::Config(format!("line {}: expected `key = value`", number + 1))
+                })?;
+            let err = || {
+                Error::Config(format!(
+                    "line {}: bad value `{}` for `{}`",
+                    number + 1,
+                    value,
+                    key
+                ))
+            };
+            let flag = || value.parse::<bool>().map_err(|_| err());
+
+            match key {
+                "platform" => self = value.parse::<Platform>()?.quirks(),
+                "shift_vy" => self.shift_vy = flag()?,
+                "vf_reset" => self.vf_reset = flag()?,
+                "jump_vx" => self.jump_vx = flag()?,
+                "wrap_sprites" => self.wrap_sprites = flag()?,
+                "display_wait" => self.display_wait = flag()?,
+                "memory_wrap" => self.memory_wrap = flag()?,
+                "index_increment" => {
+                    self.index_increment = match value {
+                        "x+1" => IndexIncrement::XPlusOne,
+                        "x" => IndexIncrement::X,
+                        "none" => IndexIncrement::None,
+                        _ => return Err(err()),
+                    }
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "line {}: unknown quirk `{}`",
+                        number + 1,
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IndexIncrement, Platform, Quirks};
+
+    #[test]
+    fn test_config() {
+        let config = "
+            # start from SUPER-CHIP, but with VIP loads
+            platform = schip
+            index_increment = x+1
+            wrap_sprites = true # trailing comment
+        ";
+        let quirks = Quirks::default().apply_config(config).unwrap();
+        assert_eq!(quirks.index_increment, IndexIncrement::XPlusOne);
+        assert!(quirks.wrap_sprites);
+        assert!(quirks.jump_vx);
+        assert!(!quirks.shift_vy);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(Quirks::default().apply_config("shift_vy").is_err());
+        assert!(Quirks::default().apply_config("shift_vy = maybe").is_err());
+        assert!(Quirks::default().apply_config("colour = red").is_err());
+        assert!("gameboy".parse::<Platform>().is_err());
+    }
+}
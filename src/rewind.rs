@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+// A bounded history of machine snapshots, one per frame, to step back in time.
+//
+// Only the newest snapshot is kept in full. Every older frame is stored as the
+// difference to the frame after it: the XOR of both, run-length encoded.
+// Most of memory doesn't change from one frame to the next, so a delta is
+// mostly zeros and only takes a few bytes.
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // deltas[i] turns frame i + 1 back into frame i, the newest is at the back
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // keep at most `capacity` frames to go back to
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Record the snapshot of the newest frame.
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(encode(&snapshot, &latest));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    // Step back one frame and return its snapshot, or `None` if the history is used up.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_ref()?;
+        let older = decode(latest, &delta);
+        self.latest = Some(older.clone());
+        Some(older)
+    }
+
+    // number of frames that can be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // bytes used by the history, for tuning the capacity
+    pub fn size(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+// Delta that turns `from` into `to`:
+// the length of `to`, then runs of [zero bytes][literal bytes] of `from ^ to`.
+fn encode(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let len = from.len().max(to.len());
+    let xor: Vec<u8> = (0..len)
+        .map(|i| from.get(i).unwrap_or(&0) ^ to.get(i).unwrap_or(&0))
+        .collect();
+
+    let mut delta = Vec::new();
+    put_varint(&mut delta, to.len());
+    let mut i = 0;
+    while i < len {
+        let zeros = xor[i..].iter().take_while(|&&b| b == 0).count();
+        i += zeros;
+        let literal = xor[i..].iter().take_while(|&&b| b != 0).count();
+        put_varint(&mut delta, zeros);
+        put_varint(&mut delta, literal);
+        delta.extend_from_slice(&xor[i..i + literal]);
+        i += literal;
+    }
+    delta
+}
+
+fn decode(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let to_len = get_varint(delta, &mut pos);
+    let mut to = from.to_vec();
+    to.resize(from.len().max(to_len), 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += get_varint(delta, &mut pos);
+        let literal = get_varint(delta, &mut pos);
+        for byte in &delta[pos..pos + literal] {
+            to[i] ^= byte;
+            i += 1;
+        }
+        pos += literal;
+    }
+    to.truncate(to_len);
+    to
+}
+
+// LEB128: 7 bits per byte, the high bit marks that more bytes follow.
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Rewind;
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(2);
+        let frames = [
+            vec![0u8; 300],
+            vec![1u8; 300],
+            vec![1u8; 302],
+            vec![7u8; 10],
+        ];
+        for frame in &frames {
+            rewind.push(frame.clone());
+        }
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(rewind.pop().unwrap(), frames[2]);
+        assert_eq!(rewind.pop().unwrap(), frames[1]);
+        assert_eq!(rewind.pop(), None);
+
+        rewind.push(frames[3].clone());
+        assert_eq!(rewind.pop().unwrap(), frames[1]);
+    }
+
+    #[test]
+    fn test_delta_is_small() {
+        let mut rewind = Rewind::new(10);
+        let mut frame = vec![0u8; 0x1000];
+        for i in 0..10 {
+            frame[0x300 + i] = i as u8 + 1;
+            rewind.push(frame.clone());
+        }
+        assert!(rewind.size() < 0x1000 + 10 * 16);
+    }
+}
@@ -0,0 +1,178 @@
+use super::error::Error;
+use super::Result;
+
+// Save state file layout, all numbers big-endian like chip8 itself:
+//
+//     "C8ST"       magic
+//     u16          format version
+//     u32          crc32 of the rom the state belongs to
+//     u32          length of the payload
+//     [u8]         payload, the machine written by `Chip8::save_state`
+//     u32          crc32 of everything above
+const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 4 + 2 + 4 + 4;
+
+// Put the header and checksum around a payload.
+pub fn seal(rom_hash: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len() + 4);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_be_bytes());
+    data.extend_from_slice(&rom_hash.to_be_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(payload);
+    let checksum = crc32(&data);
+    data.extend_from_slice(&checksum.to_be_bytes());
+    data
+}
+
+// Check the header and checksum of a save state and return its payload.
+pub fn open(data: &[u8], rom_hash: u32) -> Result<&[u8]> {
+    let err = |msg: &str| Error::State(msg.to_string());
+    if data.len() < HEADER_LEN + 4 || &data[..4] != MAGIC {
+        return Err(err("not a save state"));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32(body).to_be_bytes() != checksum {
+        return Err(err("checksum mismatch, the file is damaged"));
+    }
+
+    let mut header = StateReader::new(&body[4..HEADER_LEN]);
+    let version = header.u16()?;
+    if version != VERSION {
+        return Err(Error::State(format!(
+            "unsupported version {}, expected {}",
+            version, VERSION
+        )));
+    }
+    if header.u32()? != rom_hash {
+        return Err(err("the state belongs to another rom"));
+    }
+    let len = header.u32()? as usize;
+    let payload = &body[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(err("payload has the wrong length"));
+    }
+    Ok(payload)
+}
+
+// CRC-32 (IEEE), used for the checksum and to identify roms.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::State("unexpected end of state".to_string()));
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(Error::State(format!("bad flag {}", value))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, open, seal};
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let data = seal(0x1234, b"payload");
+        assert_eq!(open(&data, 0x1234).unwrap(), b"payload");
+        assert!(open(&data, 0x4321).is_err());
+
+        let mut damaged = data.clone();
+        damaged[15] ^= 1;
+        assert!(open(&damaged, 0x1234).is_err());
+        assert!(open(&data[..10], 0x1234).is_err());
+    }
+}
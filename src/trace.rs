@@ -0,0 +1,771 @@
+use super::chip8::{Byte, Chip8, Word};
+use super::disasm::{self, Syntax};
+use super::error::Error;
+use super::instruction::{self, Class};
+use super::Result;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// Execution traces record the machine before every instruction.
+//
+// The text format is one line per instruction, in fixed columns:
+//
+//     00000012 PC=0204 OP=D015 DRW V0, V1, 5         V0=00 .. VF=00 I=0300 SP=00 DT=00 ST=00 D=1A2B3C4D
+//
+// the cycle number, PC, opcode, mnemonic, registers, stack depth, timers and the crc32
+// of the display. The binary format is a header and then fixed size records,
+// all numbers big-endian like chip8 itself:
+//
+//     "C8TR"       magic
+//     u16          format version
+//
+//     u64          cycle
+//     u16          PC
+//     u16          opcode
+//     [u8; 16]     V0-VF
+//     u16          I
+//     u8           SP
+//     u8           DT
+//     u8           ST
+//     u32          display hash
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u16 = 1;
+pub const RECORD_LEN: usize = 8 + 2 + 2 + 16 + 2 + 3 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "binary" | "bin" => Ok(Format::Binary),
+            _ => Err(Error::Config(format!("unknown trace format `{}`", s))),
+        }
+    }
+}
+
+// The machine right before one instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub cycle: u64,
+    pub pc: Word,
+    pub opcode: Word,
+    pub registers: [Byte; 16],
+    pub i: Word,
+    pub sp: Byte,
+    pub dt: Byte,
+    pub st: Byte,
+    pub display: u32,
+}
+
+impl Record {
+    pub fn line(&self, mnemonic: &str) -> String {
+        let mut line = format!(
+            "{:08} PC={:04X} OP={:04X} {:<20}",
+            self.cycle, self.pc, self.opcode, mnemonic
+        );
+        for (x, v) in self.registers.iter().enumerate() {
+            line.push_str(&format!(" V{:X}={:02X}", x, v));
+        }
+        line.push_str(&format!(
+            " I={:04X} SP={:02X} DT={:02X} ST={:02X} D={:08X}",
+            self.i, self.sp, self.dt, self.st, self.display
+        ));
+        line
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut data = [0; RECORD_LEN];
+        data[0..8].copy_from_slice(&self.cycle.to_be_bytes());
+        data[8..10].copy_from_slice(&self.pc.to_be_bytes());
+        data[10..12].copy_from_slice(&self.opcode.to_be_bytes());
+        data[12..28].copy_from_slice(&self.registers);
+        data[28..30].copy_from_slice(&self.i.to_be_bytes());
+        data[30] = self.sp;
+        data[31] = self.dt;
+        data[32] = self.st;
+        data[33..37].copy_from_slice(&self.display.to_be_bytes());
+        data
+    }
+}
+
+// Writes a trace of every instruction the machine executes, see `Chip8::set_tracer`.
+// Filters leave out instructions outside an address range or of other classes,
+// the cycle numbers keep counting all of them.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    range: Option<RangeInclusive<Word>>,
+    classes: Option<Vec<Class>>,
+    cycle: u64,
+    // hash of the display, recomputed only after display instructions
+    display: Option<u32>,
+    // the first write error, reported by `finish`
+    error: Option<std::io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: Format) -> Self {
+        let mut tracer = Tracer {
+            out: Box::new(out),
+            format,
+            range: None,
+            classes: None,
+            cycle: 0,
+            display: None,
+            error: None,
+        };
+        if format == Format::Binary {
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&VERSION.to_be_bytes());
+            tracer.error = tracer.out.write_all(&header).err();
+        }
+        tracer
+    }
+
+    // Write the trace to a new file at `path`.
+    pub fn create(path: &str, format: Format) -> Result<Self> {
+        let file = File::create(path).map_err(|e| Error::Trace(format!("{}: {}", path, e)))?;
+        Ok(Tracer::new(BufWriter::new(file), format))
+    }
+
+    // only trace instructions at these addresses
+    pub fn set_range(&mut self, range: Option<RangeInclusive<Word>>) {
+        self.range = range;
+    }
+
+    // only trace instructions of these classes, illegal opcodes are always traced
+    pub fn set_classes(&mut self, classes: Option<Vec<Class>>) {
+        self.classes = classes;
+    }
+
+    // The display may have changed behind the tracer's back, e.g. by loading a state.
+    pub fn screen_changed(&mut self) {
+        self.display = None;
+    }
+
+    // Record the machine before it executes the instruction at PC.
+    pub fn record(&mut self, chip8: &Chip8) {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if self.error.is_some() {
+            return;
+        }
+
+        let pc = chip8.pc();
+        let memory = chip8.memory();
+        let word = |address: usize| {
+            let byte = |address: usize| memory.get(address).copied().unwrap_or(0) as Word;
+            byte(address) << 8 | byte(address + 1)
+        };
+        let opcode = word(pc as usize);
+        let instruction = instruction::decode(opcode, chip8.platform()).ok();
+        let class = instruction.map(|instruction| instruction.class());
+        let display = *self.display.get_or_insert_with(|| chip8.display_hash());
+        if class == Some(Class::Display) {
+            self.display = None;
+        }
+        if self
+            .range
+            .as_ref()
+            .is_some_and(|range| !range.contains(&pc))
+        {
+            return;
+        }
+        if let (Some(classes), Some(class)) = (&self.classes, class) {
+            if !classes.contains(&class) {
+                return;
+            }
+        }
+
+        let stack = chip8.stack();
+        let record = Record {
+            cycle,
+            pc,
+            opcode,
+            registers: std::array::from_fn(|x| chip8.register(x)),
+            i: chip8.index(),
+            sp: stack.len() as Byte,
+            dt: chip8.delay_timer(),
+            st: chip8.sound_timer(),
+            display,
+        };
+        let result = match self.format {
+            Format::Text => {
+                let mnemonic = match instruction {
+                    Some(instruction) => disasm::mnemonic(
+                        instruction,
+                        Syntax::Cowgod,
+                        &BTreeMap::new(),
+                        word(pc as usize + 2),
+                    ),
+                    None => format!("DW 0x{:04X}", opcode),
+                };
+                writeln!(self.out, "{}", record.line(&mnemonic))
+            }
+            Format::Binary => self.out.write_all(&record.to_bytes()),
+        };
+        self.error = result.err();
+    }
+
+    // Flush the trace and report the first error writing it.
+    pub fn finish(mut self) -> Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+        .map_err(|e| Error::Trace(e.to_string()))
+    }
+}
+
+// Parse an address range like `0x200-0x2FF`, both ends included.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<Word>> {
+    let address = |s: &str| {
+        let s = s.trim();
+        let digits = s
+            .strip_prefix("0x")
+            .or_else(|| s.strip_prefix("0X"))
+            .unwrap_or(s);
+        Word::from_str_radix(digits, 16)
+            .map_err(|_| Error::Config(format!("bad address `{}` in range", s)))
+    };
+    match s.split_once('-') {
+        Some((start, end)) => Ok(address(start)?..=address(end)?),
+        None => Err(Error::Config(format!("range `{}` is not START-END", s))),
+    }
+}
+
+// A field of a trace entry that two traces are compared on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Pc,
+    Opcode,
+    V(usize),
+    I,
+    Sp,
+    Dt,
+    St,
+    Display,
+}
+
+impl Field {
+    // every field, in the order they are compared
+    pub fn all() -> Vec<Field> {
+        let mut fields = vec![Field::Pc, Field::Opcode];
+        fields.extend((0..16).map(Field::V));
+        fields.extend([Field::I, Field::Sp, Field::Dt, Field::St, Field::Display]);
+        fields
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Field::Pc => "PC".to_string(),
+            Field::Opcode => "OP".to_string(),
+            Field::V(x) => format!("V{:X}", x),
+            Field::I => "I".to_string(),
+            Field::Sp => "SP".to_string(),
+            Field::Dt => "DT".to_string(),
+            Field::St => "ST".to_string(),
+            Field::Display => "display".to_string(),
+        }
+    }
+
+    // hex digits to print the field with
+    fn width(&self) -> usize {
+        match self {
+            Field::Pc | Field::Opcode | Field::I => 4,
+            Field::Display => 8,
+            _ => 2,
+        }
+    }
+}
+
+// Parse a comma separated list of fields. `v` stands for all registers, `timers` for DT and ST.
+pub fn parse_fields(s: &str) -> Result<Vec<Field>> {
+    let mut fields = Vec::new();
+    for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match name.to_ascii_lowercase().as_str() {
+            "pc" => fields.push(Field::Pc),
+            "op" | "opcode" => fields.push(Field::Opcode),
+            "v" | "registers" => fields.extend((0..16).map(Field::V)),
+            "i" => fields.push(Field::I),
+            "sp" | "stack" => fields.push(Field::Sp),
+            "dt" => fields.push(Field::Dt),
+            "st" => fields.push(Field::St),
+            "timers" => fields.extend([Field::Dt, Field::St]),
+            "display" | "d" => fields.push(Field::Display),
+            lower => match lower
+                .strip_prefix('v')
+                .map(|x| usize::from_str_radix(x, 16))
+            {
+                Some(Ok(x)) if x < 16 => fields.push(Field::V(x)),
+                _ => return Err(Error::Config(format!("unknown trace field `{}`", name))),
+            },
+        }
+    }
+    Ok(fields)
+}
+
+// One instruction of a trace read back, by us or another emulator.
+// Other emulators log less, so everything but PC is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    // line number in a text trace, record number in a binary one
+    pub line: usize,
+    pub text: String,
+    pub cycle: Option<u64>,
+    pub pc: Word,
+    pub opcode: Option<Word>,
+    pub registers: [Option<Byte>; 16],
+    pub i: Option<Word>,
+    pub sp: Option<Byte>,
+    pub dt: Option<Byte>,
+    pub st: Option<Byte>,
+    pub display: Option<u32>,
+}
+
+impl Entry {
+    fn get(&self, field: Field) -> Option<u32> {
+        match field {
+            Field::Pc => Some(self.pc as u32),
+            Field::Opcode => self.opcode.map(u32::from),
+            Field::V(x) => self.registers[x].map(u32::from),
+            Field::I => self.i.map(u32::from),
+            Field::Sp => self.sp.map(u32::from),
+            Field::Dt => self.dt.map(u32::from),
+            Field::St => self.st.map(u32::from),
+            Field::Display => self.display,
+        }
+    }
+}
+
+// Read a trace written by `Tracer`, in either format, or a text trace of another emulator.
+//
+// Text lines are searched for `NAME=VALUE` or `NAME: VALUE` pairs in any order, with
+// names like PC, OP, V0-VF, I, SP, DT, ST and hexadecimal values. `V: 00 01 ..` lists
+// all registers at once, a line starting with `0200:` gives the PC and opcode, and a
+// leading decimal number the cycle. Lines without a PC, like headers, are skipped.
+pub fn read_trace(data: &[u8]) -> Result<Vec<Entry>> {
+    if let Some(records) = data.strip_prefix(MAGIC.as_slice()) {
+        return read_binary(records);
+    }
+    let text = String::from_utf8_lossy(data);
+    Ok(text
+        .lines()
+        .enumerate()
+        .filter_map(|(n, line)| {
+            parse_line(line).map(|entry| Entry {
+                line: n + 1,
+                ..entry
+            })
+        })
+        .collect())
+}
+
+fn read_binary(data: &[u8]) -> Result<Vec<Entry>> {
+    let bad = |what: &str| Error::Trace(format!("bad binary trace: {}", what));
+    let version = data.get(..2).ok_or_else(|| bad("no version"))?;
+    if version != VERSION.to_be_bytes() {
+        return Err(bad("unknown version"));
+    }
+    let records = &data[2..];
+    if !records.len().is_multiple_of(RECORD_LEN) {
+        return Err(bad("truncated record"));
+    }
+    let entries = records
+        .chunks(RECORD_LEN)
+        .enumerate()
+        .map(|(n, data)| {
+            let u16_at = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+            let record = Record {
+                cycle: u64::from_be_bytes(data[0..8].try_into().expect("8 bytes")),
+                pc: u16_at(8),
+                opcode: u16_at(10),
+                registers: data[12..28].try_into().expect("16 bytes"),
+                i: u16_at(28),
+                sp: data[30],
+                dt: data[31],
+                st: data[32],
+                display: u32::from_be_bytes(data[33..37].try_into().expect("4 bytes")),
+            };
+            Entry {
+                line: n + 1,
+                text: record.line(""),
+                cycle: Some(record.cycle),
+                pc: record.pc,
+                opcode: Some(record.opcode),
+                registers: record.registers.map(Some),
+                i: Some(record.i),
+                sp: Some(record.sp),
+                dt: Some(record.dt),
+                st: Some(record.st),
+                display: Some(record.display),
+            }
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn hex(s: &str) -> Option<u32> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .or_else(|| s.strip_prefix('$'))
+        .or_else(|| s.strip_prefix('#'))
+        .unwrap_or(s);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
+        return None;
+    }
+    let tokens: Vec<&str> = trimmed
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '|' | '[' | ']' | '(' | ')'))
+        .filter(|token| !token.is_empty())
+        .collect();
+    let mut entry = Entry {
+        text: line.trim_end().to_string(),
+        ..Entry::default()
+    };
+    let mut pc = None;
+
+    let mut at = 0;
+    while at < tokens.len() {
+        let token = tokens[at];
+        at += 1;
+        let (name, value) = match token.split_once(['=', ':']) {
+            // `NAME: VALUE` with the value in the next token
+            Some((name, "")) => match tokens.get(at) {
+                Some(_) if name.eq_ignore_ascii_case("v") => (name, ""),
+                Some(value) if hex(value).is_some() || value.parse::<u64>().is_ok() => {
+                    at += 1;
+                    (name, *value)
+                }
+                _ => continue,
+            },
+            Some((name, value)) => (name, value),
+            None => continue,
+        };
+        match name.to_ascii_uppercase().as_str() {
+            "CYCLE" | "CYC" | "STEP" => entry.cycle = value.parse().ok(),
+            "PC" => pc = hex(value),
+            "OP" | "OPCODE" => entry.opcode = hex(value).map(|op| op as Word),
+            "I" | "INDEX" => entry.i = hex(value).map(|i| i as Word),
+            "SP" => entry.sp = hex(value).map(|sp| sp as Byte),
+            "DT" | "DELAY" => entry.dt = hex(value).map(|dt| dt as Byte),
+            "ST" | "SOUND" => entry.st = hex(value).map(|st| st as Byte),
+            "D" | "DISPLAY" | "HASH" => entry.display = hex(value),
+            // all registers in a row
+            "V" => {
+                let mut values: Vec<u32> = hex(value).into_iter().collect();
+                while let Some(value) = tokens.get(at).and_then(|value| hex(value)) {
+                    if values.len() == 16 {
+                        break;
+                    }
+                    values.push(value);
+                    at += 1;
+                }
+                for (x, value) in values.into_iter().enumerate() {
+                    entry.registers[x] = Some(value as Byte);
+                }
+            }
+            upper => {
+                let register = upper
+                    .strip_prefix('V')
+                    .and_then(|x| usize::from_str_radix(x, 16).ok());
+                if let Some(x) = register.filter(|&x| x < 16) {
+                    entry.registers[x] = hex(value).map(|v| v as Byte);
+                }
+            }
+        }
+    }
+
+    // `0200: 6001 ...` and `00000012 PC=...`
+    if let Some(first) = tokens.first() {
+        if let Some(address) = first.strip_suffix(':').and_then(hex) {
+            if pc.is_none() {
+                pc = Some(address);
+                entry.opcode = entry
+                    .opcode
+                    .or(tokens.get(1).and_then(|op| hex(op)).map(|op| op as Word));
+            }
+        } else if entry.cycle.is_none() {
+            entry.cycle = first.parse().ok();
+        }
+    }
+    entry.pc = pc? as Word;
+    Some(entry)
+}
+
+// Where two traces first differ: the entries of both and the fields that differ.
+pub struct Divergence<'a> {
+    pub a: &'a Entry,
+    pub b: &'a Entry,
+    pub fields: Vec<Field>,
+    // up to `context` pairs of entries leading to the divergence
+    pub before: Vec<(&'a Entry, &'a Entry)>,
+    // pairs compared before the divergence
+    pub compared: usize,
+}
+
+impl Divergence<'_> {
+    pub fn report(&self, name_a: &str, name_b: &str) -> String {
+        let mut report = format!(
+            "first divergence after {} matching instructions, {}:{} and {}:{}",
+            self.compared, name_a, self.a.line, name_b, self.b.line
+        );
+        if let Some(cycle) = self.a.cycle.or(self.b.cycle) {
+            report.push_str(&format!(", cycle {}", cycle));
+        }
+        report.push('\n');
+        for field in &self.fields {
+            let width = field.width();
+            let value = |entry: &Entry| match entry.get(*field) {
+                Some(value) => format!("{:0width$X}", value, width = width),
+                None => "-".to_string(),
+            };
+            report.push_str(&format!(
+                "  {:<8}{} != {}\n",
+                field.name(),
+                value(self.a),
+                value(self.b)
+            ));
+        }
+        for (name, side) in [(name_a, 0), (name_b, 1)] {
+            report.push_str(&format!("\n{}:\n", name));
+            for pair in &self.before {
+                let entry = if side == 0 { pair.0 } else { pair.1 };
+                report.push_str(&format!("    {}\n", entry.text.trim()));
+            }
+            let entry = if side == 0 { self.a } else { self.b };
+            report.push_str(&format!("  > {}\n", entry.text.trim()));
+        }
+        report
+    }
+}
+
+// Compare two traces instruction by instruction, on every field both of them have
+// except the `ignored` ones. If both traces number their cycles, entries are matched
+// by cycle, so a filtered trace can be compared with a full one. Otherwise they are
+// matched in order. Returns the first divergence, and how many pairs matched.
+pub fn diff<'a>(
+    a: &'a [Entry],
+    b: &'a [Entry],
+    ignored: &[Field],
+    context: usize,
+) -> (Option<Divergence<'a>>, usize) {
+    let fields: Vec<Field> = Field::all()
+        .into_iter()
+        .filter(|field| !ignored.contains(field))
+        .collect();
+    let by_cycle = a.iter().chain(b).all(|entry| entry.cycle.is_some());
+    let mut before: Vec<(&Entry, &Entry)> = Vec::new();
+    let mut compared = 0;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (x, y) = (&a[i], &b[j]);
+        if by_cycle && x.cycle != y.cycle {
+            if x.cycle < y.cycle {
+                i += 1;
+            } else {
+                j += 1;
+            }
+            continue;
+        }
+        let differences: Vec<Field> = fields
+            .iter()
+            .copied()
+            .filter(|&field| match (x.get(field), y.get(field)) {
+                (Some(u), Some(v)) => u != v,
+                _ => false,
+            })
+            .collect();
+        if !differences.is_empty() {
+            let divergence = Divergence {
+                a: x,
+                b: y,
+                fields: differences,
+                before,
+                compared,
+            };
+            return (Some(divergence), compared);
+        }
+        before.push((x, y));
+        if before.len() > context {
+            before.remove(0);
+        }
+        compared += 1;
+        i += 1;
+        j += 1;
+    }
+    (None, compared)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, parse_fields, parse_range, read_trace, Field, Format, Tracer, RECORD_LEN};
+    use crate::chip8::Chip8;
+    use crate::instruction::Class;
+
+    // 0x200: V0 = 5, V1 = 3, V0 += V1, I = 0x300, jump 0x208
+    const ROM: [u8; 10] = [0x60, 0x05, 0x61, 0x03, 0x80, 0x14, 0xA3, 0x00, 0x12, 0x08];
+
+    fn trace(name: &str, format: Format, filter: impl FnOnce(&mut Tracer)) -> Vec<u8> {
+        let path =
+            std::env::temp_dir().join(format!("chip8_trace_{}_{}", std::process::id(), name));
+        let path = path.to_str().unwrap();
+        let mut tracer = Tracer::create(path, format).unwrap();
+        filter(&mut tracer);
+        let mut chip8 = Chip8::new();
+        chip8.load_bytes(&ROM).unwrap();
+        chip8.set_tracer(Some(tracer));
+        chip8.run_frame(6).unwrap();
+        chip8.take_tracer().unwrap().finish().unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let text = String::from_utf8(trace("text", Format::Text, |_| ())).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(
+            lines[2].starts_with("00000002 PC=0204 OP=8014 ADD V0, V1           V0=05 V1=03 V2=00")
+        );
+        assert!(lines[3].contains(" V0=08 V1=03 "));
+        assert!(lines[3].contains(" I=0000 SP=00 DT=00 ST=00 D="));
+        assert!(lines[5].starts_with("00000005 PC=0208 OP=1208 JP 0x208"));
+
+        // only ALU instructions at 0x202 and later
+        let text = trace("filtered", Format::Text, |tracer| {
+            tracer.set_range(Some(parse_range("0x202-0x2FF").unwrap()));
+            tracer.set_classes(Some(vec![Class::Alu]));
+        });
+        let text = String::from_utf8(text).unwrap();
+        let cycles: Vec<&str> = text.lines().map(|line| &line[..8]).collect();
+        assert_eq!(cycles, ["00000001", "00000002"]);
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let data = trace("binary", Format::Binary, |_| ());
+        assert_eq!(&data[..6], b"C8TR\x00\x01");
+        assert_eq!(data.len(), 6 + 6 * RECORD_LEN);
+        let record = &data[6 + 3 * RECORD_LEN..6 + 4 * RECORD_LEN];
+        assert_eq!(&record[..8], &3u64.to_be_bytes());
+        assert_eq!(&record[8..12], &[0x02, 0x06, 0xA3, 0x00]);
+        assert_eq!(&record[12..14], &[0x08, 0x03]);
+
+        let entries = read_trace(&data).unwrap();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[3].cycle, Some(3));
+        assert_eq!(entries[3].pc, 0x206);
+        assert_eq!(entries[3].registers[0], Some(0x08));
+    }
+
+    #[test]
+    fn test_read_trace() {
+        let ours = String::from_utf8(trace("read", Format::Text, |_| ())).unwrap();
+        let entries = read_trace(ours.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[2].cycle, Some(2));
+        assert_eq!(entries[2].pc, 0x204);
+        assert_eq!(entries[2].opcode, Some(0x8014));
+        assert_eq!(entries[3].registers[0], Some(0x08));
+        assert_eq!(entries[3].registers[15], Some(0x00));
+        assert_eq!(entries[3].sp, Some(0));
+        assert!(entries[3].display.is_some());
+
+        // traces of other emulators
+        let other = "\
+# header
+PC: 0x0204 I: 0000 V0: 05 V1: 03
+pc=$206 op=A300 v: 08 03 00 00 00 00 00 00 00 00 00 00 00 00 00 07 dt=3
+0208: 1208 V=[08, 03, 00] I=0300
+";
+        let entries = read_trace(other.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].line, 2);
+        assert_eq!(entries[0].pc, 0x204);
+        assert_eq!(entries[0].cycle, None);
+        assert_eq!(entries[0].registers[1], Some(3));
+        assert_eq!(entries[0].registers[2], None);
+        assert_eq!(entries[1].opcode, Some(0xA300));
+        assert_eq!(entries[1].registers[15], Some(7));
+        assert_eq!(entries[1].dt, Some(3));
+        assert_eq!(entries[2].pc, 0x208);
+        assert_eq!(entries[2].opcode, Some(0x1208));
+        assert_eq!(entries[2].registers[..3], [Some(8), Some(3), Some(0)]);
+        assert_eq!(entries[2].i, Some(0x300));
+    }
+
+    #[test]
+    fn test_diff() {
+        let ours = trace("diff", Format::Text, |_| ());
+        let a = read_trace(&ours).unwrap();
+        let (divergence, compared) = diff(&a, &a, &[], 2);
+        assert!(divergence.is_none());
+        assert_eq!(compared, 6);
+
+        // another emulator that got ADD wrong, without cycle numbers
+        let other = "\
+PC=0200 V0=00 V1=00
+PC=0202 V0=05 V1=00
+PC=0204 V0=05 V1=03
+PC=0206 V0=09 V1=03
+";
+        let b = read_trace(other.as_bytes()).unwrap();
+        let (divergence, _) = diff(&a, &b, &[], 2);
+        let divergence = divergence.unwrap();
+        assert_eq!(divergence.a.pc, 0x206);
+        assert_eq!(divergence.fields, [Field::V(0)]);
+        assert_eq!(divergence.before.len(), 2);
+        let report = divergence.report("a.txt", "b.txt");
+        assert!(report.starts_with(
+            "first divergence after 3 matching instructions, a.txt:4 and b.txt:4, cycle 3\n  V0      08 != 09\n"
+        ));
+        assert!(report
+            .contains("b.txt:\n    PC=0202 V0=05 V1=00\n    PC=0204 V0=05 V1=03\n  > PC=0206"));
+        assert!(diff(&a, &b, &parse_fields("v0").unwrap(), 2).0.is_none());
+
+        // a filtered trace is matched by cycle
+        let filtered = trace("diff_filtered", Format::Text, |tracer| {
+            tracer.set_range(Some(0x204..=0x206));
+        });
+        let b = read_trace(&filtered).unwrap();
+        let (divergence, compared) = diff(&a, &b, &[], 2);
+        assert!(divergence.is_none());
+        assert_eq!(compared, 2);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(
+            parse_fields("pc, vA,timers").unwrap(),
+            [Field::Pc, Field::V(10), Field::Dt, Field::St]
+        );
+        assert_eq!(parse_fields("v").unwrap().len(), 16);
+        assert!(parse_fields("pc,vz").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x200-0x2ff").unwrap(), 0x200..=0x2FF);
+        assert_eq!(parse_range("300 - 310").unwrap(), 0x300..=0x310);
+        assert!(parse_range("0x200").is_err());
+        assert!(parse_range("0x200-zz").is_err());
+    }
+}
@@ -0,0 +1,501 @@
+// The SDL frontend of the emulator: the window, keyboard and buzzer,
+// and the debuggers that drive the machine in it.
+use chip8_emulator::chip8::{Chip8, HIRES_HEIGHT, HIRES_WIDTH, PALETTE};
+use chip8_emulator::dap::DapServer;
+use chip8_emulator::debugger::Debugger;
+use chip8_emulator::error::Error;
+use chip8_emulator::gdb::GdbStub;
+use chip8_emulator::quirks::Platform;
+use chip8_emulator::rewind::Rewind;
+use chip8_emulator::Result;
+use clap::ArgMatches;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use sdl2::EventPump;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 320;
+
+// Length of one 60Hz frame.
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// The `=` hotkey doubles ipf up to this, a frame has to stay short enough
+// to keep handling events.
+const MAX_IPF: u32 = 5000;
+
+const SAMPLE_RATE: i32 = 44100;
+// Time for the buzzer to fade in or out, short enough to not be heard
+// but long enough to avoid clicks.
+const FADE_SECONDS: f32 = 0.005;
+
+// Frontend settings that can be changed while running.
+struct Settings {
+    ipf: u32,
+    muted: bool,
+    // save state slots are stored next to the rom
+    rom_path: String,
+    // Backspace is held, frames run backwards through the rewind history
+    rewinding: bool,
+    // stopped in the debugger
+    paused: bool,
+    // Escape was pressed or the window closed, leave the loop so the trace is flushed
+    quit: bool,
+}
+
+// Debuggers drive the machine in place of the plain frame loop.
+// They stop at faults instead of the emulator.
+enum Control {
+    // --debug, commands read from the terminal
+    Repl(Debugger, Receiver<String>),
+    // --gdb, a remote debugger over TCP
+    Gdb(GdbStub),
+    // --dap, an editor over the Debug Adapter Protocol
+    Dap(DapServer),
+}
+
+impl Control {
+    // Run one frame unless the debugger holds the machine, returns whether it ran.
+    fn run_frame(&mut self, chip8: &mut Chip8, ipf: u32) -> bool {
+        match self {
+            Control::Repl(debugger, _) => {
+                if debugger.paused() {
+                    return false;
+                }
+                if let Some(report) = debugger.run_frame(chip8, ipf) {
+                    print_output(&report);
+                    prompt();
+                }
+                true
+            }
+            Control::Gdb(gdb) => gdb.run_frame(chip8, ipf),
+            Control::Dap(dap) => dap.run_frame(chip8, ipf),
+        }
+    }
+
+    fn paused(&self) -> bool {
+        match self {
+            Control::Repl(debugger, _) => debugger.paused(),
+            Control::Gdb(gdb) => gdb.stopped(),
+            Control::Dap(dap) => dap.stopped(),
+        }
+    }
+
+    // whether the debugger ended the emulator
+    fn quitting(&self) -> bool {
+        match self {
+            Control::Repl(debugger, _) => debugger.quitting(),
+            Control::Gdb(gdb) => gdb.quitting(),
+            Control::Dap(dap) => dap.quitting(),
+        }
+    }
+}
+
+// The buzzer, played by SDL on its audio thread.
+// It plays a square wave of `tone` Hz, or the XO-CHIP audio pattern if one is set,
+// and fades between silence and `volume` instead of switching abruptly.
+struct Buzzer {
+    tone: f32,
+    volume: f32,
+    // whether the sound timer is running and the buzzer is not muted
+    playing: bool,
+    // XO-CHIP audio pattern and its playback rate in bits per second
+    pattern: Option<([u8; 16], f32)>,
+    phase: f32,
+    level: f32,
+}
+
+impl AudioCallback for Buzzer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let fade = self.volume / (FADE_SECONDS * SAMPLE_RATE as f32);
+        let target = if self.playing { self.volume } else { 0.0 };
+        for sample in out.iter_mut() {
+            if self.level < target {
+                self.level = (self.level + fade).min(target);
+            } else {
+                self.level = (self.level - fade).max(target);
+            }
+
+            let high = match self.pattern {
+                Some((pattern, rate)) => {
+                    self.phase = (self.phase + rate / SAMPLE_RATE as f32) % 128.0;
+                    let bit = self.phase as usize;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                None => {
+                    self.phase = (self.phase + self.tone / SAMPLE_RATE as f32) % 1.0;
+                    self.phase < 0.5
+                }
+            };
+            *sample = if high { self.level } else { -self.level };
+        }
+    }
+}
+
+// Run the machine in a window with the frontend options of `app`,
+// until the program exits or the window is closed.
+pub fn play(app: &ArgMatches, chip8: &mut Chip8, ipf: u32, path: Option<String>) -> Result<()> {
+    let buzzer = Buzzer {
+        tone: *app.get_one::<f32>("tone").expect("default"),
+        volume: *app.get_one::<u8>("volume").expect("default") as f32 / 100.0,
+        playing: false,
+        pattern: None,
+        phase: 0.0,
+        level: 0.0,
+    };
+    let settings = Settings {
+        ipf,
+        muted: app.get_flag("mute"),
+        rom_path: path.unwrap_or_else(|| "chip8".to_string()),
+        rewinding: false,
+        paused: false,
+        quit: false,
+    };
+    let rewind = Rewind::new(*app.get_one::<u32>("rewind").expect("default") as usize * 60);
+
+    let control = if app.get_flag("debug") {
+        Some(Control::Repl(Debugger::new(), read_commands()))
+    } else if let Some(&port) = app.get_one::<u16>("gdb") {
+        let gdb = GdbStub::bind(("127.0.0.1", port))?;
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        Some(Control::Gdb(gdb))
+    } else if let Some(&port) = app.get_one::<u16>("dap") {
+        let dap = DapServer::bind(("127.0.0.1", port))?;
+        println!("waiting for a DAP client on 127.0.0.1:{}", port);
+        Some(Control::Dap(dap))
+    } else {
+        None
+    };
+
+    run(chip8, settings, buzzer, rewind, control)
+}
+
+fn run(
+    chip8: &mut Chip8,
+    mut settings: Settings,
+    buzzer: Buzzer,
+    mut rewind: Rewind,
+    mut control: Option<Control>,
+) -> Result<()> {
+    let sdl2_context = sdl2::init().map_err(Error::SdlError)?;
+    let audio_subsystem = sdl2_context.audio().map_err(Error::SdlError)?;
+    let desired = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(1),
+        samples: None,
+    };
+    let mut audio = audio_subsystem
+        .open_playback(None, &desired, |_| buzzer)
+        .map_err(Error::SdlError)?;
+    audio.resume();
+
+    let video_subsystem = sdl2_context.video().map_err(Error::SdlError)?;
+    let window = video_subsystem
+        .window("chip8_emulator", WINDOW_WIDTH, WINDOW_HEIGHT)
+        .position_centered()
+        .build()?;
+
+    let mut canvas = window.into_canvas().build()?;
+    let texture_creator = canvas.texture_creator();
+    // big enough for hi-res, lo-res only uses the top left part
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            HIRES_WIDTH as u32,
+            HIRES_HEIGHT as u32,
+        )
+        .map_err(|e| Error::SdlError(e.to_string()))?;
+    let mut event_pump = sdl2_context.event_pump().map_err(Error::SdlError)?;
+
+    let mut next_frame = Instant::now();
+    let mut title = String::new();
+    // set when the machine faulted, it then stays paused until it is rewound
+    let mut fault: Option<Error> = None;
+    rewind.push(chip8.save_state());
+    if let Some(Control::Repl(debugger, _)) = control.as_mut() {
+        println!("{}", debugger.command(chip8, "list"));
+        prompt();
+    }
+
+    while !chip8.halted() {
+        let mut redraw = key_event(&mut event_pump, chip8, &mut settings);
+        if settings.quit {
+            return Ok(());
+        }
+
+        if let Some(Control::Repl(debugger, commands)) = control.as_mut() {
+            // commands are also taken while running, e.g. to set a breakpoint or pause
+            for line in commands.try_iter() {
+                print_output(&debugger.command(chip8, &line));
+                if debugger.quitting() {
+                    return Ok(());
+                }
+                if debugger.paused() {
+                    prompt();
+                }
+                redraw = true;
+            }
+        }
+        if settings.rewinding {
+            // one frame back per frame, so rewinding plays at normal speed
+            if let Some(snapshot) = rewind.pop() {
+                chip8.load_state(&snapshot)?;
+                fault = None;
+            }
+        } else if let Some(control) = control.as_mut() {
+            if control.run_frame(chip8, settings.ipf) {
+                rewind.push(chip8.save_state());
+            }
+            settings.paused = control.paused();
+            if control.quitting() {
+                return Ok(());
+            }
+        } else if fault.is_none() {
+            if let Err(e) = chip8.run_frame(settings.ipf) {
+                eprintln!("{}", e);
+                fault = Some(e);
+            }
+            if let Some(e) = chip8.take_fault() {
+                eprintln!("skipped: {}", e);
+            }
+            rewind.push(chip8.save_state());
+        }
+        let running = fault.is_none() && !settings.rewinding && !settings.paused;
+        update_buzzer(&mut audio, chip8, &settings, running);
+        if chip8.take_draw_flag() || redraw {
+            canvas_draw(&mut canvas, &mut texture, chip8)?;
+        }
+        let status = status(chip8, &settings, fault.as_ref());
+        if status != title {
+            canvas
+                .window_mut()
+                .set_title(&status)
+                .map_err(|e| Error::SdlError(e.to_string()))?;
+            title = status;
+        }
+        wait_for_frame(&mut next_frame);
+    }
+    Ok(())
+}
+
+// Read debugger commands from the terminal on a thread of their own,
+// so the window keeps running while waiting for input.
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines().map_while(|line| line.ok()) {
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+        // end of input quits, like closing a debugger
+        let _ = sender.send("quit".to_string());
+    });
+    receiver
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = std::io::stdout().flush();
+}
+
+fn print_output(output: &str) {
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+}
+
+// Sleep until the deadline of the next frame.
+// Deadlines advance by exactly one frame, so sleeping too long in one frame
+// is made up in the next one instead of drifting.
+fn wait_for_frame(next_frame: &mut Instant) {
+    *next_frame += FRAME;
+    let now = Instant::now();
+    if *next_frame > now {
+        std::thread::sleep(*next_frame - now);
+    } else if now - *next_frame > FRAME * 5 {
+        // too far behind (e.g. the window was dragged), don't try to catch up
+        *next_frame = now;
+    }
+}
+
+// The window title: the speed and state of the emulator, or the fault it stopped at.
+fn status(chip8: &Chip8, settings: &Settings, fault: Option<&Error>) -> String {
+    if let Some(e) = fault {
+        return format!("chip8_emulator - {}", e);
+    }
+    let mut status = format!("chip8_emulator - {} ipf", settings.ipf);
+    if settings.muted {
+        status.push_str(", muted");
+    }
+    if settings.rewinding {
+        status.push_str(", rewinding");
+    }
+    if settings.paused {
+        status.push_str(", paused");
+    }
+    if chip8.waiting_for_key() {
+        status.push_str(", waiting for key");
+    }
+    status
+}
+
+// Hand the state of the sound timer over to the audio thread, once per frame.
+fn update_buzzer(
+    audio: &mut AudioDevice<Buzzer>,
+    chip8: &Chip8,
+    settings: &Settings,
+    running: bool,
+) {
+    let mut buzzer = audio.lock();
+    buzzer.playing = running && !settings.muted && chip8.sound_active();
+    buzzer.pattern = if chip8.platform() == Platform::XoChip {
+        Some((*chip8.audio_pattern(), chip8.playback_rate() as f32))
+    } else {
+        None
+    };
+}
+
+// Upload the screen into the streaming texture and let SDL scale it to the window.
+fn canvas_draw(canvas: &mut Canvas<Window>, texture: &mut Texture, chip8: &Chip8) -> Result<()> {
+    let (width, height) = (chip8.width(), chip8.height());
+    texture
+        .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for y in 0..height {
+                for x in 0..width {
+                    let color = PALETTE[chip8.pixel(x, y) as usize & 0x3];
+                    let offset = y * pitch + x * 3;
+                    buffer[offset..offset + 3].copy_from_slice(&color);
+                }
+            }
+        })
+        .map_err(Error::SdlError)?;
+
+    canvas
+        .copy(texture, Rect::new(0, 0, width as u32, height as u32), None)
+        .map_err(Error::SdlError)?;
+    canvas.present();
+    Ok(())
+}
+
+// F1-F8 select the save state slots 1-8.
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        _ => None,
+    }
+}
+
+// Save the machine into a slot file next to the rom, or restore it from there.
+// Failures are only reported, the emulator keeps running.
+fn save_or_load_state(chip8: &mut Chip8, rom_path: &str, slot: u8, save: bool) {
+    let path = format!("{}.{}.state", rom_path, slot);
+    let result = if save {
+        std::fs::write(&path, chip8.save_state()).map_err(|e| Error::State(e.to_string()))
+    } else {
+        std::fs::read(&path)
+            .map_err(|e| Error::State(e.to_string()))
+            .and_then(|data| chip8.load_state(&data))
+    };
+    match result {
+        Ok(()) if save => println!("saved state {} to {}", slot, path),
+        Ok(()) => println!("loaded state {} from {}", slot, path),
+        Err(e) => eprintln!("state {}: {}", slot, e),
+    }
+}
+
+// Handle the pending events, returns whether the window has to be redrawn.
+fn key_event(event_pump: &mut EventPump, chip8: &mut Chip8, settings: &mut Settings) -> bool {
+    let mut redraw = false;
+    for event in event_pump.poll_iter() {
+        match event {
+            Event::Quit { .. } => settings.quit = true,
+            Event::Window {
+                win_event: WindowEvent::Exposed,
+                ..
+            } => redraw = true,
+            Event::KeyDown {
+                keycode: Some(keycode),
+                keymod,
+                repeat,
+                ..
+            } => {
+                if let Some(slot) = state_slot(keycode).filter(|_| !repeat) {
+                    let save = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    save_or_load_state(chip8, &settings.rom_path, slot, save);
+                    redraw = true;
+                }
+                match keycode {
+                    Keycode::X => chip8.key_down(0),
+                    Keycode::Num1 => chip8.key_down(1),
+                    Keycode::Num2 => chip8.key_down(2),
+                    Keycode::Num3 => chip8.key_down(3),
+                    Keycode::Q => chip8.key_down(4),
+                    Keycode::W => chip8.key_down(5),
+                    Keycode::E => chip8.key_down(6),
+                    Keycode::A => chip8.key_down(7),
+                    Keycode::S => chip8.key_down(8),
+                    Keycode::D => chip8.key_down(9),
+                    Keycode::Z => chip8.key_down(10),
+                    Keycode::C => chip8.key_down(11),
+                    Keycode::Num4 => chip8.key_down(12),
+                    Keycode::R => chip8.key_down(13),
+                    Keycode::F => chip8.key_down(14),
+                    Keycode::V => chip8.key_down(15),
+                    Keycode::Escape => settings.quit = true,
+                    // held keys repeat, the hotkeys act once per press
+                    Keycode::Equals if !repeat => {
+                        settings.ipf = settings
+                            .ipf
+                            .max(settings.ipf.saturating_mul(2).min(MAX_IPF))
+                    }
+                    Keycode::Minus if !repeat => settings.ipf = (settings.ipf / 2).max(1),
+                    Keycode::M if !repeat => settings.muted = !settings.muted,
+                    Keycode::Backspace => settings.rewinding = true,
+                    _ => (),
+                }
+            }
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => match keycode {
+                Keycode::Backspace => settings.rewinding = false,
+                Keycode::X => chip8.key_up(0),
+                Keycode::Num1 => chip8.key_up(1),
+                Keycode::Num2 => chip8.key_up(2),
+                Keycode::Num3 => chip8.key_up(3),
+                Keycode::Q => chip8.key_up(4),
+                Keycode::W => chip8.key_up(5),
+                Keycode::E => chip8.key_up(6),
+                Keycode::A => chip8.key_up(7),
+                Keycode::S => chip8.key_up(8),
+                Keycode::D => chip8.key_up(9),
+                Keycode::Z => chip8.key_up(10),
+                Keycode::C => chip8.key_up(11),
+                Keycode::Num4 => chip8.key_up(12),
+                Keycode::R => chip8.key_up(13),
+                Keycode::F => chip8.key_up(14),
+                Keycode::V => chip8.key_up(15),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    redraw
+}